@@ -0,0 +1,400 @@
+//! Talking to the device over its configuration protocol.
+
+use std::io::{ErrorKind, Read, Write};
+
+use anyhow::{bail, Context, Result};
+use hyperdeck_core::config::Config;
+use hyperdeck_core::protocol::{self, FrameDecoder, Request, Response, MAX_FRAME_LEN};
+use hyperdeck_core::queue::Stats;
+
+pub struct Client<P> {
+    port: P,
+    decoder: FrameDecoder,
+}
+
+impl<P: Read + Write> Client<P> {
+    /// Opens a session on `port`, checking that the device speaks our protocol version.
+    pub fn connect(port: P) -> Result<Self> {
+        let mut client = Self {
+            port,
+            decoder: FrameDecoder::new(),
+        };
+
+        // A lone delimiter flushes any partial frame left over from an earlier session.
+        client.port.write_all(&[0])?;
+
+        match client.request(&Request::Hello)? {
+            Response::Hello { version, .. } if version == protocol::VERSION => Ok(client),
+            Response::Hello { version, .. } => bail!(
+                "device speaks protocol version {version}, but this tool speaks {}",
+                protocol::VERSION
+            ),
+            other => bail!("unexpected response to hello: {other:?}"),
+        }
+    }
+
+    /// Sends `request` and waits for the device's answer.
+    ///
+    /// Error responses are turned into `Err`s.
+    pub fn request(&mut self, request: &Request) -> Result<Response> {
+        let mut frame = [0_u8; MAX_FRAME_LEN];
+        let length = protocol::encode_frame(request, &mut frame)
+            .map_err(|err| anyhow::anyhow!("couldn't encode request: {err:?}"))?;
+
+        self.port.write_all(&frame[..length])?;
+        self.port.flush()?;
+
+        let mut buf = [0_u8; 64];
+
+        loop {
+            let count = match self.port.read(&mut buf) {
+                Ok(0) => bail!("device closed the connection"),
+                Ok(count) => count,
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) if err.kind() == ErrorKind::TimedOut => {
+                    bail!("timed out waiting for the device to answer {request:?}")
+                }
+                Err(err) => return Err(err.into()),
+            };
+
+            for byte in &buf[..count] {
+                match self.decoder.feed::<Response>(*byte) {
+                    Some(Ok(Response::Error(err))) => bail!("device rejected {request:?}: {err:?}"),
+                    Some(Ok(response)) => return Ok(response),
+                    Some(Err(err)) => bail!("received a corrupt response: {err:?}"),
+                    None => (),
+                }
+            }
+        }
+    }
+
+    /// Replaces the device's configuration with `config` and commits it to flash.
+    pub fn upload(&mut self, config: &Config) -> Result<()> {
+        for (i, layer) in config.layers.iter().enumerate() {
+            let request = Request::SetLayer {
+                layer: i as u8,
+                config: layer.clone(),
+            };
+
+            self.request(&request)
+                .with_context(|| format!("couldn't upload layer {i}"))?;
+        }
+
+        for (i, steps) in config.macros.iter().enumerate() {
+            let request = Request::SetMacro {
+                index: i as u8,
+                steps: steps.clone(),
+            };
+
+            self.request(&request)
+                .with_context(|| format!("couldn't upload macro {i}"))?;
+        }
+
+        for (i, text) in config.texts.iter().enumerate() {
+            let request = Request::SetText {
+                index: i as u8,
+                text: text.clone(),
+            };
+
+            self.request(&request)
+                .with_context(|| format!("couldn't upload text {i}"))?;
+        }
+
+        self.request(&Request::SetLayout(config.layout))?;
+        self.request(&Request::SetUnicode(config.unicode))?;
+        self.request(&Request::SetBrightness(config.brightness))?;
+        self.request(&Request::SetIndicators(config.indicators))?;
+        self.request(&Request::SetTapping(config.tapping))?;
+        self.request(&Request::SetDebounce(config.debounce))?;
+        self.request(&Request::SetRepeat(config.repeat))?;
+        self.request(&Request::Commit)
+            .context("couldn't commit the configuration to flash")?;
+
+        Ok(())
+    }
+
+    /// Reads back the device's working configuration.
+    pub fn download(&mut self) -> Result<Config> {
+        let mut config = Config::default();
+
+        for (i, layer) in config.layers.iter_mut().enumerate() {
+            *layer = match self.request(&Request::GetLayer { layer: i as u8 })? {
+                Response::Layer(layer) => layer,
+                other => bail!("unexpected response to layer request: {other:?}"),
+            };
+        }
+
+        for (i, steps) in config.macros.iter_mut().enumerate() {
+            *steps = match self.request(&Request::GetMacro { index: i as u8 })? {
+                Response::Macro(steps) => steps,
+                other => bail!("unexpected response to macro request: {other:?}"),
+            };
+        }
+
+        for (i, text) in config.texts.iter_mut().enumerate() {
+            *text = match self.request(&Request::GetText { index: i as u8 })? {
+                Response::Text(text) => text,
+                other => bail!("unexpected response to text request: {other:?}"),
+            };
+        }
+
+        config.layout = match self.request(&Request::GetLayout)? {
+            Response::Layout(layout) => layout,
+            other => bail!("unexpected response to layout request: {other:?}"),
+        };
+
+        config.unicode = match self.request(&Request::GetUnicode)? {
+            Response::Unicode(mode) => mode,
+            other => bail!("unexpected response to unicode request: {other:?}"),
+        };
+
+        config.brightness = match self.request(&Request::GetBrightness)? {
+            Response::Brightness(brightness) => brightness,
+            other => bail!("unexpected response to brightness request: {other:?}"),
+        };
+
+        config.indicators = match self.request(&Request::GetIndicators)? {
+            Response::Indicators(indicators) => indicators,
+            other => bail!("unexpected response to indicators request: {other:?}"),
+        };
+
+        config.tapping = match self.request(&Request::GetTapping)? {
+            Response::Tapping(tapping) => tapping,
+            other => bail!("unexpected response to tapping request: {other:?}"),
+        };
+
+        config.debounce = match self.request(&Request::GetDebounce)? {
+            Response::Debounce(debounce) => debounce,
+            other => bail!("unexpected response to debounce request: {other:?}"),
+        };
+
+        config.repeat = match self.request(&Request::GetRepeat)? {
+            Response::Repeat(repeat) => repeat,
+            other => bail!("unexpected response to repeat request: {other:?}"),
+        };
+
+        Ok(config)
+    }
+
+    /// Reads the device's HID report counters.
+    pub fn stats(&mut self) -> Result<Stats> {
+        match self.request(&Request::GetStats)? {
+            Response::Stats(stats) => Ok(stats),
+            other => bail!("unexpected response to stats request: {other:?}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::io;
+
+    use hyperdeck_core::config::{
+        Brightness, Combo, Indicator, KeyAction, KeyConfig, LayerConfig, Macro, MacroStep,
+    };
+    use hyperdeck_core::layout::Layout;
+    use hyperdeck_core::leds::Led;
+    use hyperdeck_core::mock::MemFlash;
+    use hyperdeck_core::session::{Session, Transport};
+    use hyperdeck_core::time::{Duration, Instant};
+    use hyperdeck_core::unicode::UnicodeMode;
+
+    use super::*;
+
+    /// The device end of the serial link: bytes the host wrote, and bytes for the host to read.
+    #[derive(Default)]
+    struct Wire {
+        to_device: VecDeque<u8>,
+        to_host: VecDeque<u8>,
+    }
+
+    impl Transport for Wire {
+        fn read(&mut self, buf: &mut [u8]) -> usize {
+            let count = buf.len().min(self.to_device.len());
+
+            for (slot, byte) in buf.iter_mut().zip(self.to_device.drain(..count)) {
+                *slot = byte;
+            }
+
+            count
+        }
+
+        fn write(&mut self, buf: &[u8]) -> usize {
+            // Mimic the CDC class' small transmit buffer.
+            let count = buf.len().min(16);
+            self.to_host.extend(&buf[..count]);
+            count
+        }
+    }
+
+    /// A serial port with the firmware's session logic on the other end.
+    struct FakeDevice {
+        wire: Wire,
+        session: Session,
+        config: Config,
+        /// Sized like the firmware's config region: eight 8K slots.
+        flash: MemFlash<{ 8 * 8192 }>,
+        stats: Stats,
+        now: Instant,
+        /// Drops everything the host writes, as if the cable were pulled.
+        unplugged: bool,
+    }
+
+    impl FakeDevice {
+        fn new() -> Self {
+            Self {
+                wire: Wire::default(),
+                session: Session::new(),
+                config: Config::default(),
+                flash: MemFlash::new(),
+                stats: Stats::default(),
+                now: Instant::from_ticks(0),
+                unplugged: false,
+            }
+        }
+    }
+
+    impl Read for FakeDevice {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            // Run the "main loop" until the device has something to say.
+            for _ in 0..1000 {
+                if !self.wire.to_host.is_empty() {
+                    let count = buf.len().min(self.wire.to_host.len());
+
+                    for (slot, byte) in buf.iter_mut().zip(self.wire.to_host.drain(..count)) {
+                        *slot = byte;
+                    }
+
+                    return Ok(count);
+                }
+
+                self.session.poll(
+                    &mut self.wire,
+                    &mut self.config,
+                    &mut self.flash,
+                    self.stats,
+                    self.now,
+                );
+
+                self.now += Duration::millis(1);
+            }
+
+            Err(ErrorKind::TimedOut.into())
+        }
+    }
+
+    impl Write for FakeDevice {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if !self.unplugged {
+                self.wire.to_device.extend(buf);
+            }
+
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn sample() -> Config {
+        let mut config = Config {
+            brightness: Brightness {
+                keypad: 30,
+                display: 70,
+            },
+            ..Default::default()
+        };
+
+        let mut layer = LayerConfig {
+            name: heapless::String::from("Default"),
+            ..Default::default()
+        };
+
+        layer.keys[4] = KeyConfig {
+            on_press: Some(KeyAction::Keyboard([0x01, 0, 0x06, 0, 0, 0, 0, 0])),
+            on_hold: None,
+            colors: [1, 2, 3, 4, 5, 6],
+            tapping_term: Some(300),
+            on_taps: [Some(KeyAction::Macro(2)), None],
+            on_tap_hold: None,
+            repeat: true,
+        };
+        layer
+            .combos
+            .push(Combo {
+                keys: 1 << 4 | 1 << 5,
+                action: KeyAction::Consumer(0xE2),
+            })
+            .unwrap();
+
+        config.layers[0] = Some(layer.clone());
+        config.layers[3] = Some(layer);
+
+        config.macros[2] =
+            Macro::from_slice(&[MacroStep::Tap(0x04), MacroStep::Delay(20)]).unwrap();
+
+        config.texts[1] = "Grüße".into();
+        config.layout = Layout::De;
+        config.unicode = Some(UnicodeMode::Linux);
+        config.tapping.hold_on_other_key_press = true;
+        config.tapping.combo_term = 80;
+        config.debounce.time = 12;
+        config.repeat.rate = 0;
+        config.indicators[Led::CapsLock as usize] = Some(Indicator {
+            key: Some(2),
+            color: [255, 0, 0],
+            display: true,
+        });
+
+        config
+    }
+
+    #[test]
+    fn upload_then_download() {
+        let mut client = Client::connect(FakeDevice::new()).unwrap();
+
+        client.upload(&sample()).unwrap();
+        assert_eq!(client.download().unwrap(), sample());
+
+        // The upload was committed, so it survives a reload from flash.
+        let device = &mut client.port;
+        assert_eq!(Config::load(&mut device.flash).unwrap(), sample());
+    }
+
+    #[test]
+    fn reads_stats() {
+        let mut device = FakeDevice::new();
+        device.stats.overflows = 3;
+        device.stats.dropped = 1;
+
+        let mut client = Client::connect(device).unwrap();
+        let stats = client.stats().unwrap();
+
+        assert_eq!(stats.overflows, 3);
+        assert_eq!(stats.dropped, 1);
+    }
+
+    #[test]
+    fn surfaces_device_errors() {
+        let mut client = Client::connect(FakeDevice::new()).unwrap();
+
+        let err = client
+            .request(&Request::GetLayerName { layer: 9 })
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "device rejected GetLayerName { layer: 9 }: NoSuchLayer"
+        );
+    }
+
+    #[test]
+    fn times_out_on_silence() {
+        let mut client = Client::connect(FakeDevice::new()).unwrap();
+        client.port.unplugged = true;
+
+        let err = client.request(&Request::GetBrightness).unwrap_err();
+        assert!(err.to_string().starts_with("timed out"));
+    }
+}
@@ -0,0 +1,182 @@
+//! Just enough PNG and PPM encoding to write out the simulator's frames.
+
+use embedded_graphics::pixelcolor::Rgb888;
+use embedded_graphics::prelude::*;
+use hyperdeck_core::crc::Crc32;
+
+/// An RGB image, row by row.
+pub struct Image {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<Rgb888>,
+}
+
+impl Image {
+    pub fn new(width: u32, height: u32, background: Rgb888) -> Self {
+        Self {
+            width,
+            height,
+            pixels: vec![background; (width * height) as usize],
+        }
+    }
+
+    /// Fills the `size` by `size` square with its top left corner at `(x, y)`.
+    pub fn fill_square(&mut self, x: u32, y: u32, size: u32, color: Rgb888) {
+        for row in y..(y + size).min(self.height) {
+            for column in x..(x + size).min(self.width) {
+                self.pixels[(row * self.width + column) as usize] = color;
+            }
+        }
+    }
+
+    fn rgb(&self) -> impl Iterator<Item = [u8; 3]> + '_ {
+        self.pixels.iter().map(|p| [p.r(), p.g(), p.b()])
+    }
+
+    /// Encodes the image as a binary PPM (P6).
+    pub fn to_ppm(&self) -> Vec<u8> {
+        let mut ppm = format!("P6\n{} {}\n255\n", self.width, self.height).into_bytes();
+        ppm.extend(self.rgb().flatten());
+        ppm
+    }
+
+    /// Encodes the image as an 8-bit RGB PNG. The image data is stored, not
+    /// compressed, which keeps this short at the cost of bigger files.
+    pub fn to_png(&self) -> Vec<u8> {
+        let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+
+        let mut header = Vec::new();
+        header.extend(self.width.to_be_bytes());
+        header.extend(self.height.to_be_bytes());
+        // 8 bits per channel, truecolor, then the default compression, filter
+        // and interlace methods.
+        header.extend([8, 2, 0, 0, 0]);
+        chunk(&mut png, b"IHDR", &header);
+
+        // Each row starts with its filter type, which is always none.
+        let mut raw = Vec::new();
+        for row in self.rgb().collect::<Vec<_>>().chunks(self.width as usize) {
+            raw.push(0);
+            raw.extend(row.iter().flatten());
+        }
+        chunk(&mut png, b"IDAT", &zlib_stored(&raw));
+
+        chunk(&mut png, b"IEND", &[]);
+        png
+    }
+}
+
+fn chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    let mut crc = Crc32::new();
+    crc.update(kind);
+    crc.update(data);
+
+    png.extend((data.len() as u32).to_be_bytes());
+    png.extend(kind);
+    png.extend(data);
+    png.extend(crc.finish().to_be_bytes());
+}
+
+/// Wraps `data` in a zlib stream of uncompressed deflate blocks.
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    // Deflate with a 32K window, no dictionary, and a header check that makes
+    // the first two bytes a multiple of 31.
+    let mut zlib = vec![0x78, 0x01];
+    let mut blocks = data.chunks(u16::MAX as usize).peekable();
+
+    if blocks.peek().is_none() {
+        zlib.extend([1, 0, 0, 0xFF, 0xFF]);
+    }
+
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none();
+        let len = block.len() as u16;
+
+        zlib.push(last as u8);
+        zlib.extend(len.to_le_bytes());
+        zlib.extend((!len).to_le_bytes());
+        zlib.extend(block);
+    }
+
+    zlib.extend(adler32(data).to_be_bytes());
+    zlib
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1_u32, 0_u32);
+
+    for byte in data {
+        a = (a + *byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+
+    b << 16 | a
+}
+
+#[cfg(test)]
+mod tests {
+    use hyperdeck_core::crc::crc32;
+
+    use super::*;
+
+    fn image() -> Image {
+        let mut image = Image::new(3, 2, Rgb888::BLACK);
+        image.fill_square(1, 1, 5, Rgb888::new(1, 2, 3));
+        image
+    }
+
+    #[test]
+    fn encodes_ppm() {
+        let ppm = image().to_ppm();
+
+        assert!(ppm.starts_with(b"P6\n3 2\n255\n"));
+        assert_eq!(
+            ppm[11..],
+            [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 2, 3, 1, 2, 3]
+        );
+    }
+
+    #[test]
+    fn encodes_png() {
+        let png = image().to_png();
+
+        assert!(png.starts_with(b"\x89PNG\r\n\x1a\n"));
+
+        // Walk the chunks, checking each one's CRC.
+        let mut rest = &png[8..];
+        let mut kinds = Vec::new();
+        let mut idat = Vec::new();
+
+        while !rest.is_empty() {
+            let len = u32::from_be_bytes(rest[..4].try_into().unwrap()) as usize;
+            let (kind, data) = (&rest[4..8], &rest[8..8 + len]);
+            let crc = u32::from_be_bytes(rest[8 + len..12 + len].try_into().unwrap());
+
+            assert_eq!(crc, crc32(&rest[4..8 + len]));
+            kinds.push(String::from_utf8(kind.to_vec()).unwrap());
+
+            if kind == b"IDAT" {
+                idat = data.to_vec();
+            }
+
+            rest = &rest[12 + len..];
+        }
+
+        assert_eq!(kinds, ["IHDR", "IDAT", "IEND"]);
+
+        // One stored block, holding both filtered rows.
+        let rows = [
+            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, //
+            0, 0, 0, 0, 1, 2, 3, 1, 2, 3,
+        ];
+        assert_eq!(idat[..7], [0x78, 0x01, 1, 20, 0, !20, 0xFF]);
+        assert_eq!(idat[7..27], rows);
+        assert_eq!(idat[27..], adler32(&rows).to_be_bytes());
+    }
+
+    #[test]
+    fn adler32_matches_the_reference() {
+        // The usual example, from Wikipedia.
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+    }
+}
@@ -0,0 +1,843 @@
+//! The TOML keymap format, and its translation to and from [`Config`].
+//!
+//! ```toml
+//! layout = "us"
+//! unicode = "linux"
+//! texts = ["Kind regards,\nAlex"]
+//!
+//! [brightness]
+//! keypad = 10
+//! display = 100
+//!
+//! [tapping]
+//! term = 200
+//! permissive_hold = true
+//! combo_term = 50
+//!
+//! [debounce]
+//! algorithm = "symmetric"
+//! time = 5
+//!
+//! [repeat]
+//! delay = 500
+//! rate = 20
+//!
+//! [[layer]]
+//! name = "Editing"
+//!
+//! [[layer.key]]
+//! index = 0
+//! press = "ctrl+shift+t"
+//! hold = "macro(0)"
+//! double_tap = "ctrl+shift+k"
+//! tapping_term = 250
+//! color = "#101010"
+//! pressed_color = "#00ff00"
+//!
+//! [[layer.key]]
+//! index = 1
+//! press = "backspace"
+//! repeat = true
+//!
+//! [[layer.combo]]
+//! keys = [12, 13]
+//! press = "media(mute)"
+//!
+//! [[macro]]
+//! steps = ["tap ctrl+k", "delay 50", "tap ctrl+c"]
+//!
+//! [[indicator]]
+//! led = "caps_lock"
+//! key = 13
+//! color = "#ff0000"
+//! display = true
+//! ```
+//!
+//! Actions are key chords, macros (`macro(n)`), text snippets (`text(n)`), single
+//! characters (`unicode(U+1F600)` or `unicode(€)`), media keys (`media(mute)`),
+//! mouse keys (`click(left)`, `move(up)`, `scroll(down)`), or layer switches:
+//! `mo(n)` (while held), `tg(n)` (toggle), `osl(n)` (next key press only), `to(n)`
+//! and `df(n)` (set the default layer). Layers, macros and texts are
+//! numbered in the order they appear. Keys that aren't listed fall through to the
+//! layer below, and the last two keys on the keypad always step through the layers.
+//!
+//! Media keys are named `play_pause`, `stop`, `next`, `previous`, `mute`,
+//! `volume_up`, `volume_down`, `browser_home`, `calculator` and so on, or given as a
+//! raw consumer usage like `0xe9`. `media_repeat(...)` keeps tapping the key while
+//! it's held, which is what volume keys usually want. It follows the `repeat` delay
+//! and rate below, whether or not the key sets `repeat = true`.
+//!
+//! A key with a `hold` action waits to see whether it's tapped or held. It counts as
+//! held once it's been down for the `tapping` term (200 milliseconds, unless the key
+//! sets its own `tapping_term`), or sooner with `permissive_hold` (another key was
+//! tapped meanwhile) or `hold_on_other_key_press` (another key was pressed).
+//!
+//! Keys with `repeat = true` perform their action over and over while held, like
+//! a keyboard does, starting after the `repeat` delay (500 milliseconds) at the
+//! `repeat` rate (20 times a second, or never if it's 0).
+//!
+//! Keys can also do something else when they're tapped twice (`double_tap`), three
+//! times (`triple_tap`), or tapped and then held (`tap_hold`). Each tap waits up to
+//! the tapping term for the next one, so the plain `press` action is delayed by
+//! that much.
+//!
+//! Combos perform their own action when all of their `keys` (two to four of them)
+//! are pressed within the `combo_term` of each other (50 milliseconds by default),
+//! instead of the keys' own actions, which are delayed by that much. A combo lasts
+//! until the first of its keys is released, and works on any layer above its own
+//! too.
+//!
+//! Key presses are `debounce`d for `time` milliseconds (5 by default, 0 to turn it
+//! off), so that bouncing or chattering switches don't register twice. The
+//! `algorithm` is `symmetric` (the default: waits for every key to settle),
+//! `deferred_per_key` (waits for each key to settle on its own), or `eager_per_key`
+//! (reports a change straight away, then ignores the key until it settles).
+//!
+//! Macro steps are `press`, `release` or `tap` followed by a chord, or `delay`
+//! followed by a number of milliseconds.
+//!
+//! Texts are typed as keystrokes, so the device needs to know the host's keyboard
+//! `layout`: `us` (the default), `uk` or `de`. Characters the layout doesn't have
+//! are entered with the `unicode` input method, if one is set: `linux` (IBus),
+//! `macos` (Unicode Hex Input), `windows` (hex Alt codes) or `wincompose`.
+//!
+//! Indicators show the host's `num_lock`, `caps_lock`, `scroll_lock`, `compose` or
+//! `kana` LED while it's lit, by giving a key (0 to 15, on every layer) a different
+//! color, by listing it on the display's home screen, or both.
+
+use anyhow::{bail, ensure, Context, Result};
+use hyperdeck_core::config::{
+    self, Brightness, Config, KeyAction, KeyConfig, LayerConfig, Repeat, Tapping, LAYER_KEYS,
+    MACRO_STEPS, MAX_COMBOS, MAX_COMBO_KEYS, MAX_LAYERS, MAX_MACROS, MAX_TEXTS, NAME_LEN, TEXT_LEN,
+};
+use hyperdeck_core::debounce::Debounce;
+use hyperdeck_core::keypad::NUM_KEYS;
+use hyperdeck_core::layout::Layout;
+use hyperdeck_core::leds::Led;
+use hyperdeck_core::unicode::UnicodeMode;
+use serde::{Deserialize, Serialize};
+
+use crate::keys::{format_action, format_step, parse_action, parse_step};
+
+/// Colors used for keys that don't specify their own, matching the firmware's defaults.
+const DEFAULT_COLOR: [u8; 3] = [16, 16, 16];
+const DEFAULT_PRESSED_COLOR: [u8; 3] = [0, 255, 0];
+/// Color for indicator keys that don't specify one.
+const DEFAULT_INDICATOR_COLOR: [u8; 3] = [255, 255, 255];
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Keymap {
+    #[serde(default)]
+    pub layout: Layout,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unicode: Option<UnicodeMode>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub texts: Vec<String>,
+    #[serde(default)]
+    pub brightness: Brightness,
+    #[serde(default)]
+    pub tapping: Tapping,
+    #[serde(default)]
+    pub debounce: Debounce,
+    #[serde(default)]
+    pub repeat: Repeat,
+    #[serde(default, rename = "layer")]
+    pub layers: Vec<Layer>,
+    #[serde(default, rename = "macro", skip_serializing_if = "Vec::is_empty")]
+    pub macros: Vec<Macro>,
+    #[serde(default, rename = "indicator", skip_serializing_if = "Vec::is_empty")]
+    pub indicators: Vec<Indicator>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Layer {
+    pub name: String,
+    #[serde(default, rename = "key")]
+    pub keys: Vec<Key>,
+    #[serde(default, rename = "combo", skip_serializing_if = "Vec::is_empty")]
+    pub combos: Vec<Combo>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Key {
+    pub index: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub press: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hold: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub double_tap: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub triple_tap: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tap_hold: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tapping_term: Option<u16>,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub repeat: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub color: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pressed_color: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Combo {
+    pub keys: Vec<u8>,
+    pub press: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Macro {
+    pub steps: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Indicator {
+    pub led: Led,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub color: Option<String>,
+    #[serde(default)]
+    pub display: bool,
+}
+
+impl Keymap {
+    pub fn parse(source: &str) -> Result<Self> {
+        Ok(toml::from_str(source)?)
+    }
+
+    pub fn to_toml(&self) -> Result<String> {
+        Ok(toml::to_string_pretty(self)?)
+    }
+
+    /// Validates the keymap against the device's limits and compiles it.
+    pub fn compile(&self) -> Result<Config> {
+        ensure!(
+            self.layers.len() <= MAX_LAYERS,
+            "the device supports at most {MAX_LAYERS} layers, but {} are defined",
+            self.layers.len()
+        );
+
+        ensure!(
+            self.macros.len() <= MAX_MACROS,
+            "the device supports at most {MAX_MACROS} macros, but {} are defined",
+            self.macros.len()
+        );
+
+        ensure!(
+            self.texts.len() <= MAX_TEXTS,
+            "the device supports at most {MAX_TEXTS} texts, but {} are defined",
+            self.texts.len()
+        );
+
+        ensure!(
+            self.brightness.keypad <= 100 && self.brightness.display <= 100,
+            "brightness values are percentages, and can't exceed 100"
+        );
+
+        let mut config = Config {
+            layout: self.layout,
+            unicode: self.unicode,
+            brightness: self.brightness,
+            tapping: self.tapping,
+            debounce: self.debounce,
+            repeat: self.repeat,
+            ..Default::default()
+        };
+
+        for (i, layer) in self.layers.iter().enumerate() {
+            let compiled = layer
+                .compile()
+                .with_context(|| format!("in layer {i} (\"{}\")", layer.name))?;
+
+            config.layers[i] = Some(compiled);
+        }
+
+        for (i, steps) in self.macros.iter().enumerate() {
+            config.macros[i] = steps.compile().with_context(|| format!("in macro {i}"))?;
+        }
+
+        for (i, text) in self.texts.iter().enumerate() {
+            config.texts[i] = self
+                .compile_text(text)
+                .with_context(|| format!("in text {i}"))?;
+        }
+
+        for indicator in &self.indicators {
+            let led = name(&indicator.led);
+            let slot = &mut config.indicators[indicator.led as usize];
+
+            ensure!(
+                slot.is_none(),
+                "the {led} indicator is defined more than once"
+            );
+            *slot = Some(
+                indicator
+                    .compile()
+                    .with_context(|| format!("in the {led} indicator"))?,
+            );
+        }
+
+        // Actions pointing at missing layers, macros or texts are ignored by the
+        // firmware, which is almost certainly not what was intended.
+        for (i, layer) in config.layers.iter().flatten().enumerate() {
+            let keys = layer.keys.iter().enumerate().flat_map(|(index, key)| {
+                key.actions()
+                    .map(move |action| (format!("key {index}"), action))
+            });
+            let combos = (layer.combos.iter().enumerate())
+                .map(|(index, combo)| (format!("combo {index}"), &combo.action));
+
+            for (location, action) in keys.chain(combos) {
+                let (kind, target, count) = match action {
+                    KeyAction::Keyboard(_)
+                    | KeyAction::Consumer(_)
+                    | KeyAction::ConsumerRepeat(_)
+                    | KeyAction::Mouse(_) => continue,
+                    KeyAction::Unicode(c) => {
+                        self.check_unicode(*c).with_context(|| {
+                            format!("in layer {i} (\"{}\"): in {location}", layer.name)
+                        })?;
+                        continue;
+                    }
+                    KeyAction::Layer(action) => ("layer", action.layer(), self.layers.len()),
+                    KeyAction::Macro(target) => ("macro", *target, self.macros.len()),
+                    KeyAction::Text(target) => ("text", *target, self.texts.len()),
+                };
+
+                ensure!(
+                    (target as usize) < count,
+                    "in layer {i} (\"{}\"): in {location}: {kind} {target} isn't defined",
+                    layer.name
+                );
+            }
+        }
+
+        Ok(config)
+    }
+
+    /// Reconstructs a keymap from a device configuration.
+    pub fn decompile(config: &Config) -> Self {
+        let layers = config
+            .layers
+            .iter()
+            .flatten()
+            .map(Layer::decompile)
+            .collect();
+
+        // Unused macros still take up their index, except at the end.
+        let used = config.macros.iter().rposition(|steps| !steps.is_empty());
+        let macros = config.macros[..used.map_or(0, |i| i + 1)]
+            .iter()
+            .map(Macro::decompile)
+            .collect();
+
+        let used = config.texts.iter().rposition(|text| !text.is_empty());
+        let texts = config.texts[..used.map_or(0, |i| i + 1)]
+            .iter()
+            .map(|text| text.to_string())
+            .collect();
+
+        let indicators = Led::ALL
+            .into_iter()
+            .filter_map(|led| Some(Indicator::decompile(led, &config.indicators[led as usize]?)))
+            .collect();
+
+        Self {
+            layout: config.layout,
+            unicode: config.unicode,
+            texts,
+            brightness: config.brightness,
+            tapping: config.tapping,
+            debounce: config.debounce,
+            repeat: config.repeat,
+            layers,
+            macros,
+            indicators,
+        }
+    }
+
+    /// Checks that every character of `text` can be typed or entered.
+    fn compile_text(&self, text: &str) -> Result<heapless::String<TEXT_LEN>> {
+        ensure!(
+            text.len() <= TEXT_LEN,
+            "texts can be at most {TEXT_LEN} bytes long"
+        );
+
+        let typeable = |c: char| {
+            self.layout.keystrokes(c).is_some()
+                || self.unicode.and_then(|mode| mode.steps(c)).is_some()
+        };
+
+        if let Some(c) = text.chars().find(|c| !typeable(*c)) {
+            let layout = name(&self.layout);
+
+            match self.unicode {
+                Some(mode) => bail!(
+                    "{c:?} can't be typed on the `{layout}` layout, or entered with the `{}` input method",
+                    name(&mode)
+                ),
+                None => bail!(
+                    "{c:?} can't be typed on the `{layout}` layout, and no `unicode` input method is set"
+                ),
+            }
+        }
+
+        Ok(heapless::String::from(text))
+    }
+
+    /// Checks that a `unicode(...)` action can be typed or entered.
+    fn check_unicode(&self, c: char) -> Result<()> {
+        if self.layout.keystrokes(c).is_some() {
+            return Ok(());
+        }
+
+        let Some(mode) = self.unicode else {
+            bail!(
+                "`{}` isn't on the `{}` layout, and needs a `unicode` input method",
+                format_action(&KeyAction::Unicode(c)),
+                name(&self.layout)
+            );
+        };
+
+        ensure!(
+            mode.steps(c).is_some(),
+            "{c:?} can't be entered with the `{}` input method",
+            name(&mode)
+        );
+
+        Ok(())
+    }
+}
+
+impl Layer {
+    fn compile(&self) -> Result<LayerConfig> {
+        ensure!(
+            self.name.len() <= NAME_LEN,
+            "layer names can be at most {NAME_LEN} bytes long"
+        );
+
+        let name = heapless::String::from(self.name.as_str());
+
+        let mut keys: [KeyConfig; LAYER_KEYS] = core::array::from_fn(|_| Key::unbound());
+        let mut seen = [false; LAYER_KEYS];
+
+        for key in &self.keys {
+            let index = key.index as usize;
+
+            ensure!(
+                index < LAYER_KEYS,
+                "key index {index} is out of range (keys are numbered 0 to {})",
+                LAYER_KEYS - 1
+            );
+
+            ensure!(!seen[index], "key {index} is defined more than once");
+            seen[index] = true;
+
+            keys[index] = key.compile().with_context(|| format!("in key {index}"))?;
+        }
+
+        ensure!(
+            self.combos.len() <= MAX_COMBOS,
+            "layers can have at most {MAX_COMBOS} combos, but {} are defined",
+            self.combos.len()
+        );
+
+        let mut combos = heapless::Vec::new();
+
+        for (i, combo) in self.combos.iter().enumerate() {
+            let compiled = combo.compile().with_context(|| format!("in combo {i}"))?;
+
+            ensure!(
+                !combos
+                    .iter()
+                    .any(|other: &config::Combo| other.keys == compiled.keys),
+                "combo {i} has the same keys as an earlier one"
+            );
+
+            // Can't fail, the count was checked above.
+            let _ = combos.push(compiled);
+        }
+
+        Ok(LayerConfig { name, keys, combos })
+    }
+
+    fn decompile(layer: &LayerConfig) -> Self {
+        let keys = layer
+            .keys
+            .iter()
+            .enumerate()
+            .filter(|(_, key)| **key != Key::unbound())
+            .map(|(i, key)| Key::decompile(i as u8, key))
+            .collect();
+
+        Self {
+            name: layer.name.to_string(),
+            keys,
+            combos: layer.combos.iter().map(Combo::decompile).collect(),
+        }
+    }
+}
+
+impl Combo {
+    fn compile(&self) -> Result<config::Combo> {
+        ensure!(
+            (2..=MAX_COMBO_KEYS).contains(&self.keys.len()),
+            "combos need between 2 and {MAX_COMBO_KEYS} keys"
+        );
+
+        let mut keys = 0_u16;
+
+        for &index in &self.keys {
+            ensure!(
+                (index as usize) < LAYER_KEYS,
+                "key index {index} is out of range (keys are numbered 0 to {})",
+                LAYER_KEYS - 1
+            );
+
+            ensure!(
+                keys & 1 << index == 0,
+                "key {index} is listed more than once"
+            );
+            keys |= 1 << index;
+        }
+
+        Ok(config::Combo {
+            keys,
+            action: parse_action(&self.press)?,
+        })
+    }
+
+    fn decompile(combo: &config::Combo) -> Self {
+        Self {
+            keys: (0..LAYER_KEYS as u8)
+                .filter(|index| combo.keys & 1 << index != 0)
+                .collect(),
+            press: format_action(&combo.action),
+        }
+    }
+}
+
+impl Key {
+    fn compile(&self) -> Result<KeyConfig> {
+        let color = parse_color(self.color.as_deref(), DEFAULT_COLOR)?;
+        let pressed = parse_color(self.pressed_color.as_deref(), DEFAULT_PRESSED_COLOR)?;
+
+        let mut colors = [0; 6];
+        colors[..3].copy_from_slice(&color);
+        colors[3..].copy_from_slice(&pressed);
+
+        let parse = |action: &Option<String>| action.as_deref().map(parse_action).transpose();
+
+        let key = KeyConfig {
+            on_press: parse(&self.press)?,
+            on_hold: parse(&self.hold)?,
+            colors,
+            tapping_term: self.tapping_term,
+            on_taps: [parse(&self.double_tap)?, parse(&self.triple_tap)?],
+            on_tap_hold: parse(&self.tap_hold)?,
+            repeat: self.repeat,
+        };
+
+        ensure!(
+            self.triple_tap.is_none() || self.double_tap.is_some(),
+            "keys need a `double_tap` action to have a `triple_tap` one"
+        );
+
+        ensure!(
+            self.tapping_term.is_none() || key.on_hold.is_some() || key.is_tap_dance(),
+            "`tapping_term` only applies to keys with a `hold` or tap dance action"
+        );
+
+        Ok(key)
+    }
+
+    /// What an unlisted key compiles to.
+    fn unbound() -> KeyConfig {
+        KeyConfig {
+            on_press: None,
+            on_hold: None,
+            colors: [DEFAULT_COLOR, DEFAULT_PRESSED_COLOR]
+                .concat()
+                .try_into()
+                .unwrap(),
+            ..Default::default()
+        }
+    }
+
+    fn decompile(index: u8, key: &KeyConfig) -> Self {
+        let color = |rgb: &[u8], default: [u8; 3]| match rgb == default {
+            true => None,
+            false => Some(format_color(rgb)),
+        };
+
+        Self {
+            index,
+            press: key.on_press.as_ref().map(format_action),
+            hold: key.on_hold.as_ref().map(format_action),
+            double_tap: key.on_taps[0].as_ref().map(format_action),
+            triple_tap: key.on_taps[1].as_ref().map(format_action),
+            tap_hold: key.on_tap_hold.as_ref().map(format_action),
+            tapping_term: key.tapping_term,
+            repeat: key.repeat,
+            color: color(&key.colors[..3], DEFAULT_COLOR),
+            pressed_color: color(&key.colors[3..], DEFAULT_PRESSED_COLOR),
+        }
+    }
+}
+
+impl Macro {
+    fn compile(&self) -> Result<config::Macro> {
+        let mut steps = config::Macro::new();
+
+        for step in &self.steps {
+            for step in parse_step(step)? {
+                if steps.push(step).is_err() {
+                    bail!("macros can have at most {MACRO_STEPS} steps, once chords are expanded");
+                }
+            }
+        }
+
+        Ok(steps)
+    }
+
+    fn decompile(steps: &config::Macro) -> Self {
+        Self {
+            steps: steps.iter().map(format_step).collect(),
+        }
+    }
+}
+
+impl Indicator {
+    fn compile(&self) -> Result<config::Indicator> {
+        ensure!(
+            self.key.is_some() || self.display,
+            "indicators need a `key`, `display = true`, or both"
+        );
+
+        if let Some(key) = self.key {
+            ensure!(
+                (key as usize) < NUM_KEYS,
+                "key index {key} is out of range (keys are numbered 0 to {})",
+                NUM_KEYS - 1
+            );
+        }
+
+        Ok(config::Indicator {
+            key: self.key,
+            color: parse_color(self.color.as_deref(), DEFAULT_INDICATOR_COLOR)?,
+            display: self.display,
+        })
+    }
+
+    fn decompile(led: Led, indicator: &config::Indicator) -> Self {
+        let color = match (indicator.key, indicator.color) {
+            (Some(_), color) if color != DEFAULT_INDICATOR_COLOR => Some(format_color(&color)),
+            _ => None,
+        };
+
+        Self {
+            led,
+            key: indicator.key,
+            color,
+            display: indicator.display,
+        }
+    }
+}
+
+/// The keymap spelling of a setting like [`Layout`].
+fn name<T: Serialize>(value: &T) -> String {
+    match toml::Value::try_from(value) {
+        Ok(toml::Value::String(name)) => name,
+        _ => unreachable!("settings serialize as strings"),
+    }
+}
+
+fn is_false(value: &bool) -> bool {
+    !value
+}
+
+fn format_color(rgb: &[u8]) -> String {
+    format!("#{:02x}{:02x}{:02x}", rgb[0], rgb[1], rgb[2])
+}
+
+/// Parses a `#rrggbb` color, falling back to `default` if none was given.
+fn parse_color(color: Option<&str>, default: [u8; 3]) -> Result<[u8; 3]> {
+    let Some(color) = color else {
+        return Ok(default);
+    };
+
+    let hex = match color.strip_prefix('#') {
+        Some(hex) if hex.len() == 6 && hex.is_ascii() => hex,
+        _ => bail!("colors must be written as `#rrggbb`, not `{color}`"),
+    };
+
+    let channel = |i: usize| {
+        u8::from_str_radix(&hex[i..i + 2], 16)
+            .with_context(|| format!("`{color}` isn't a valid hex color"))
+    };
+
+    Ok([channel(0)?, channel(2)?, channel(4)?])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXAMPLE: &str = include_str!("../keymap.example.toml");
+
+    #[test]
+    fn example_compiles() {
+        let config = Keymap::parse(EXAMPLE).unwrap().compile().unwrap();
+        let layer = config.layers[0].as_ref().unwrap();
+
+        assert_eq!(layer.name, "Default");
+        assert_eq!(
+            layer.keys[0].on_press,
+            Some(KeyAction::Keyboard([0x03, 0, 0x17, 0, 0, 0, 0, 0]))
+        );
+        assert_eq!(layer.keys[0].colors, [0x10, 0x10, 0x10, 0x00, 0xff, 0x00]);
+        assert_eq!(
+            layer.combos[0],
+            config::Combo {
+                keys: 0b110,
+                action: KeyAction::Keyboard([0x01, 0, 0x1B, 0, 0, 0, 0, 0]),
+            }
+        );
+    }
+
+    #[test]
+    fn decompile_round_trips() {
+        let config = Keymap::parse(EXAMPLE).unwrap().compile().unwrap();
+        let toml = Keymap::decompile(&config).to_toml().unwrap();
+
+        assert_eq!(Keymap::parse(&toml).unwrap().compile().unwrap(), config);
+    }
+
+    fn error(source: &str) -> String {
+        let err = Keymap::parse(source)
+            .and_then(|keymap| keymap.compile())
+            .unwrap_err();
+        format!("{err:#}")
+    }
+
+    #[test]
+    fn enforces_limits() {
+        let seven_layers = "[[layer]]\nname = \"x\"\n".repeat(7);
+        assert!(error(&seven_layers).contains("at most 6 layers"));
+
+        let long_name = "[[layer]]\nname = \"a very long layer name\"";
+        assert!(error(long_name).contains("at most 16 bytes"));
+
+        let bad_index = "[[layer]]\nname = \"x\"\n[[layer.key]]\nindex = 14";
+        assert!(error(bad_index).contains("out of range"));
+
+        let duplicate =
+            "[[layer]]\nname = \"x\"\n[[layer.key]]\nindex = 1\n[[layer.key]]\nindex = 1";
+        assert!(error(duplicate).contains("more than once"));
+
+        let missing_layer = "[[layer]]\nname = \"x\"\n[[layer.key]]\nindex = 1\nhold = \"mo(1)\"";
+        assert!(error(missing_layer).contains("layer 1 isn't defined"));
+
+        let missing_macro =
+            "[[layer]]\nname = \"x\"\n[[layer.key]]\nindex = 1\npress = \"macro(0)\"";
+        assert!(error(missing_macro).contains("macro 0 isn't defined"));
+
+        let missing_text = "[[layer]]\nname = \"x\"\n[[layer.key]]\nindex = 1\npress = \"text(0)\"";
+        assert!(error(missing_text).contains("text 0 isn't defined"));
+
+        let long_text = format!("texts = [\"{}\"]", "a".repeat(65));
+        assert!(error(&long_text).contains("at most 64 bytes"));
+
+        let long_macro = format!("[[macro]]\nsteps = [{}]", "\"tap ctrl+a\",".repeat(9));
+        assert!(error(&long_macro).contains("at most 24 steps"));
+
+        let pointless_term =
+            "[[layer]]\nname = \"x\"\n[[layer.key]]\nindex = 1\npress = \"a\"\ntapping_term = 100";
+        assert!(error(pointless_term).contains("only applies to keys with a `hold` or tap"));
+
+        let lone_triple = "[[layer]]\nname = \"x\"\n[[layer.key]]\nindex = 1\ntriple_tap = \"a\"";
+        assert!(error(lone_triple).contains("need a `double_tap`"));
+
+        let missing_target =
+            "[[layer]]\nname = \"x\"\n[[layer.key]]\nindex = 1\ntap_hold = \"text(0)\"";
+        assert!(error(missing_target).contains("text 0 isn't defined"));
+
+        let twice =
+            "[[indicator]]\nled = \"kana\"\nkey = 1\n[[indicator]]\nled = \"kana\"\nkey = 2";
+        assert!(error(twice).contains("kana indicator is defined more than once"));
+
+        let pointless = "[[indicator]]\nled = \"num_lock\"";
+        assert!(error(pointless).contains("need a `key`"));
+
+        let bad_key = "[[indicator]]\nled = \"num_lock\"\nkey = 16";
+        assert!(error(bad_key).contains("out of range"));
+
+        let combo = |keys: &str| {
+            format!("[[layer]]\nname = \"x\"\n[[layer.combo]]\nkeys = {keys}\npress = \"a\"")
+        };
+        assert!(error(&combo("[1]")).contains("between 2 and 4 keys"));
+        assert!(error(&combo("[1, 2, 3, 4, 5]")).contains("between 2 and 4 keys"));
+        assert!(error(&combo("[13, 14]")).contains("out of range"));
+        assert!(error(&combo("[1, 1]")).contains("listed more than once"));
+
+        let twice = format!(
+            "{}\n[[layer.combo]]\nkeys = [2, 1]\npress = \"b\"",
+            combo("[1, 2]")
+        );
+        assert!(error(&twice).contains("same keys as an earlier one"));
+
+        let too_many = format!(
+            "[[layer]]\nname = \"x\"\n{}",
+            "[[layer.combo]]\nkeys = [1, 2]\npress = \"a\"\n".repeat(9)
+        );
+        assert!(error(&too_many).contains("at most 8 combos"));
+
+        let missing_target =
+            "[[layer]]\nname = \"x\"\n[[layer.combo]]\nkeys = [1, 2]\npress = \"macro(0)\"";
+        assert_eq!(
+            error(missing_target),
+            "in layer 0 (\"x\"): in combo 0: macro 0 isn't defined"
+        );
+    }
+
+    #[test]
+    fn reports_location() {
+        let source = "[[layer]]\nname = \"Main\"\n[[layer.key]]\nindex = 2\npress = \"ctrl+nope\"";
+        assert_eq!(
+            error(source),
+            "in layer 0 (\"Main\"): in key 2: unknown key name `nope` in `ctrl+nope`"
+        );
+
+        let source = "[[layer]]\nname = \"Main\"\n[[layer.key]]\nindex = 2\ncolor = \"red\"";
+        assert!(error(source).contains("#rrggbb"));
+
+        let source = "layout = \"uk\"\nunicode = \"windows\"\ntexts = [\"Grüße 😀\"]";
+        assert_eq!(
+            error(source),
+            "in text 0: '😀' can't be typed on the `uk` layout, or entered with the `windows` \
+             input method"
+        );
+
+        let source = "[[layer]]\nname = \"x\"\n[[layer.key]]\nindex = 1\npress = \"unicode(é)\"";
+        assert_eq!(
+            error(source),
+            "in layer 0 (\"x\"): in key 1: `unicode(U+00E9)` isn't on the `us` layout, and needs \
+             a `unicode` input method"
+        );
+
+        let source = "layout = \"uk\"\ntexts = [\"ok\", \"Grüße\"]";
+        assert_eq!(
+            error(source),
+            "in text 1: 'ü' can't be typed on the `uk` layout, and no `unicode` input method is set"
+        );
+    }
+}
@@ -0,0 +1,585 @@
+//! Human-readable key actions: chords such as `ctrl+shift+t`, and functions
+//! such as `mo(1)` or `text(0)`.
+
+use anyhow::{bail, Context, Result};
+use hyperdeck_core::config::{
+    Direction, KeyAction, LayerAction, MacroStep, MouseAction, MAX_LAYERS, MAX_MACROS, MAX_TEXTS,
+};
+
+/// Modifier names and their bits in the report's modifier byte.
+const MODIFIERS: &[(&str, u8)] = &[
+    ("ctrl", 0x01),
+    ("shift", 0x02),
+    ("alt", 0x04),
+    ("gui", 0x08),
+    ("rctrl", 0x10),
+    ("rshift", 0x20),
+    ("ralt", 0x40),
+    ("rgui", 0x80),
+];
+
+/// Alternative spellings, mapped to the canonical names above.
+const MODIFIER_ALIASES: &[(&str, &str)] = &[
+    ("control", "ctrl"),
+    ("lctrl", "ctrl"),
+    ("lshift", "shift"),
+    ("lalt", "alt"),
+    ("option", "alt"),
+    ("altgr", "ralt"),
+    ("super", "gui"),
+    ("win", "gui"),
+    ("cmd", "gui"),
+    ("meta", "gui"),
+];
+
+/// Key names and their HID usage IDs (keyboard/keypad page).
+#[rustfmt::skip]
+const KEYS: &[(&str, u8)] = &[
+    ("a", 0x04), ("b", 0x05), ("c", 0x06), ("d", 0x07), ("e", 0x08), ("f", 0x09),
+    ("g", 0x0A), ("h", 0x0B), ("i", 0x0C), ("j", 0x0D), ("k", 0x0E), ("l", 0x0F),
+    ("m", 0x10), ("n", 0x11), ("o", 0x12), ("p", 0x13), ("q", 0x14), ("r", 0x15),
+    ("s", 0x16), ("t", 0x17), ("u", 0x18), ("v", 0x19), ("w", 0x1A), ("x", 0x1B),
+    ("y", 0x1C), ("z", 0x1D),
+    ("1", 0x1E), ("2", 0x1F), ("3", 0x20), ("4", 0x21), ("5", 0x22),
+    ("6", 0x23), ("7", 0x24), ("8", 0x25), ("9", 0x26), ("0", 0x27),
+    ("enter", 0x28), ("esc", 0x29), ("backspace", 0x2A), ("tab", 0x2B), ("space", 0x2C),
+    ("minus", 0x2D), ("equal", 0x2E), ("leftbracket", 0x2F), ("rightbracket", 0x30),
+    ("backslash", 0x31), ("semicolon", 0x33), ("quote", 0x34), ("grave", 0x35),
+    ("comma", 0x36), ("period", 0x37), ("slash", 0x38), ("capslock", 0x39),
+    ("f1", 0x3A), ("f2", 0x3B), ("f3", 0x3C), ("f4", 0x3D), ("f5", 0x3E), ("f6", 0x3F),
+    ("f7", 0x40), ("f8", 0x41), ("f9", 0x42), ("f10", 0x43), ("f11", 0x44), ("f12", 0x45),
+    ("printscreen", 0x46), ("scrolllock", 0x47), ("pause", 0x48), ("insert", 0x49),
+    ("home", 0x4A), ("pageup", 0x4B), ("delete", 0x4C), ("end", 0x4D), ("pagedown", 0x4E),
+    ("right", 0x4F), ("left", 0x50), ("down", 0x51), ("up", 0x52), ("numlock", 0x53),
+    ("menu", 0x65),
+    ("f13", 0x68), ("f14", 0x69), ("f15", 0x6A), ("f16", 0x6B), ("f17", 0x6C), ("f18", 0x6D),
+    ("f19", 0x6E), ("f20", 0x6F), ("f21", 0x70), ("f22", 0x71), ("f23", 0x72), ("f24", 0x73),
+];
+
+/// Consumer control names and their usage IDs (consumer page).
+#[rustfmt::skip]
+const CONSUMER: &[(&str, u16)] = &[
+    ("play_pause", 0xCD), ("stop", 0xB7), ("next", 0xB5), ("previous", 0xB6),
+    ("fast_forward", 0xB3), ("rewind", 0xB4), ("eject", 0xB8),
+    ("mute", 0xE2), ("volume_up", 0xE9), ("volume_down", 0xEA),
+    ("brightness_up", 0x6F), ("brightness_down", 0x70),
+    ("media_player", 0x183), ("mail", 0x18A), ("calculator", 0x192), ("file_browser", 0x194),
+    ("browser_search", 0x221), ("browser_home", 0x223), ("browser_back", 0x224),
+    ("browser_forward", 0x225), ("browser_stop", 0x226), ("browser_refresh", 0x227),
+    ("browser_bookmarks", 0x22A),
+];
+
+const CONSUMER_ALIASES: &[(&str, &str)] = &[
+    ("play", "play_pause"),
+    ("pause", "play_pause"),
+    ("prev", "previous"),
+    ("vol_up", "volume_up"),
+    ("vol_down", "volume_down"),
+    ("calc", "calculator"),
+];
+
+/// Mouse button names, in button number order.
+const MOUSE_BUTTONS: &[&str] = &["left", "right", "middle", "back", "forward"];
+
+const DIRECTIONS: &[(&str, Direction)] = &[
+    ("up", Direction::Up),
+    ("down", Direction::Down),
+    ("left", Direction::Left),
+    ("right", Direction::Right),
+];
+
+const KEY_ALIASES: &[(&str, &str)] = &[
+    ("return", "enter"),
+    ("escape", "esc"),
+    ("del", "delete"),
+    ("ins", "insert"),
+    ("pgup", "pageup"),
+    ("pgdn", "pagedown"),
+];
+
+type LayerFn = fn(u8) -> LayerAction;
+
+/// Layer switching functions, named after their QMK equivalents.
+const LAYER_ACTIONS: &[(&str, LayerFn)] = &[
+    ("mo", LayerAction::Momentary),
+    ("tg", LayerAction::Toggle),
+    ("osl", LayerAction::OneShot),
+    ("to", LayerAction::To),
+    ("df", LayerAction::Default),
+];
+
+/// Parses a layer switch like `tg(2)`, a macro like `macro(0)`, a text snippet
+/// like `text(1)`, a character like `unicode(U+1F600)` or `unicode(€)`, a media
+/// key like `media(mute)` or `media_repeat(volume_up)`, a mouse key like
+/// `click(left)`, `move(up)` or `scroll(down)`, or a chord like `ctrl+shift+t`.
+pub fn parse_action(action: &str) -> Result<KeyAction> {
+    let Some((name, rest)) = action.trim().split_once('(') else {
+        return parse_chord(action).map(KeyAction::Keyboard);
+    };
+
+    let name = name.trim().to_ascii_lowercase();
+
+    let Some(index) = rest.strip_suffix(')') else {
+        bail!("missing `)` in `{action}`");
+    };
+
+    if name == "unicode" {
+        return parse_char(index.trim())
+            .map(KeyAction::Unicode)
+            .with_context(|| format!("`{action}` needs a character, or a code point like U+00E9"));
+    }
+
+    match name.as_str() {
+        "media" => return parse_consumer(index.trim()).map(KeyAction::Consumer),
+        "media_repeat" => return parse_consumer(index.trim()).map(KeyAction::ConsumerRepeat),
+        "click" | "move" | "scroll" => {
+            return parse_mouse(&name, index.trim()).map(KeyAction::Mouse)
+        }
+        _ => (),
+    }
+
+    let index: u8 = index
+        .trim()
+        .parse()
+        .with_context(|| format!("`{action}` needs a number"))?;
+
+    match name.as_str() {
+        "macro" => {
+            check_index(action, "macro", index, MAX_MACROS)?;
+            return Ok(KeyAction::Macro(index));
+        }
+        "text" => {
+            check_index(action, "text", index, MAX_TEXTS)?;
+            return Ok(KeyAction::Text(index));
+        }
+        _ => (),
+    }
+
+    let Some((_, constructor)) = LAYER_ACTIONS.iter().find(|(n, _)| *n == name) else {
+        bail!("unknown function `{name}` in `{action}`");
+    };
+
+    check_index(action, "layer", index, MAX_LAYERS)?;
+
+    Ok(KeyAction::Layer(constructor(index)))
+}
+
+/// Parses a consumer control name like `volume_up`, or a raw usage like `0xe9`.
+fn parse_consumer(name: &str) -> Result<u16> {
+    let lower = name.to_ascii_lowercase();
+
+    let usage = CONSUMER_ALIASES
+        .iter()
+        .find(|(alias, _)| *alias == lower)
+        .map_or(lower.as_str(), |(_, canonical)| canonical);
+
+    let usage = CONSUMER
+        .iter()
+        .find(|(n, _)| *n == usage)
+        .map(|(_, usage)| *usage)
+        .or_else(|| {
+            let hex = lower.strip_prefix("0x")?;
+            u16::from_str_radix(hex, 16).ok()
+        });
+
+    match usage {
+        Some(usage) if usage != 0 => Ok(usage),
+        _ => bail!("unknown media key `{name}`"),
+    }
+}
+
+pub fn consumer_name(usage: u16) -> String {
+    match CONSUMER.iter().find(|(_, u)| *u == usage) {
+        Some((name, _)) => name.to_string(),
+        None => format!("0x{usage:03x}"),
+    }
+}
+
+/// Parses the argument of a `click`, `move` or `scroll` action.
+fn parse_mouse(function: &str, argument: &str) -> Result<MouseAction> {
+    let lower = argument.to_ascii_lowercase();
+
+    if function == "click" {
+        let button = MOUSE_BUTTONS.iter().position(|name| *name == lower);
+
+        return match button {
+            Some(index) => Ok(MouseAction::Button(index as u8 + 1)),
+            None => bail!(
+                "unknown mouse button `{argument}` (expected {})",
+                MOUSE_BUTTONS.join(", ")
+            ),
+        };
+    }
+
+    let Some((_, direction)) = DIRECTIONS.iter().find(|(name, _)| *name == lower) else {
+        bail!("unknown direction `{argument}` (expected up, down, left or right)");
+    };
+
+    match function {
+        "move" => Ok(MouseAction::Move(*direction)),
+        _ => Ok(MouseAction::Scroll(*direction)),
+    }
+}
+
+fn format_mouse(action: &MouseAction) -> String {
+    let direction = |direction: &Direction| {
+        DIRECTIONS
+            .iter()
+            .find(|(_, d)| d == direction)
+            .map_or("", |(name, _)| name)
+    };
+
+    match action {
+        MouseAction::Button(button) => {
+            let name = MOUSE_BUTTONS.get((*button as usize).wrapping_sub(1));
+            format!("click({})", name.unwrap_or(&"left"))
+        }
+        MouseAction::Move(d) => format!("move({})", direction(d)),
+        MouseAction::Scroll(d) => format!("scroll({})", direction(d)),
+    }
+}
+
+/// Parses a literal character, or a code point written as `U+` and hex digits.
+fn parse_char(c: &str) -> Option<char> {
+    let mut chars = c.chars();
+
+    if let (Some(c), None) = (chars.next(), chars.next()) {
+        return Some(c);
+    }
+
+    let hex = c.strip_prefix("U+").or_else(|| c.strip_prefix("u+"))?;
+
+    u32::from_str_radix(hex, 16).ok().and_then(char::from_u32)
+}
+
+fn check_index(action: &str, kind: &str, index: u8, count: usize) -> Result<()> {
+    if index as usize >= count {
+        bail!(
+            "`{action}` refers to {kind} {index}, but {kind}s are numbered 0 to {}",
+            count - 1
+        );
+    }
+
+    Ok(())
+}
+
+/// Formats an action the way [`parse_action`] expects it.
+pub fn format_action(action: &KeyAction) -> String {
+    let (name, index) = match action {
+        KeyAction::Keyboard(report) => return format_chord(report),
+        KeyAction::Macro(index) => ("macro", index),
+        KeyAction::Text(index) => ("text", index),
+        KeyAction::Unicode(c) => return format!("unicode(U+{:04X})", *c as u32),
+        KeyAction::Consumer(usage) => return format!("media({})", consumer_name(*usage)),
+        KeyAction::ConsumerRepeat(usage) => {
+            return format!("media_repeat({})", consumer_name(*usage))
+        }
+        KeyAction::Mouse(action) => return format_mouse(action),
+        KeyAction::Layer(LayerAction::Momentary(layer)) => ("mo", layer),
+        KeyAction::Layer(LayerAction::Toggle(layer)) => ("tg", layer),
+        KeyAction::Layer(LayerAction::OneShot(layer)) => ("osl", layer),
+        KeyAction::Layer(LayerAction::To(layer)) => ("to", layer),
+        KeyAction::Layer(LayerAction::Default(layer)) => ("df", layer),
+    };
+
+    format!("{name}({index})")
+}
+
+/// Parses a macro step: `press`, `release` or `tap` followed by a chord, or
+/// `delay` followed by a number of milliseconds.
+///
+/// A chord expands to one step per key: `tap ctrl+k` presses control, taps K,
+/// then releases control.
+pub fn parse_step(step: &str) -> Result<Vec<MacroStep>> {
+    let (verb, operand) = step
+        .trim()
+        .split_once(char::is_whitespace)
+        .with_context(|| format!("`{step}` is missing a key or duration"))?;
+
+    let operand = operand.trim();
+
+    if verb.eq_ignore_ascii_case("delay") {
+        let ms = operand
+            .strip_suffix("ms")
+            .unwrap_or(operand)
+            .trim()
+            .parse()
+            .with_context(|| format!("`{step}` needs a delay in milliseconds, up to 65535"))?;
+
+        return Ok(vec![MacroStep::Delay(ms)]);
+    }
+
+    let usages = usages(&parse_chord(operand)?);
+
+    let steps = match verb.to_ascii_lowercase().as_str() {
+        "press" => usages.iter().copied().map(MacroStep::Press).collect(),
+        "release" => usages
+            .iter()
+            .rev()
+            .copied()
+            .map(MacroStep::Release)
+            .collect(),
+        "tap" => {
+            let (last, rest) = usages.split_last().context("empty chord")?;
+
+            rest.iter()
+                .copied()
+                .map(MacroStep::Press)
+                .chain([MacroStep::Tap(*last)])
+                .chain(rest.iter().rev().copied().map(MacroStep::Release))
+                .collect()
+        }
+        _ => {
+            bail!("unknown macro step `{verb}` in `{step}` (expected press, release, tap or delay)")
+        }
+    };
+
+    Ok(steps)
+}
+
+/// Formats a single step the way [`parse_step`] expects it.
+pub fn format_step(step: &MacroStep) -> String {
+    let (verb, usage) = match *step {
+        MacroStep::Press(usage) => ("press", usage),
+        MacroStep::Release(usage) => ("release", usage),
+        MacroStep::Tap(usage) => ("tap", usage),
+        MacroStep::Delay(ms) => return format!("delay {ms}"),
+    };
+
+    let name = match usage {
+        0xE0..=0xE7 => MODIFIERS[(usage - 0xE0) as usize].0.to_string(),
+        _ => key_name(usage),
+    };
+
+    format!("{verb} {name}")
+}
+
+/// Splits a report into HID usages, modifiers (`0xE0` to `0xE7`) first.
+fn usages(report: &[u8; 8]) -> Vec<u8> {
+    let modifiers = (0..8)
+        .filter(|bit| report[0] & (1 << bit) != 0)
+        .map(|bit| 0xE0 + bit);
+
+    modifiers
+        .chain(report[2..].iter().copied().filter(|code| *code != 0))
+        .collect()
+}
+
+fn key_name(code: u8) -> String {
+    match KEYS.iter().find(|(_, c)| *c == code) {
+        Some((name, _)) => name.to_string(),
+        None => format!("0x{code:02x}"),
+    }
+}
+
+/// Parses a chord like `ctrl+shift+t` into an 8-byte boot keyboard report
+/// (modifier byte, reserved byte, then up to six keycodes).
+pub fn parse_chord(chord: &str) -> Result<[u8; 8]> {
+    let mut report = [0_u8; 8];
+    let mut keys = 0;
+
+    for part in chord.split('+').map(str::trim) {
+        let name = part.to_ascii_lowercase();
+
+        if name.is_empty() {
+            bail!("empty key name in `{chord}`");
+        }
+
+        if let Some(bit) = lookup(MODIFIERS, MODIFIER_ALIASES, &name) {
+            report[0] |= bit;
+            continue;
+        }
+
+        let code = lookup(KEYS, KEY_ALIASES, &name).or_else(|| {
+            let hex = name.strip_prefix("0x")?;
+            u8::from_str_radix(hex, 16).ok()
+        });
+
+        let Some(code) = code else {
+            bail!("unknown key name `{part}` in `{chord}`");
+        };
+
+        if report[2..2 + keys].contains(&code) {
+            bail!("`{part}` appears twice in `{chord}`");
+        }
+
+        if keys == 6 {
+            bail!("`{chord}` has more than six non-modifier keys");
+        }
+
+        report[2 + keys] = code;
+        keys += 1;
+    }
+
+    Ok(report)
+}
+
+/// Formats a report back into a chord. Unknown keycodes are written as hex.
+pub fn format_chord(report: &[u8; 8]) -> String {
+    format_keys(report[0], &report[2..])
+}
+
+/// Formats a modifier byte and keycodes as a chord, ignoring zero keycodes.
+pub fn format_keys(modifiers: u8, keys: &[u8]) -> String {
+    let modifiers = MODIFIERS
+        .iter()
+        .filter(|(_, bit)| modifiers & bit != 0)
+        .map(|(name, _)| name.to_string());
+
+    let keys = keys
+        .iter()
+        .filter(|code| **code != 0)
+        .map(|code| key_name(*code));
+
+    modifiers.chain(keys).collect::<Vec<_>>().join("+")
+}
+
+fn lookup(table: &[(&str, u8)], aliases: &[(&str, &str)], name: &str) -> Option<u8> {
+    let name = aliases
+        .iter()
+        .find(|(alias, _)| *alias == name)
+        .map_or(name, |(_, canonical)| canonical);
+
+    table
+        .iter()
+        .find(|(n, _)| *n == name)
+        .map(|(_, code)| *code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_chords() {
+        assert_eq!(
+            parse_chord("ctrl+shift+t").unwrap(),
+            [0x03, 0, 0x17, 0, 0, 0, 0, 0]
+        );
+        assert_eq!(
+            parse_chord("Cmd + Space").unwrap(),
+            [0x08, 0, 0x2C, 0, 0, 0, 0, 0]
+        );
+        assert_eq!(parse_chord("f13").unwrap(), [0, 0, 0x68, 0, 0, 0, 0, 0]);
+        assert_eq!(parse_chord("0x87").unwrap(), [0, 0, 0x87, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn rejects_bad_chords() {
+        assert!(parse_chord("ctrl+nope").is_err());
+        assert!(parse_chord("ctrl++t").is_err());
+        assert!(parse_chord("a+a").is_err());
+        assert!(parse_chord("a+b+c+d+e+f+g").is_err());
+        assert!(parse_chord("0xctrl").is_err());
+    }
+
+    #[test]
+    fn parses_layer_actions() {
+        assert_eq!(
+            parse_action("mo(1)").unwrap(),
+            KeyAction::Layer(LayerAction::Momentary(1))
+        );
+        assert_eq!(
+            parse_action(" OSL( 5 ) ").unwrap(),
+            KeyAction::Layer(LayerAction::OneShot(5))
+        );
+
+        assert_eq!(parse_action("macro(15)").unwrap(), KeyAction::Macro(15));
+        assert_eq!(parse_action("text(7)").unwrap(), KeyAction::Text(7));
+        assert_eq!(
+            parse_action("unicode(U+1F600)").unwrap(),
+            KeyAction::Unicode('😀')
+        );
+        assert_eq!(parse_action("unicode(€)").unwrap(), KeyAction::Unicode('€'));
+        assert_eq!(format_action(&KeyAction::Unicode('é')), "unicode(U+00E9)");
+
+        assert_eq!(
+            parse_action("media(Mute)").unwrap(),
+            KeyAction::Consumer(0xE2)
+        );
+        assert_eq!(
+            parse_action("media(0x29f)").unwrap(),
+            KeyAction::Consumer(0x29F)
+        );
+        assert_eq!(
+            parse_action("media_repeat(vol_up)").unwrap(),
+            KeyAction::ConsumerRepeat(0xE9)
+        );
+        assert_eq!(
+            format_action(&KeyAction::Consumer(0x223)),
+            "media(browser_home)"
+        );
+        assert_eq!(format_action(&KeyAction::Consumer(0x29F)), "media(0x29f)");
+
+        assert_eq!(
+            parse_action("click(Middle)").unwrap(),
+            KeyAction::Mouse(MouseAction::Button(3))
+        );
+        assert_eq!(
+            parse_action("scroll(left)").unwrap(),
+            KeyAction::Mouse(MouseAction::Scroll(Direction::Left))
+        );
+
+        for action in ["click(forward)", "move(down)", "scroll(up)"] {
+            assert_eq!(format_action(&parse_action(action).unwrap()), action);
+        }
+
+        assert!(parse_action("click(up)").is_err());
+        assert!(parse_action("move(sideways)").is_err());
+        assert!(parse_action("media(loud)").is_err());
+        assert!(parse_action("media(0x0)").is_err());
+        assert!(parse_action("mo(6)").is_err());
+        assert!(parse_action("unicode(U+D800)").is_err());
+        assert!(parse_action("unicode(ab)").is_err());
+        assert!(parse_action("macro(16)").is_err());
+        assert!(parse_action("mo(x)").is_err());
+        assert!(parse_action("mo(1").is_err());
+        assert!(parse_action("layer(1)").is_err());
+    }
+
+    #[test]
+    fn formats_round_trip() {
+        for chord in ["ctrl+shift+t", "gui+space", "ralt+e", "a+b+c+d+e+f", "0x87"] {
+            assert_eq!(format_chord(&parse_chord(chord).unwrap()), chord);
+        }
+
+        for action in [
+            "shift+a", "mo(0)", "tg(1)", "osl(2)", "to(3)", "df(4)", "macro(5)",
+        ] {
+            assert_eq!(format_action(&parse_action(action).unwrap()), action);
+        }
+
+        for step in [
+            "press ctrl",
+            "release rgui",
+            "tap k",
+            "tap 0x87",
+            "delay 250",
+        ] {
+            assert_eq!(format_step(&parse_step(step).unwrap()[0]), step);
+        }
+    }
+
+    #[test]
+    fn parses_steps() {
+        use MacroStep::*;
+
+        assert_eq!(
+            parse_step("tap ctrl+k").unwrap(),
+            [Press(0xE0), Tap(0x0E), Release(0xE0)]
+        );
+        assert_eq!(
+            parse_step("press ctrl+shift").unwrap(),
+            [Press(0xE0), Press(0xE1)]
+        );
+        assert_eq!(
+            parse_step("release ctrl+shift").unwrap(),
+            [Release(0xE1), Release(0xE0)]
+        );
+        assert_eq!(parse_step(" Delay  20ms ").unwrap(), [Delay(20)]);
+
+        assert!(parse_step("tap").is_err());
+        assert!(parse_step("hold k").is_err());
+        assert!(parse_step("delay 70000").is_err());
+    }
+}
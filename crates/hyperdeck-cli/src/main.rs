@@ -0,0 +1,167 @@
+//! Host-side configurator for the Hyperdeck.
+//!
+//! Keymaps are written in TOML (see `keymap.example.toml`), checked against the
+//! device's limits, and uploaded over the configuration serial port. They can
+//! also be tried out on a simulated device first; see [`sim`].
+
+mod client;
+mod image;
+mod keymap;
+mod keys;
+mod sim;
+
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use clap::{Parser, Subcommand};
+use hyperdeck_core::config::Config;
+
+use crate::client::Client;
+use crate::keymap::Keymap;
+use crate::sim::Format;
+
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Checks a keymap for errors without touching the device.
+    Check { keymap: PathBuf },
+    /// Compiles a keymap to the device's binary configuration format.
+    Compile {
+        keymap: PathBuf,
+        #[arg(short, long)]
+        output: PathBuf,
+    },
+    /// Uploads a keymap to the device and saves it to flash.
+    Upload {
+        keymap: PathBuf,
+        /// The device's serial port, such as `/dev/ttyACM0` or `COM3`.
+        #[arg(short, long)]
+        port: String,
+    },
+    /// Downloads the device's configuration as a keymap.
+    Download {
+        #[arg(short, long)]
+        port: String,
+        /// Where to write the keymap; printed to stdout if not given.
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Shows how many HID reports the device has sent, dropped or had to hold back.
+    Stats {
+        #[arg(short, long)]
+        port: String,
+    },
+    /// Runs a script of key presses against a simulated device, printing the HID
+    /// reports it sends.
+    Simulate {
+        keymap: PathBuf,
+        /// The script to run; read from stdin if not given.
+        #[arg(short, long)]
+        script: Option<PathBuf>,
+        /// Where to write snapshots.
+        #[arg(short, long, default_value = ".")]
+        output: PathBuf,
+        #[arg(short, long, value_enum, default_value_t = Format::Png)]
+        format: Format,
+    },
+}
+
+fn main() -> Result<()> {
+    match Cli::parse().command {
+        Command::Check { keymap } => {
+            load(&keymap)?;
+            println!("{} is valid", keymap.display());
+        }
+        Command::Compile { keymap, output } => {
+            let config = load(&keymap)?;
+
+            let mut buf = [0_u8; Config::MAX_ENCODED_LEN];
+            let length = config
+                .encode(&mut buf)
+                .map_err(|err| anyhow::anyhow!("couldn't encode the configuration: {err:?}"))?;
+
+            fs::write(&output, &buf[..length])
+                .with_context(|| format!("couldn't write {}", output.display()))?;
+        }
+        Command::Upload { keymap, port } => {
+            let config = load(&keymap)?;
+            connect(&port)?.upload(&config)?;
+        }
+        Command::Download { port, output } => {
+            let config = connect(&port)?.download()?;
+            let toml = Keymap::decompile(&config).to_toml()?;
+
+            match output {
+                Some(path) => fs::write(&path, toml)
+                    .with_context(|| format!("couldn't write {}", path.display()))?,
+                None => print!("{toml}"),
+            }
+        }
+        Command::Stats { port } => {
+            let stats = connect(&port)?.stats()?;
+
+            println!("reports sent:     {}", stats.sent);
+            println!("reports rejected: {}", stats.rejected);
+            println!("queue overflows:  {}", stats.overflows);
+            println!("queue peak:       {}", stats.peak);
+            println!("changes dropped:  {}", stats.dropped);
+        }
+        Command::Simulate {
+            keymap,
+            script,
+            output,
+            format,
+        } => {
+            let config = load(&keymap)?;
+
+            let source = match script {
+                Some(path) => fs::read_to_string(&path)
+                    .with_context(|| format!("couldn't read {}", path.display()))?,
+                None => {
+                    let mut source = String::new();
+                    io::stdin()
+                        .read_to_string(&mut source)
+                        .context("couldn't read the script from stdin")?;
+                    source
+                }
+            };
+
+            let script = sim::parse(&source)?;
+
+            fs::create_dir_all(&output)
+                .with_context(|| format!("couldn't create {}", output.display()))?;
+            sim::run(config, &script, &output, format, &mut io::stdout().lock())?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads and compiles the keymap at `path`.
+fn load(path: &Path) -> Result<Config> {
+    let source =
+        fs::read_to_string(path).with_context(|| format!("couldn't read {}", path.display()))?;
+
+    Keymap::parse(&source)
+        .and_then(|keymap| keymap.compile())
+        .with_context(|| format!("in {}", path.display()))
+}
+
+fn connect(path: &str) -> Result<Client<Box<dyn serialport::SerialPort>>> {
+    // CDC ignores the baud rate, but the API insists on one.
+    let port = serialport::new(path, 115_200)
+        .timeout(Duration::from_secs(2))
+        .open()
+        .with_context(|| format!("couldn't open {path}"))?;
+
+    Client::connect(port).with_context(|| format!("couldn't talk to a Hyperdeck on {path}"))
+}
@@ -0,0 +1,536 @@
+//! A headless simulator of the whole device, for trying out keymaps and screens
+//! without a Pico.
+//!
+//! The simulator runs the firmware's own main loop ([`Device`]) against a
+//! simulated keypad and display, one pass per simulated millisecond, as driven
+//! by a script. Scripts have one command per line; blank lines and anything
+//! after a `#` are ignored.
+//!
+//! ```text
+//! press 0 1         # Presses keys, by index (0 to 15, row by row)
+//! release 1         # Releases them
+//! tap 4             # Presses keys, waits 50 ms, then releases them
+//! wait 300          # Lets 300 ms pass
+//! leds caps num     # Lights keyboard LEDs, as the host would; `leds` alone
+//!                   # turns them all off
+//! protocol boot     # Switches keyboard reports to the boot (or report) protocol
+//! snapshot layer-1  # Writes out the screen and the key colors
+//! ```
+//!
+//! Presses and releases take effect on the next pass, so they take a
+//! millisecond each. Every report the device sends is logged along with when
+//! it was sent. Snapshots are written to `<name>.png` (the screen) and
+//! `<name>-keys.png` (the key colors), or as PPMs. Key colors are shown at full
+//! brightness, whatever the configured brightness is.
+
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use embedded_graphics::pixelcolor::Rgb888;
+use embedded_graphics::prelude::*;
+use hyperdeck_core::config::Config;
+use hyperdeck_core::device::{Device, Host};
+use hyperdeck_core::hid::Report;
+use hyperdeck_core::keyboard::{Output, Protocol};
+use hyperdeck_core::keypad::{Keypad, NUM_KEYS};
+use hyperdeck_core::leds::{Led, Leds};
+use hyperdeck_core::mock::{Apa102, Apa102Cs, Apa102Spi, Canvas, Tca9555};
+use hyperdeck_core::screen::{Screen, HEIGHT, WIDTH};
+use hyperdeck_core::time::{Clock, Duration, FakeClock};
+
+use crate::image::Image;
+use crate::keys::{consumer_name, format_chord, format_keys};
+
+/// How long `tap` holds keys down for.
+const TAP_TIME: u64 = 50;
+
+/// Size of each key in key color snapshots, and of the gaps around them.
+const KEY_SIZE: u32 = 32;
+const KEY_GAP: u32 = 4;
+/// Keys per row.
+const COLUMNS: u32 = 4;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Command {
+    Press(u16),
+    Release(u16),
+    Tap(u16),
+    Wait(u64),
+    Leds(Leds),
+    Protocol(Protocol),
+    Snapshot(String),
+}
+
+/// Parses a script, one [`Command`] per line.
+pub fn parse(script: &str) -> Result<Vec<Command>> {
+    let mut commands = Vec::new();
+
+    for (i, line) in script.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default();
+        let mut words = line.split_whitespace();
+
+        let Some(command) = words.next() else {
+            continue;
+        };
+
+        let args: Vec<_> = words.collect();
+        let command =
+            parse_command(command, &args).with_context(|| format!("on line {}", i + 1))?;
+
+        commands.push(command);
+    }
+
+    Ok(commands)
+}
+
+fn parse_command(command: &str, args: &[&str]) -> Result<Command> {
+    let command = match command {
+        "press" => Command::Press(parse_keys(args)?),
+        "release" => Command::Release(parse_keys(args)?),
+        "tap" => Command::Tap(parse_keys(args)?),
+        "wait" => match args {
+            [ms] => Command::Wait(ms.parse().with_context(|| format!("bad time `{ms}`"))?),
+            _ => bail!("`wait` takes a time in milliseconds"),
+        },
+        "leds" => Command::Leds(parse_leds(args)?),
+        "protocol" => match args {
+            ["boot"] => Command::Protocol(Protocol::Boot),
+            ["report"] => Command::Protocol(Protocol::Report),
+            _ => bail!("`protocol` takes `boot` or `report`"),
+        },
+        "snapshot" => match args {
+            [name] => Command::Snapshot(name.to_string()),
+            _ => bail!("`snapshot` takes a name"),
+        },
+        _ => bail!("unknown command `{command}`"),
+    };
+
+    Ok(command)
+}
+
+/// Parses key indices into a bitmask.
+fn parse_keys(args: &[&str]) -> Result<u16> {
+    if args.is_empty() {
+        bail!("no keys given");
+    }
+
+    args.iter()
+        .try_fold(0, |keys, arg| match arg.parse::<usize>() {
+            Ok(key) if key < NUM_KEYS => Ok(keys | 1 << key),
+            _ => bail!("`{arg}` isn't a key between 0 and {}", NUM_KEYS - 1),
+        })
+}
+
+fn parse_leds(args: &[&str]) -> Result<Leds> {
+    args.iter().try_fold(Leds(0), |leds, arg| {
+        let led = match arg.to_ascii_lowercase().as_str() {
+            "num" => Led::NumLock,
+            "caps" => Led::CapsLock,
+            "scroll" => Led::ScrollLock,
+            "compose" => Led::Compose,
+            "kana" => Led::Kana,
+            _ => bail!("unknown LED `{arg}`; expected num, caps, scroll, compose or kana"),
+        };
+
+        Ok(Leds(leds.0 | 1 << led as u8))
+    })
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    Png,
+    Ppm,
+}
+
+impl Format {
+    fn extension(self) -> &'static str {
+        match self {
+            Self::Png => "png",
+            Self::Ppm => "ppm",
+        }
+    }
+
+    fn encode(self, image: &Image) -> Vec<u8> {
+        match self {
+            Self::Png => image.to_png(),
+            Self::Ppm => image.to_ppm(),
+        }
+    }
+}
+
+/// A report the device sent.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Sent {
+    /// A boot protocol keyboard report.
+    Boot([u8; 8]),
+    Report(Report),
+}
+
+impl std::fmt::Display for Sent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let or_none = |s: String| if s.is_empty() { "-".to_string() } else { s };
+
+        match *self {
+            Self::Boot(report) => write!(f, "boot keyboard  {}", or_none(format_chord(&report))),
+            Self::Report(Report::Keyboard(report)) => {
+                let keys: Vec<_> = report.pressed().collect();
+                write!(
+                    f,
+                    "keyboard  {}",
+                    or_none(format_keys(report.modifiers, &keys))
+                )
+            }
+            Self::Report(Report::Consumer(0)) => write!(f, "consumer  -"),
+            Self::Report(Report::Consumer(usage)) => {
+                write!(f, "consumer  {}", consumer_name(usage))
+            }
+            Self::Report(Report::System(usage)) => write!(f, "system  0x{usage:02x}"),
+            Self::Report(Report::Mouse(report)) => write!(
+                f,
+                "mouse  buttons={:05b} x={} y={} wheel={} pan={}",
+                report.buttons, report.x, report.y, report.wheel, report.pan
+            ),
+        }
+    }
+}
+
+/// Runs `script` against `config`, logging reports to `log` and writing
+/// snapshots into `output`.
+pub fn run(
+    config: Config,
+    script: &[Command],
+    output: &Path,
+    format: Format,
+    log: &mut impl Write,
+) -> Result<()> {
+    let mut sim = Simulator::new(config);
+
+    for command in script {
+        if let Command::Snapshot(name) = command {
+            for (suffix, image) in [("", sim.screen()), ("-keys", sim.keys())] {
+                let path = output.join(format!("{name}{suffix}.{}", format.extension()));
+
+                fs::write(&path, format.encode(&image))
+                    .with_context(|| format!("couldn't write {}", path.display()))?;
+            }
+        } else {
+            sim.run(command);
+        }
+
+        for (ms, sent) in sim.take_sent() {
+            writeln!(log, "{ms:>8} ms  {sent}")?;
+        }
+    }
+
+    Ok(())
+}
+
+pub struct Simulator {
+    config: Config,
+    device: Device,
+    keypad: Keypad<Tca9555, Apa102Spi, Apa102Cs>,
+    /// Shares its keys with the keypad's.
+    expander: Tca9555,
+    /// The keys that are down.
+    pressed: u16,
+    leds: Apa102Spi,
+    /// The last frame sent to the LEDs.
+    frame: Vec<u8>,
+    clock: FakeClock,
+    host: SimHost,
+}
+
+impl Simulator {
+    /// Starts the device, as far as showing the home screen.
+    pub fn new(config: Config) -> Self {
+        let expander = Tca9555::new();
+        let (leds, cs) = Apa102::chain();
+        let mut keypad = Keypad::new(expander.clone(), leds.clone(), cs);
+
+        keypad.set_brightness(config.brightness.keypad_f32());
+        keypad.set_debounce(config.debounce);
+        keypad.set_repeat(config.repeat);
+
+        let device = Device::new();
+        let mut host = SimHost::new();
+
+        keypad.set_colors(device.key_colors(&config));
+        host.show(device.home(&config).unwrap_or(Screen::Splash { seed: 1 }));
+
+        let mut sim = Self {
+            config,
+            device,
+            keypad,
+            expander,
+            pressed: 0,
+            leds,
+            frame: Vec::new(),
+            clock: FakeClock::new(),
+            host,
+        };
+
+        // Light up the keys.
+        sim.step();
+        sim
+    }
+
+    pub fn run(&mut self, command: &Command) {
+        match *command {
+            Command::Press(keys) => self.set_pressed(self.pressed | keys),
+            Command::Release(keys) => self.set_pressed(self.pressed & !keys),
+            Command::Tap(keys) => {
+                self.set_pressed(self.pressed | keys);
+                self.wait(TAP_TIME - 1);
+                self.set_pressed(self.pressed & !keys);
+            }
+            Command::Wait(ms) => self.wait(ms),
+            Command::Leds(leds) => {
+                self.host.leds = leds;
+                self.step();
+            }
+            Command::Protocol(protocol) => {
+                self.host.protocol = protocol;
+                self.step();
+            }
+            // Snapshots don't change anything.
+            Command::Snapshot(_) => (),
+        }
+    }
+
+    fn set_pressed(&mut self, keys: u16) {
+        self.pressed = keys;
+        self.expander.set_pressed(keys);
+        self.step();
+    }
+
+    fn wait(&mut self, ms: u64) {
+        for _ in 0..ms {
+            self.step();
+        }
+    }
+
+    /// Runs one pass of the main loop, a millisecond after the last.
+    fn step(&mut self) {
+        self.clock.advance(Duration::millis(1));
+
+        let now = self.clock.now().duration_since_epoch().to_millis();
+
+        self.device
+            .update(&self.config, &mut self.keypad, &self.clock, &mut self.host)
+            .expect("the simulated keypad never fails");
+
+        if let Some(frame) = self.leds.take_frames().pop() {
+            self.frame = frame;
+        }
+
+        for sent in self.host.sent.drain(..) {
+            self.host.log.push((now, sent));
+        }
+    }
+
+    /// The reports sent since the last call, with when they were sent (in
+    /// milliseconds since the device started).
+    pub fn take_sent(&mut self) -> Vec<(u64, Sent)> {
+        std::mem::take(&mut self.host.log)
+    }
+
+    /// The screen, as it is now.
+    pub fn screen(&self) -> Image {
+        let mut image = Image::new(WIDTH as u32, HEIGHT as u32, Rgb888::BLACK);
+
+        for (pixel, color) in image.pixels.iter_mut().zip(&self.host.display.pixels) {
+            *pixel = Rgb888::from(*color);
+        }
+
+        image
+    }
+
+    /// The key colors, as last sent to the LEDs, laid out like the keypad.
+    pub fn keys(&self) -> Image {
+        let size = COLUMNS * KEY_SIZE + (COLUMNS + 1) * KEY_GAP;
+        let mut image = Image::new(size, size, Rgb888::new(32, 32, 32));
+
+        // Skip the start frame, then read one blue, green and red per LED, after
+        // its brightness byte.
+        let colors = self
+            .frame
+            .get(4..4 + NUM_KEYS * 4)
+            .unwrap_or_default()
+            .chunks(4)
+            .map(|led| Rgb888::new(led[3], led[2], led[1]));
+
+        for (i, color) in colors.enumerate() {
+            let (row, column) = (i as u32 / COLUMNS, i as u32 % COLUMNS);
+
+            image.fill_square(
+                KEY_GAP + column * (KEY_SIZE + KEY_GAP),
+                KEY_GAP + row * (KEY_SIZE + KEY_GAP),
+                KEY_SIZE,
+                color,
+            );
+        }
+
+        image
+    }
+}
+
+struct SimHost {
+    display: Canvas,
+    leds: Leds,
+    protocol: Protocol,
+    /// Reports sent during the current pass.
+    sent: Vec<Sent>,
+    log: Vec<(u64, Sent)>,
+}
+
+impl SimHost {
+    fn new() -> Self {
+        Self {
+            display: Canvas::new(),
+            leds: Leds(0),
+            protocol: Protocol::Report,
+            sent: Vec::new(),
+            log: Vec::new(),
+        }
+    }
+}
+
+impl Host for SimHost {
+    fn send_keyboard(&mut self, output: Output) -> bool {
+        self.sent.push(match output {
+            Output::Boot(report) => Sent::Boot(report),
+            Output::Report(report) => Sent::Report(Report::Keyboard(report)),
+        });
+        true
+    }
+
+    fn send(&mut self, report: Report) -> bool {
+        self.sent.push(Sent::Report(report));
+        true
+    }
+
+    fn leds(&self) -> Leds {
+        self.leds
+    }
+
+    fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    fn show(&mut self, screen: Screen) {
+        let _ = screen.draw(&mut self.display);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hyperdeck_core::screen::LAYER_COLORS;
+
+    use super::*;
+    use crate::keymap::Keymap;
+
+    const EXAMPLE: &str = include_str!("../keymap.example.toml");
+
+    fn simulator() -> Simulator {
+        Simulator::new(Keymap::parse(EXAMPLE).unwrap().compile().unwrap())
+    }
+
+    fn run(sim: &mut Simulator, script: &str) -> Vec<String> {
+        for command in parse(script).unwrap() {
+            sim.run(&command);
+        }
+
+        sim.take_sent()
+            .into_iter()
+            .map(|(ms, sent)| format!("{ms} {sent}"))
+            .collect()
+    }
+
+    #[test]
+    fn parses_scripts() {
+        let script = "
+            # Comments and blank lines are skipped
+
+            press 0 15
+            tap 3  # after commands too
+            wait 250
+            leds caps KANA
+            leds
+            protocol boot
+            snapshot home
+        ";
+
+        assert_eq!(
+            parse(script).unwrap(),
+            [
+                Command::Press(0x8001),
+                Command::Tap(0b1000),
+                Command::Wait(250),
+                Command::Leds(Leds(0b10010)),
+                Command::Leds(Leds(0)),
+                Command::Protocol(Protocol::Boot),
+                Command::Snapshot("home".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_bad_scripts() {
+        for script in ["press", "press 16", "wait soon", "leds shift", "jump 1"] {
+            assert!(parse(script).is_err(), "{script}");
+        }
+
+        let err = parse("tap 1\n\ntap x").unwrap_err();
+        assert_eq!(err.to_string(), "on line 3");
+    }
+
+    #[test]
+    fn sends_reports() {
+        let mut sim = simulator();
+
+        // The device starts by telling the host nothing's pressed.
+        assert_eq!(run(&mut sim, ""), ["1 keyboard  -"]);
+
+        // Key 0 can be held, so the tap is only performed once it's released,
+        // which debouncing holds back for another 5 ms.
+        assert_eq!(
+            run(&mut sim, "tap 0\nwait 20"),
+            ["57 keyboard  ctrl+shift+t", "57 keyboard  -"]
+        );
+
+        assert_eq!(
+            run(&mut sim, "protocol boot\ntap 0\nwait 20"),
+            [
+                "73 boot keyboard  -",
+                "129 boot keyboard  ctrl+shift+t",
+                "129 boot keyboard  -"
+            ]
+        );
+    }
+
+    #[test]
+    fn renders_the_device() {
+        let mut sim = simulator();
+
+        let screen = sim.screen();
+        assert_eq!(screen.pixels[0], Rgb888::from(LAYER_COLORS[0]));
+
+        // Pressed keys light up in their pressed color.
+        let key = |sim: &Simulator, i: u32| {
+            let (row, column) = (i / COLUMNS, i % COLUMNS);
+            let (x, y) = (
+                KEY_GAP + column * (KEY_SIZE + KEY_GAP),
+                KEY_GAP + row * (KEY_SIZE + KEY_GAP),
+            );
+            let keys = sim.keys();
+            keys.pixels[(y * keys.width + x) as usize]
+        };
+
+        assert_eq!(key(&sim, 0), Rgb888::new(0x10, 0x10, 0x10));
+        run(&mut sim, "press 0\nwait 10");
+        assert_eq!(key(&sim, 0), Rgb888::new(0x00, 0xFF, 0x00));
+    }
+}
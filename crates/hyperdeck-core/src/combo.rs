@@ -0,0 +1,539 @@
+//! Resolves combos: sets of keys that perform their own action when pressed
+//! together, instead of their individual ones.
+//!
+//! [`Combos`] sits in front of the rest of the [`Engine`](crate::engine::Engine).
+//! Pressing a key that's part of an active combo holds the press back, along with
+//! any further presses of keys from the same combos, until one of these happens:
+//!
+//! - The keys down match a combo exactly, and no bigger one could still be
+//!   completed. It fires straight away.
+//! - A key that can't complete a combo with them is pressed, any key is
+//!   released, or the [combo term](crate::config::Tapping::combo_term) runs out.
+//!   If the keys down match a combo by then, it fires anyway; if not, the presses
+//!   are let through as they were, with their original times, ahead of the event
+//!   that let them through.
+//!
+//! A combo is performed as its lowest key (see [`Combo::key`]), and lasts until
+//! the first of its keys is released. Releasing the others does nothing, since the
+//! engine never saw them pressed.
+
+use heapless::Vec;
+
+use crate::config::{Combo, KeyAction, LAYER_KEYS, MAX_COMBO_KEYS};
+use crate::keypad::{KeyEvent, NUM_KEYS};
+use crate::time::{Duration, Instant};
+
+/// A key event, as far as the rest of the engine is concerned.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Event {
+    /// An event from a key that's not part of a combo.
+    Key(u8, KeyEvent),
+    /// A combo fired, to be performed as `key`.
+    ComboPressed { key: u8, action: KeyAction },
+    /// A combo performed as `key` was released.
+    ComboReleased { key: u8 },
+}
+
+impl Event {
+    /// The key the event is for, and what it amounts to for that key.
+    pub fn key_event(&self) -> (u8, KeyEvent) {
+        match *self {
+            Event::Key(id, event) => (id, event),
+            Event::ComboPressed { key, .. } => (key, KeyEvent::Pressed),
+            Event::ComboReleased { key } => (key, KeyEvent::Released),
+        }
+    }
+}
+
+/// The events let through by a single call, with when they happened.
+///
+/// At most every held back press comes out at once, followed by the event that
+/// let them through.
+pub type Events = Vec<(Event, Instant), { MAX_COMBO_KEYS + 1 }>;
+
+pub struct Combos {
+    /// Presses held back while they could still be part of a combo, oldest first.
+    pending: Vec<(u8, Instant), MAX_COMBO_KEYS>,
+    /// When the pending presses stop waiting for the rest of a combo.
+    deadline: Instant,
+    /// For every key down as part of a fired combo, the key it's performed as.
+    fired: [Option<u8>; NUM_KEYS],
+    /// Fired combos that haven't been released yet, by the key they're performed as.
+    active: u16,
+}
+
+impl Combos {
+    pub const fn new() -> Self {
+        Self {
+            pending: Vec::new(),
+            deadline: Instant::from_ticks(0),
+            fired: [None; NUM_KEYS],
+            active: 0,
+        }
+    }
+
+    /// Feeds an event from key `id` at `now` through `combos`, returning whatever
+    /// it lets through. Presses that could start a combo have to wait for `term`.
+    pub fn feed(
+        &mut self,
+        combos: &[Combo],
+        term: Duration,
+        id: u8,
+        event: KeyEvent,
+        now: Instant,
+    ) -> Events {
+        let mut events = self.poll(combos, now);
+
+        match event {
+            KeyEvent::Pressed => self.press(combos, term, id, now, &mut events),
+            KeyEvent::Held | KeyEvent::Repeat if self.is_held_back(id) => (),
+            KeyEvent::Held | KeyEvent::Repeat => push(&mut events, Event::Key(id, event), now),
+            KeyEvent::Released => self.release(combos, id, now, &mut events),
+        }
+
+        events
+    }
+
+    /// Lets the pending presses through if the combo term is up.
+    pub fn poll(&mut self, combos: &[Combo], now: Instant) -> Events {
+        let mut events = Events::new();
+
+        if !self.pending.is_empty() && now >= self.deadline {
+            self.resolve(combos, &mut events);
+        }
+
+        events
+    }
+
+    fn press(
+        &mut self,
+        combos: &[Combo],
+        term: Duration,
+        id: u8,
+        now: Instant,
+        events: &mut Events,
+    ) {
+        let keys = self.pending_keys() | bit(id);
+
+        if bit(id) == 0 || !usable(combos).any(|combo| combo.keys & keys == keys) {
+            // The press can't complete a combo with the pending ones, but might
+            // still start one of its own.
+            if !self.pending.is_empty() {
+                self.resolve(combos, events);
+                return self.press(combos, term, id, now, events);
+            }
+
+            return push(events, Event::Key(id, KeyEvent::Pressed), now);
+        }
+
+        if self.pending.is_empty() {
+            self.deadline = now + term;
+        }
+
+        // Usable combos have room for all their keys, so this always fits.
+        let _ = self.pending.push((id, now));
+
+        if !usable(combos).any(|combo| combo.keys & keys == keys && combo.keys != keys) {
+            self.resolve(combos, events);
+        }
+    }
+
+    fn release(&mut self, combos: &[Combo], id: u8, now: Instant, events: &mut Events) {
+        // Keep the events in order, even if it costs a combo.
+        if !self.pending.is_empty() {
+            self.resolve(combos, events);
+        }
+
+        match self.fired.get_mut(id as usize).and_then(Option::take) {
+            Some(key) if self.active & bit(key) != 0 => {
+                self.active &= !bit(key);
+                push(events, Event::ComboReleased { key }, now);
+            }
+            Some(_) => (),
+            None => push(events, Event::Key(id, KeyEvent::Released), now),
+        }
+    }
+
+    /// Fires the combo matching the pending presses, or lets them through if none
+    /// does.
+    fn resolve(&mut self, combos: &[Combo], events: &mut Events) {
+        let keys = self.pending_keys();
+
+        match usable(combos).find(|combo| combo.keys == keys) {
+            Some(combo) => {
+                let key = combo.key();
+                let at = self.pending.last().map_or(self.deadline, |&(_, at)| at);
+
+                for &(id, _) in self.pending.iter() {
+                    self.fired[id as usize] = Some(key);
+                }
+
+                self.active |= bit(key);
+                push(
+                    events,
+                    Event::ComboPressed {
+                        key,
+                        action: combo.action,
+                    },
+                    at,
+                );
+            }
+            None => {
+                for &(id, at) in self.pending.iter() {
+                    push(events, Event::Key(id, KeyEvent::Pressed), at);
+                }
+            }
+        }
+
+        self.pending.clear();
+    }
+
+    /// Whether key `id`'s press hasn't been let through as a plain key.
+    fn is_held_back(&self, id: u8) -> bool {
+        let fired = self.fired.get(id as usize).is_some_and(Option::is_some);
+
+        fired || self.pending_keys() & bit(id) != 0
+    }
+
+    fn pending_keys(&self) -> u16 {
+        self.pending.iter().fold(0, |keys, &(id, _)| keys | bit(id))
+    }
+}
+
+impl Default for Combos {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The combos that can actually fire: ones with between two and
+/// [`MAX_COMBO_KEYS`] keys, none of them reserved.
+fn usable(combos: &[Combo]) -> impl Iterator<Item = &Combo> {
+    combos.iter().filter(|combo| {
+        let count = combo.keys.count_ones() as usize;
+
+        (2..=MAX_COMBO_KEYS).contains(&count) && combo.keys >> LAYER_KEYS == 0
+    })
+}
+
+/// The bit for key `id`; zero for keys that can't be in a combo.
+fn bit(id: u8) -> u16 {
+    match (id as usize) < LAYER_KEYS {
+        true => 1 << id,
+        false => 0,
+    }
+}
+
+fn push(events: &mut Events, event: Event, at: Instant) {
+    // See [`Events`] for why this always fits.
+    let _ = events.push((event, at));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use super::*;
+    use crate::keypad::KeyEvent::*;
+
+    const MUTE: KeyAction = KeyAction::Consumer(0xE2);
+    const PLAY: KeyAction = KeyAction::Consumer(0xCD);
+    const NEXT: KeyAction = KeyAction::Consumer(0xB5);
+
+    const TERM: Duration = Duration::millis(50);
+
+    fn combo(keys: &[u8], action: KeyAction) -> Combo {
+        Combo {
+            keys: keys.iter().fold(0, |mask, &id| mask | 1 << id),
+            action,
+        }
+    }
+
+    fn at(ms: u64) -> Instant {
+        Instant::from_ticks(ms * 1000)
+    }
+
+    /// Feeds `events` through new combos at the given times in milliseconds,
+    /// polling every millisecond in between, and returns what comes out.
+    fn play(combos: &[Combo], events: &[(u64, u8, KeyEvent)]) -> Vec<(u64, Event)> {
+        let mut resolver = Combos::new();
+        let mut out = Vec::new();
+        let mut clock = 0;
+        let mut collect = |events: Events| {
+            for (event, at) in events {
+                out.push((at.ticks() / 1000, event));
+            }
+        };
+
+        for &(time, id, event) in events {
+            while clock < time {
+                clock += 1;
+                collect(resolver.poll(combos, at(clock)));
+            }
+
+            collect(resolver.feed(combos, TERM, id, event, at(time)));
+        }
+
+        for _ in 0..100 {
+            clock += 1;
+            collect(resolver.poll(combos, at(clock)));
+        }
+
+        out
+    }
+
+    fn key(id: u8, event: KeyEvent) -> Event {
+        Event::Key(id, event)
+    }
+
+    #[test]
+    fn other_keys_pass_straight_through() {
+        let combos = [combo(&[12, 13], MUTE)];
+
+        assert_eq!(
+            play(
+                &combos,
+                &[(0, 3, Pressed), (10, 3, Held), (20, 3, Released)]
+            ),
+            [
+                (0, key(3, Pressed)),
+                (10, key(3, Held)),
+                (20, key(3, Released))
+            ]
+        );
+    }
+
+    #[test]
+    fn keys_pressed_together_fire_the_combo() {
+        let combos = [combo(&[12, 13], MUTE)];
+
+        assert_eq!(
+            play(
+                &combos,
+                &[
+                    (0, 13, Pressed),
+                    (20, 12, Pressed),
+                    (100, 13, Held),
+                    (200, 12, Released),
+                    (210, 13, Released),
+                ]
+            ),
+            [
+                (
+                    20,
+                    Event::ComboPressed {
+                        key: 12,
+                        action: MUTE
+                    }
+                ),
+                (200, Event::ComboReleased { key: 12 }),
+            ]
+        );
+    }
+
+    #[test]
+    fn slow_presses_are_let_through() {
+        let combos = [combo(&[12, 13], MUTE)];
+
+        assert_eq!(
+            play(
+                &combos,
+                &[
+                    (0, 12, Pressed),
+                    (60, 13, Pressed),
+                    (70, 12, Released),
+                    (80, 13, Released)
+                ]
+            ),
+            [
+                (0, key(12, Pressed)),
+                (60, key(13, Pressed)),
+                (70, key(12, Released)),
+                (80, key(13, Released)),
+            ]
+        );
+
+        // A release lets pending presses through too, so they stay in order.
+        assert_eq!(
+            play(
+                &combos,
+                &[
+                    (0, 3, Pressed),
+                    (10, 12, Pressed),
+                    (20, 3, Released),
+                    (30, 13, Pressed)
+                ]
+            ),
+            [
+                (0, key(3, Pressed)),
+                (10, key(12, Pressed)),
+                (20, key(3, Released)),
+                (30, key(13, Pressed)),
+            ]
+        );
+    }
+
+    #[test]
+    fn other_keys_break_the_combo() {
+        let combos = [combo(&[12, 13], MUTE)];
+
+        assert_eq!(
+            play(
+                &combos,
+                &[(0, 12, Pressed), (10, 3, Pressed), (20, 13, Pressed)]
+            ),
+            [
+                (0, key(12, Pressed)),
+                (10, key(3, Pressed)),
+                (20, key(13, Pressed))
+            ]
+        );
+    }
+
+    #[test]
+    fn overlapping_combos() {
+        let combos = [
+            combo(&[12, 13], MUTE),
+            combo(&[11, 12, 13], PLAY),
+            combo(&[10, 11], NEXT),
+        ];
+
+        // A bigger combo could still be completed, so the smaller one waits for it.
+        assert_eq!(
+            play(
+                &combos,
+                &[(0, 12, Pressed), (10, 13, Pressed), (20, 11, Pressed)]
+            ),
+            [(
+                20,
+                Event::ComboPressed {
+                    key: 11,
+                    action: PLAY
+                }
+            )]
+        );
+
+        // And fires once the combo term is up.
+        assert_eq!(
+            play(&combos, &[(0, 12, Pressed), (10, 13, Pressed)]),
+            [(
+                10,
+                Event::ComboPressed {
+                    key: 12,
+                    action: MUTE
+                }
+            )]
+        );
+
+        // A key from another combo starts that one instead.
+        assert_eq!(
+            play(
+                &combos,
+                &[(0, 12, Pressed), (10, 10, Pressed), (20, 11, Pressed)]
+            ),
+            [
+                (0, key(12, Pressed)),
+                (
+                    20,
+                    Event::ComboPressed {
+                        key: 10,
+                        action: NEXT
+                    }
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn partially_released_combos() {
+        let combos = [combo(&[12, 13], MUTE), combo(&[11, 12, 13], PLAY)];
+
+        // Releasing a key before the combo is complete lets the presses through.
+        assert_eq!(
+            play(
+                &combos,
+                &[(0, 11, Pressed), (10, 11, Released), (20, 12, Pressed)]
+            ),
+            [
+                (0, key(11, Pressed)),
+                (10, key(11, Released)),
+                (20, key(12, Pressed))
+            ]
+        );
+
+        // Unless what's down already makes a combo.
+        assert_eq!(
+            play(
+                &combos,
+                &[(0, 12, Pressed), (10, 13, Pressed), (20, 13, Released)]
+            ),
+            [
+                (
+                    10,
+                    Event::ComboPressed {
+                        key: 12,
+                        action: MUTE
+                    }
+                ),
+                (20, Event::ComboReleased { key: 12 }),
+            ]
+        );
+
+        // Pressing a released key again doesn't fire the combo again, since the
+        // other key has been down for too long.
+        assert_eq!(
+            play(
+                &combos,
+                &[
+                    (0, 11, Pressed),
+                    (10, 12, Pressed),
+                    (20, 13, Pressed),
+                    (100, 12, Released),
+                    (200, 12, Pressed),
+                    (300, 12, Released),
+                    (310, 11, Released),
+                    (320, 13, Released),
+                ]
+            ),
+            [
+                (
+                    20,
+                    Event::ComboPressed {
+                        key: 11,
+                        action: PLAY
+                    }
+                ),
+                (100, Event::ComboReleased { key: 11 }),
+                (200, key(12, Pressed)),
+                (300, key(12, Released)),
+            ]
+        );
+    }
+
+    #[test]
+    fn unusable_combos_are_ignored() {
+        let combos = [
+            combo(&[12], MUTE),
+            combo(&[13, 14], MUTE),
+            combo(&[0, 1, 2, 3, 4], MUTE),
+        ];
+
+        assert_eq!(
+            play(
+                &combos,
+                &[
+                    (0, 12, Pressed),
+                    (10, 13, Pressed),
+                    (20, 14, Pressed),
+                    (30, 0, Pressed)
+                ]
+            ),
+            [
+                (0, key(12, Pressed)),
+                (10, key(13, Pressed)),
+                (20, key(14, Pressed)),
+                (30, key(0, Pressed)),
+            ]
+        );
+    }
+}
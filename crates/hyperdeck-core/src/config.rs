@@ -0,0 +1,629 @@
+//! Device configuration and its on-flash encoding.
+//!
+//! A stored configuration is a fixed 12-byte header followed by the
+//! [postcard](https://docs.rs/postcard)-encoded [`Config`]:
+//!
+//! | Offset | Size | Field                                   |
+//! |--------|------|-----------------------------------------|
+//! | 0      | 4    | Magic (`HDCK`)                          |
+//! | 4      | 2    | Schema version (little endian)          |
+//! | 6      | 2    | Payload length in bytes (little endian) |
+//! | 8      | 4    | CRC-32 of the payload (little endian)   |
+//! | 12     | ..   | Payload                                 |
+
+use embedded_storage::nor_flash::{NorFlash, NorFlashError, NorFlashErrorKind};
+use heapless::{String, Vec};
+use serde::{Deserialize, Serialize};
+
+use crate::crc::crc32;
+use crate::debounce::Debounce;
+use crate::layout::Layout;
+use crate::storage;
+use crate::time::Duration;
+use crate::unicode::UnicodeMode;
+
+/// Maximum number of layers a [`Config`] can hold.
+pub const MAX_LAYERS: usize = 6;
+/// Number of configurable keys per layer (the remaining two are reserved).
+pub const LAYER_KEYS: usize = 14;
+/// Maximum length of a layer name, in bytes.
+pub const NAME_LEN: usize = 16;
+/// Maximum number of macros a [`Config`] can hold.
+pub const MAX_MACROS: usize = 16;
+/// Maximum number of steps in a single macro.
+pub const MACRO_STEPS: usize = 24;
+/// Maximum number of text snippets a [`Config`] can hold.
+pub const MAX_TEXTS: usize = 8;
+/// Maximum length of a text snippet, in bytes.
+pub const TEXT_LEN: usize = 64;
+/// Number of host keyboard LEDs that can have an [`Indicator`].
+pub const NUM_LEDS: usize = 5;
+/// Most taps in a row a key can tell apart (see [`KeyConfig::on_taps`]).
+pub const MAX_TAPS: usize = 3;
+/// Maximum number of combos per layer.
+pub const MAX_COMBOS: usize = 8;
+/// Most keys a single [`Combo`] can take.
+pub const MAX_COMBO_KEYS: usize = 4;
+
+#[derive(Clone, Default, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Config {
+    pub layers: [Option<LayerConfig>; MAX_LAYERS],
+    /// Macros, referred to by index from [`KeyAction::Macro`]. Empty ones are unused.
+    pub macros: [Macro; MAX_MACROS],
+    /// Text snippets, referred to by index from [`KeyAction::Text`].
+    pub texts: [String<TEXT_LEN>; MAX_TEXTS],
+    /// The host's keyboard layout, used to type [`Config::texts`].
+    pub layout: Layout,
+    /// How to enter characters that [`Config::layout`] doesn't have, if at all.
+    pub unicode: Option<UnicodeMode>,
+    /// How each of the host's keyboard LEDs is shown, indexed by
+    /// [`Led`](crate::leds::Led).
+    pub indicators: [Option<Indicator>; NUM_LEDS],
+    pub brightness: Brightness,
+    pub tapping: Tapping,
+    pub debounce: Debounce,
+    pub repeat: Repeat,
+}
+
+/// Backlight levels, as percentages.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Brightness {
+    pub keypad: u8,
+    pub display: u8,
+}
+
+impl Default for Brightness {
+    fn default() -> Self {
+        Self {
+            keypad: 10,
+            display: 100,
+        }
+    }
+}
+
+impl Brightness {
+    /// Keypad brightness as a fraction between 0.0 and 1.0.
+    pub fn keypad_f32(&self) -> f32 {
+        self.keypad.min(100) as f32 / 100.0
+    }
+
+    /// Display brightness as a fraction between 0.0 and 1.0.
+    pub fn display_f32(&self) -> f32 {
+        self.display.min(100) as f32 / 100.0
+    }
+}
+
+/// How keys with both an `on_press` and an `on_hold` action decide which one
+/// was meant, and how quickly a [`Combo`]'s keys have to be pressed. See
+/// [`engine`](crate::engine).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Tapping {
+    /// How long such a key has to be held before it counts as held, in
+    /// milliseconds, unless it sets its own [`KeyConfig::tapping_term`].
+    pub term: u16,
+    /// Count the key as held if another key is pressed and released while it's
+    /// down, even before the tapping term is up (QMK's `PERMISSIVE_HOLD`).
+    pub permissive_hold: bool,
+    /// Count the key as held as soon as another key is pressed while it's down
+    /// (QMK's `HOLD_ON_OTHER_KEY_PRESS`).
+    pub hold_on_other_key_press: bool,
+    /// How long after the first of a combo's keys the rest can be pressed, in
+    /// milliseconds (QMK's `COMBO_TERM`).
+    pub combo_term: u16,
+}
+
+impl Default for Tapping {
+    fn default() -> Self {
+        Self {
+            term: 200,
+            permissive_hold: false,
+            hold_on_other_key_press: false,
+            combo_term: 50,
+        }
+    }
+}
+
+/// Auto-repeat for keys with [`KeyConfig::repeat`] set, like a keyboard's
+/// typematic delay and rate.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Repeat {
+    /// How long a key has to be down before it starts repeating, in milliseconds.
+    pub delay: u16,
+    /// Repeats per second after that, or 0 to never repeat.
+    pub rate: u8,
+}
+
+impl Default for Repeat {
+    fn default() -> Self {
+        Self {
+            delay: 500,
+            rate: 20,
+        }
+    }
+}
+
+impl Repeat {
+    pub fn delay(&self) -> Duration {
+        Duration::millis(self.delay as u64)
+    }
+
+    /// The time between repeats, if there are any.
+    pub fn period(&self) -> Option<Duration> {
+        (self.rate > 0).then(|| Duration::micros(1_000_000 / self.rate as u64))
+    }
+}
+
+#[derive(Clone, Default, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LayerConfig {
+    pub name: String<NAME_LEN>,
+    pub keys: [KeyConfig; LAYER_KEYS],
+    pub combos: Vec<Combo, MAX_COMBOS>,
+}
+
+/// Keys that perform their own action when pressed together, instead of their
+/// individual ones. See [`combo`](crate::combo).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Combo {
+    /// The keys, as a bitmask of key indices.
+    pub keys: u16,
+    pub action: KeyAction,
+}
+
+impl Combo {
+    /// The key the combo's action is performed as: the lowest of its keys.
+    pub fn key(&self) -> u8 {
+        self.keys.trailing_zeros() as u8
+    }
+}
+
+/// A key binding. Keys with no actions are transparent, and fall through to the
+/// next active layer down (see [`engine`](crate::engine)).
+#[derive(Clone, Default, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyConfig {
+    pub on_press: Option<KeyAction>,
+    pub on_hold: Option<KeyAction>,
+    pub colors: [u8; 6],
+    /// Overrides [`Tapping::term`] for this key, in milliseconds.
+    pub tapping_term: Option<u16>,
+    /// Performed instead of `on_press` when the key is tapped twice, then three
+    /// times, in quick succession (QMK's tap dance).
+    pub on_taps: [Option<KeyAction>; MAX_TAPS - 1],
+    /// Performed instead of `on_hold` when the key is tapped, then pressed again
+    /// and held.
+    pub on_tap_hold: Option<KeyAction>,
+    /// Perform the action again and again while the key is held down, at the
+    /// [`Repeat`] rate.
+    pub repeat: bool,
+}
+
+impl KeyConfig {
+    /// Whether the key does anything when pressed or held.
+    pub fn is_bound(&self) -> bool {
+        self.on_press.is_some() || self.on_hold.is_some() || self.is_tap_dance()
+    }
+
+    /// Every action the key has.
+    pub fn actions(&self) -> impl Iterator<Item = &KeyAction> {
+        self.on_press
+            .iter()
+            .chain(&self.on_hold)
+            .chain(self.on_taps.iter().flatten())
+            .chain(&self.on_tap_hold)
+    }
+
+    /// Whether the key does something different when tapped more than once.
+    pub fn is_tap_dance(&self) -> bool {
+        self.on_taps.iter().any(Option::is_some) || self.on_tap_hold.is_some()
+    }
+
+    /// The action for `taps` taps in a row, counting from 1.
+    pub fn tap_action(&self, taps: u8) -> Option<KeyAction> {
+        match taps {
+            0 => None,
+            1 => self.on_press,
+            n => *self.on_taps.get(n as usize - 2)?,
+        }
+    }
+
+    /// The action for holding the key down on the `taps`th press in a row. Keys
+    /// without one perform the tap action for as long as they're held.
+    pub fn hold_action(&self, taps: u8) -> Option<KeyAction> {
+        let hold = match taps {
+            1 => self.on_hold,
+            2 => self.on_tap_hold,
+            _ => None,
+        };
+
+        hold.or(self.tap_action(taps))
+    }
+
+    /// Whether tapping again after `taps` taps would do anything different.
+    pub fn has_more_taps(&self, taps: u8) -> bool {
+        let taps = taps + 1;
+        let hold = taps == 2 && self.on_tap_hold.is_some();
+
+        hold || self.tap_action(taps).is_some()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum KeyAction {
+    /// Send a boot keyboard report (modifiers, reserved byte, six keycodes).
+    Keyboard([u8; 8]),
+    /// Change which layers are active.
+    Layer(LayerAction),
+    /// Play the macro at this index in [`Config::macros`].
+    Macro(u8),
+    /// Type the text snippet at this index in [`Config::texts`].
+    Text(u8),
+    /// Type a single character, falling back to [`Config::unicode`] if it isn't on
+    /// [`Config::layout`].
+    Unicode(char),
+    /// Press a consumer control usage (media keys, browser keys, application
+    /// launchers) for as long as the key is down.
+    Consumer(u16),
+    /// Tap a consumer control usage, then keep tapping it while the key is down,
+    /// e.g. to keep stepping the volume.
+    ConsumerRepeat(u16),
+    /// Act as a mouse button, or move the pointer or scroll while the key is down.
+    Mouse(MouseAction),
+}
+
+/// Layer switching, modelled on QMK's layer keys.
+///
+/// Layers are numbered from 0 to [`MAX_LAYERS`] - 1; actions naming a layer that
+/// isn't configured are ignored.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LayerAction {
+    /// Activates the layer while the key is down (QMK's `MO`).
+    Momentary(u8),
+    /// Flips the layer on or off (QMK's `TG`).
+    Toggle(u8),
+    /// Activates the layer for the next key press only (QMK's `OSL`).
+    /// Behaves like [`LayerAction::Momentary`] if another key is pressed while it's held.
+    OneShot(u8),
+    /// Deactivates every layer except the default one, then activates this one (QMK's `TO`).
+    To(u8),
+    /// Makes this the default (bottom) layer (QMK's `DF`).
+    Default(u8),
+}
+
+impl LayerAction {
+    /// The layer this action refers to.
+    pub fn layer(&self) -> u8 {
+        match *self {
+            Self::Momentary(layer)
+            | Self::Toggle(layer)
+            | Self::OneShot(layer)
+            | Self::To(layer)
+            | Self::Default(layer) => layer,
+        }
+    }
+}
+
+/// Shows one of the host's keyboard LEDs, such as caps lock, while it's lit.
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Indicator {
+    /// A key (0 to 15) to give [`Indicator::color`] instead of its usual color.
+    pub key: Option<u8>,
+    pub color: [u8; 3],
+    /// Whether to show the LED on the display's home screen.
+    pub display: bool,
+}
+
+/// Mouse emulation, carried out by a [`Mouse`](crate::mouse::Mouse).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MouseAction {
+    /// Holds down a button, numbered from 1 (left, right, middle, back, forward).
+    Button(u8),
+    /// Moves the pointer, speeding up the longer the key is held.
+    Move(Direction),
+    /// Scrolls the wheel (up and down) or pans (left and right).
+    Scroll(Direction),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Direction {
+    Up,
+    Down,
+    Left,
+    Right,
+}
+
+/// A sequence of keyboard steps, played back by a [`MacroPlayer`](crate::macros::MacroPlayer).
+pub type Macro = Vec<MacroStep, MACRO_STEPS>;
+
+/// A single macro step.
+///
+/// Keys are HID keyboard usages, with the modifiers at `0xE0` (left control)
+/// to `0xE7` (right GUI) as in the HID usage tables.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MacroStep {
+    /// Presses a key and leaves it down.
+    Press(u8),
+    /// Releases a key pressed by an earlier step.
+    Release(u8),
+    /// Presses a key, then releases it.
+    Tap(u8),
+    /// Waits for this many milliseconds.
+    Delay(u16),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The record doesn't start with [`Config::MAGIC`] (usually blank flash).
+    BadMagic,
+    /// The record was written with a schema version we don't understand.
+    UnsupportedVersion(u16),
+    /// The length field is larger than [`Config::MAX_ENCODED_LEN`] allows,
+    /// or larger than the buffer it was read from.
+    BadLength,
+    /// The payload doesn't match the stored checksum.
+    BadChecksum,
+    /// The payload passed its checksum but couldn't be deserialized.
+    Malformed,
+    /// The encoded configuration doesn't fit in the destination.
+    TooLarge,
+    /// Every storage slot is blank or corrupt.
+    NoValidRecord,
+    /// The underlying flash reported an error.
+    Flash(NorFlashErrorKind),
+}
+
+impl<E: NorFlashError> From<E> for Error {
+    fn from(err: E) -> Self {
+        Self::Flash(err.kind())
+    }
+}
+
+impl Config {
+    pub const MAGIC: [u8; 4] = *b"HDCK";
+    pub const VERSION: u16 = 1;
+    pub const HEADER_LEN: usize = 12;
+    /// Upper bound on the size of an encoded record, header included.
+    ///
+    /// Chosen so that a record and its [`storage`] slot header fill 8K exactly.
+    pub const MAX_ENCODED_LEN: usize = 8192 - 8;
+}
+
+impl Config {
+    /// Encodes the configuration (header included) into `buf`,
+    /// returning the number of bytes written.
+    pub fn encode(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        if buf.len() < Self::HEADER_LEN {
+            return Err(Error::TooLarge);
+        }
+
+        let end = buf.len().min(Self::MAX_ENCODED_LEN);
+        let (header, payload) = buf[..end].split_at_mut(Self::HEADER_LEN);
+
+        let payload = postcard::to_slice(self, payload).map_err(|_| Error::TooLarge)?;
+        let length = payload.len();
+
+        header[0..4].copy_from_slice(&Self::MAGIC);
+        header[4..6].copy_from_slice(&Self::VERSION.to_le_bytes());
+        header[6..8].copy_from_slice(&(length as u16).to_le_bytes());
+        header[8..12].copy_from_slice(&crc32(payload).to_le_bytes());
+
+        Ok(Self::HEADER_LEN + length)
+    }
+
+    /// Decodes a configuration previously written by [`Config::encode`].
+    ///
+    /// Trailing bytes after the payload (e.g. erased flash) are ignored.
+    pub fn decode(buf: &[u8]) -> Result<Self, Error> {
+        let length = Self::check_header(buf)?;
+        let payload = buf
+            .get(Self::HEADER_LEN..Self::HEADER_LEN + length)
+            .ok_or(Error::BadLength)?;
+
+        if crc32(payload).to_le_bytes() != buf[8..12] {
+            return Err(Error::BadChecksum);
+        }
+
+        postcard::from_bytes(payload).map_err(|_| Error::Malformed)
+    }
+
+    /// Validates the magic and version of a record header,
+    /// returning the length of the payload that follows it.
+    pub(crate) fn check_header(buf: &[u8]) -> Result<usize, Error> {
+        let header = buf.get(..Self::HEADER_LEN).ok_or(Error::BadLength)?;
+
+        if header[0..4] != Self::MAGIC {
+            return Err(Error::BadMagic);
+        }
+
+        let version = u16::from_le_bytes([header[4], header[5]]);
+
+        if version != Self::VERSION {
+            return Err(Error::UnsupportedVersion(version));
+        }
+
+        let length = u16::from_le_bytes([header[6], header[7]]) as usize;
+
+        match Self::HEADER_LEN + length <= Self::MAX_ENCODED_LEN {
+            true => Ok(length),
+            false => Err(Error::BadLength),
+        }
+    }
+
+    /// Loads the newest valid configuration from `flash`.
+    ///
+    /// See [`storage`] for how records are laid out across the region.
+    pub fn load<F: NorFlash>(flash: &mut F) -> Result<Self, Error> {
+        storage::load(flash)
+    }
+
+    /// Writes the configuration to `flash` without disturbing the previous copy.
+    pub fn store<F: NorFlash>(&self, flash: &mut F) -> Result<(), Error> {
+        storage::store(flash, self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::debounce::Algorithm;
+    use crate::mock::MemFlash;
+
+    fn sample() -> Config {
+        let mut keys: [KeyConfig; LAYER_KEYS] = Default::default();
+
+        keys[0].on_press = Some(KeyAction::Keyboard([0b101, 0, 0x17, 0, 0, 0, 0, 0]));
+        keys[0].colors = [16, 16, 16, 0, 255, 0];
+        keys[13].on_hold = Some(KeyAction::Layer(LayerAction::OneShot(2)));
+        keys[13].tapping_term = Some(150);
+        keys[12].on_taps[1] = Some(KeyAction::Consumer(0xE2));
+        keys[12].on_tap_hold = Some(KeyAction::Keyboard([0, 0, 0x68, 0, 0, 0, 0, 0]));
+        keys[12].on_press = Some(KeyAction::Macro(1));
+        keys[11].on_press = Some(KeyAction::Unicode('😀'));
+        keys[11].on_hold = Some(KeyAction::ConsumerRepeat(0xE9));
+        keys[10].on_press = Some(KeyAction::Mouse(MouseAction::Move(Direction::Left)));
+
+        let mut config = Config::default();
+
+        config.macros[1] = Vec::from_slice(&[
+            MacroStep::Press(0xE0),
+            MacroStep::Tap(0x0E),
+            MacroStep::Delay(50),
+            MacroStep::Release(0xE0),
+        ])
+        .unwrap();
+
+        config.texts[7] = String::from("Grüße");
+        config.layout = Layout::De;
+        config.unicode = Some(UnicodeMode::MacOs);
+        config.tapping.permissive_hold = true;
+        config.debounce.algorithm = Algorithm::EagerPerKey;
+        config.indicators[1] = Some(Indicator {
+            key: Some(5),
+            color: [255, 0, 0],
+            display: true,
+        });
+
+        config.layers[0] = Some(LayerConfig {
+            name: String::from("Default"),
+            keys,
+            combos: Vec::from_slice(&[Combo {
+                keys: 0b11 << 12,
+                action: KeyAction::Consumer(0xE2),
+            }])
+            .unwrap(),
+        });
+
+        config
+    }
+
+    #[test]
+    fn round_trip() {
+        let config = sample();
+        let mut buf = [0_u8; Config::MAX_ENCODED_LEN];
+
+        let length = config.encode(&mut buf).unwrap();
+
+        assert_eq!(&buf[..4], b"HDCK");
+        assert_eq!(Config::decode(&buf[..length]), Ok(config));
+    }
+
+    #[test]
+    fn rejects_corruption() {
+        let mut buf = [0_u8; Config::MAX_ENCODED_LEN];
+        let length = sample().encode(&mut buf).unwrap();
+
+        let mut corrupt = buf;
+        corrupt[length - 1] ^= 0x01;
+        assert_eq!(Config::decode(&corrupt), Err(Error::BadChecksum));
+
+        let mut corrupt = buf;
+        corrupt[0] = 0xFF;
+        assert_eq!(Config::decode(&corrupt), Err(Error::BadMagic));
+
+        let mut corrupt = buf;
+        corrupt[4] = 99;
+        assert_eq!(Config::decode(&corrupt), Err(Error::UnsupportedVersion(99)));
+
+        assert_eq!(Config::decode(&buf[..length - 1]), Err(Error::BadLength));
+    }
+
+    #[test]
+    fn largest_config_fits() {
+        let action = Some(KeyAction::Keyboard([0xFF; 8]));
+
+        let key = KeyConfig {
+            on_press: action,
+            on_hold: action,
+            colors: [0xFF; 6],
+            tapping_term: Some(u16::MAX),
+            on_taps: [action; MAX_TAPS - 1],
+            on_tap_hold: action,
+            repeat: true,
+        };
+
+        let layer = LayerConfig {
+            name: String::from("0123456789abcdef"),
+            keys: core::array::from_fn(|_| key.clone()),
+            combos: Vec::from_slice(
+                &[Combo {
+                    keys: u16::MAX,
+                    action: KeyAction::Keyboard([0xFF; 8]),
+                }; MAX_COMBOS],
+            )
+            .unwrap(),
+        };
+
+        let steps = Vec::from_slice(&[MacroStep::Delay(u16::MAX); MACRO_STEPS]).unwrap();
+
+        let text: String<TEXT_LEN> = core::iter::repeat_n('~', TEXT_LEN).collect();
+
+        let config = Config {
+            layers: core::array::from_fn(|_| Some(layer.clone())),
+            macros: core::array::from_fn(|_| steps.clone()),
+            texts: core::array::from_fn(|_| text.clone()),
+            layout: Layout::De,
+            unicode: Some(UnicodeMode::WinCompose),
+            indicators: [Some(Indicator {
+                key: Some(0xFF),
+                color: [0xFF; 3],
+                display: true,
+            }); NUM_LEDS],
+            brightness: Brightness::default(),
+            tapping: Tapping {
+                term: u16::MAX,
+                permissive_hold: true,
+                hold_on_other_key_press: true,
+                combo_term: u16::MAX,
+            },
+            debounce: Debounce {
+                algorithm: Algorithm::DeferredPerKey,
+                time: u8::MAX,
+            },
+            repeat: Repeat {
+                delay: u16::MAX,
+                rate: u8::MAX,
+            },
+        };
+
+        let mut buf = [0_u8; Config::MAX_ENCODED_LEN];
+        assert!(config.encode(&mut buf).is_ok());
+    }
+
+    #[test]
+    fn rejects_small_buffer() {
+        let mut buf = [0_u8; 32];
+        assert_eq!(sample().encode(&mut buf), Err(Error::TooLarge));
+    }
+
+    #[test]
+    fn store_and_load() {
+        let mut flash = MemFlash::<{ 4 * 8192 }>::new();
+
+        assert_eq!(Config::load(&mut flash), Err(Error::NoValidRecord));
+
+        let config = sample();
+        config.store(&mut flash).unwrap();
+
+        assert_eq!(Config::load(&mut flash), Ok(config));
+    }
+}
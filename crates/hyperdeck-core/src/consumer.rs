@@ -0,0 +1,202 @@
+//! Auto-repeat for consumer control keys.
+//!
+//! Hosts act on a consumer usage when it's pressed and mostly ignore it being held,
+//! so holding volume up would only step the volume once. [`Repeater`] taps the
+//! usage over and over instead, at the same [`Repeat`] delay and rate as keys with
+//! [`KeyConfig::repeat`](crate::config::KeyConfig::repeat) set: once straight away,
+//! again after the delay, then at the rate.
+
+use crate::config::Repeat;
+use crate::time::{Duration, Instant};
+
+pub struct Repeater {
+    /// The usage being repeated, or 0 for none.
+    usage: u16,
+    /// Whether the host currently sees a usage as pressed.
+    pressed: bool,
+    /// Whether the next press is the first repeat, which waits longer.
+    first: bool,
+    /// When the next report is due.
+    next: Instant,
+    /// When the next press is due, once the current one has been released.
+    press_at: Instant,
+}
+
+impl Repeater {
+    /// How long each tap holds the usage down.
+    pub const TAP: Duration = Duration::millis(10);
+}
+
+impl Repeater {
+    pub const fn new() -> Self {
+        Self {
+            usage: 0,
+            pressed: false,
+            first: true,
+            next: Instant::from_ticks(0),
+            press_at: Instant::from_ticks(0),
+        }
+    }
+
+    /// Starts repeating `usage`, or stops if it's 0.
+    pub fn set(&mut self, usage: u16, now: Instant) {
+        self.usage = usage;
+
+        if usage != 0 {
+            self.first = true;
+            self.press_at = now;
+
+            if !self.pressed {
+                self.next = now;
+            }
+        }
+    }
+
+    /// Sends the next press or release if it's due, handing the usage (0 for a
+    /// release) to `send`. Rejected reports are retried on the next call.
+    pub fn poll(&mut self, now: Instant, repeat: Repeat, mut send: impl FnMut(u16) -> bool) {
+        if now < self.next {
+            return;
+        }
+
+        if self.pressed {
+            if send(0) {
+                self.pressed = false;
+                self.next = self.press_at;
+            }
+
+            return;
+        }
+
+        if self.usage == 0 || now < self.press_at || !send(self.usage) {
+            return;
+        }
+
+        let first = core::mem::replace(&mut self.first, false);
+
+        self.pressed = true;
+        self.next = now + Self::TAP;
+
+        match repeat.period() {
+            Some(_) if first => self.press_at = now + repeat.delay(),
+            Some(period) => self.press_at = now + period,
+            // Just the one tap, then.
+            None => self.usage = 0,
+        }
+    }
+}
+
+impl Default for Repeater {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VOLUME_UP: u16 = 0xE9;
+
+    const REPEAT: Repeat = Repeat {
+        delay: 500,
+        rate: 10,
+    };
+
+    /// Polls every millisecond from 0 until `until`, logging each report with the
+    /// time it was sent. `stop_at` releases the key.
+    fn run(
+        until: u64,
+        stop_at: u64,
+        repeat: Repeat,
+        mut accept: impl FnMut(u64) -> bool,
+    ) -> Vec<(u64, u16)> {
+        let mut repeater = Repeater::new();
+        let mut log = Vec::new();
+
+        repeater.set(VOLUME_UP, Instant::from_ticks(0));
+
+        for ms in 0..until {
+            let now = Instant::from_ticks(ms * 1000);
+
+            if ms == stop_at {
+                repeater.set(0, now);
+            }
+
+            repeater.poll(now, repeat, |usage| {
+                let accepted = accept(ms);
+
+                if accepted {
+                    log.push((ms, usage));
+                }
+
+                accepted
+            });
+        }
+
+        log
+    }
+
+    #[test]
+    fn repeats_while_held() {
+        let log = run(750, 750, REPEAT, |_| true);
+
+        assert_eq!(
+            log,
+            [
+                (0, VOLUME_UP),
+                (10, 0),
+                (500, VOLUME_UP),
+                (510, 0),
+                (600, VOLUME_UP),
+                (610, 0),
+                (700, VOLUME_UP),
+                (710, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn stopping_releases() {
+        // Let go mid-tap: the release still goes out, and nothing after it.
+        let log = run(1000, 505, REPEAT, |_| true);
+
+        assert_eq!(log, [(0, VOLUME_UP), (10, 0), (500, VOLUME_UP), (510, 0)]);
+    }
+
+    #[test]
+    fn retries_rejected_reports() {
+        let log = run(100, 100, REPEAT, |ms| ms >= 3);
+
+        assert_eq!(log, [(3, VOLUME_UP), (13, 0)]);
+    }
+
+    #[test]
+    fn follows_the_repeat_rate() {
+        let fast = Repeat {
+            delay: 200,
+            rate: 50,
+        };
+        let log = run(250, 250, fast, |_| true);
+
+        assert_eq!(
+            log,
+            [
+                (0, VOLUME_UP),
+                (10, 0),
+                (200, VOLUME_UP),
+                (210, 0),
+                (220, VOLUME_UP),
+                (230, 0),
+                (240, VOLUME_UP),
+            ]
+        );
+
+        // A rate of 0 never repeats.
+        let never = Repeat {
+            delay: 200,
+            rate: 0,
+        };
+        assert_eq!(run(1000, 1000, never, |_| true), [(0, VOLUME_UP), (10, 0)]);
+    }
+}
@@ -0,0 +1,215 @@
+//! Debouncing for the raw key states read from the keypad.
+//!
+//! Switch contacts bounce for a few milliseconds when they close or open, and
+//! worn ones can chatter for longer, so a single raw sample can't be trusted to
+//! be a real press or release. [`Debouncer`] turns a stream of raw samples, one
+//! bit per key, into a stream of stable states, using one of QMK's algorithms:
+//!
+//! - [`Algorithm::EagerPerKey`] reports a key's change straight away, then
+//!   ignores that key for the debounce time. Fast, but a glitch on an idle key
+//!   gets through as a short tap.
+//! - [`Algorithm::DeferredPerKey`] reports a key's change once the key has held
+//!   still for the debounce time. Glitches never get through, at the cost of
+//!   that much latency.
+//! - [`Algorithm::Symmetric`] waits for every key to hold still for the debounce
+//!   time, then reports all of them at once. The cheapest, but a key that keeps
+//!   chattering holds up the others.
+//!
+//! A debounce time of zero passes the raw samples straight through.
+
+use serde::{Deserialize, Serialize};
+
+use crate::keypad::NUM_KEYS;
+use crate::time::{Duration, Instant};
+
+/// A debouncing algorithm; see the [module docs](self) for how they compare.
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Algorithm {
+    EagerPerKey,
+    DeferredPerKey,
+    #[default]
+    Symmetric,
+}
+
+/// How the keypad's raw key states are debounced.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Debounce {
+    pub algorithm: Algorithm,
+    /// How long a key has to settle, in milliseconds.
+    pub time: u8,
+}
+
+impl Default for Debounce {
+    fn default() -> Self {
+        Self {
+            algorithm: Algorithm::default(),
+            time: 5,
+        }
+    }
+}
+
+pub struct Debouncer {
+    /// The debounced key states, one bit per key.
+    state: u16,
+    /// The previous raw sample.
+    raw: u16,
+    /// Per key: when its raw state last changed, or for [`Algorithm::EagerPerKey`],
+    /// when it last changed state.
+    changed: [Instant; NUM_KEYS],
+    /// When any key's raw state last changed.
+    any_changed: Instant,
+}
+
+impl Debouncer {
+    pub const fn new() -> Self {
+        Self {
+            state: 0,
+            raw: 0,
+            changed: [Instant::from_ticks(0); NUM_KEYS],
+            any_changed: Instant::from_ticks(0),
+        }
+    }
+
+    /// The debounced key states, one bit per key.
+    pub fn state(&self) -> u16 {
+        self.state
+    }
+
+    /// Takes the raw key states sampled at `now`, one bit per key, and returns
+    /// the debounced ones.
+    pub fn update(&mut self, debounce: Debounce, raw: u16, now: Instant) -> u16 {
+        let time = Duration::millis(debounce.time as u64);
+        let moved = raw ^ self.raw;
+
+        self.raw = raw;
+
+        if debounce.time == 0 {
+            self.state = raw;
+            return raw;
+        }
+
+        if moved != 0 {
+            self.any_changed = now;
+        }
+
+        match debounce.algorithm {
+            Algorithm::EagerPerKey => {
+                for i in bits(raw ^ self.state) {
+                    if now - self.changed[i] >= time {
+                        self.changed[i] = now;
+                        self.state ^= 1 << i;
+                    }
+                }
+            }
+            Algorithm::DeferredPerKey => {
+                for i in bits(moved) {
+                    self.changed[i] = now;
+                }
+
+                for i in bits(raw ^ self.state) {
+                    if now - self.changed[i] >= time {
+                        self.state ^= 1 << i;
+                    }
+                }
+            }
+            Algorithm::Symmetric => {
+                if now - self.any_changed >= time {
+                    self.state = raw;
+                }
+            }
+        }
+
+        self.state
+    }
+}
+
+impl Default for Debouncer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The indices of the set bits in `keys`.
+fn bits(keys: u16) -> impl Iterator<Item = usize> {
+    (0..NUM_KEYS).filter(move |i| keys & 1 << i != 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use super::*;
+
+    /// Key 0 bouncing as it's pressed, then again as it's released, sampled every
+    /// millisecond. Key 1 has a one-sample glitch on its own.
+    const RECORDING: [u16; 30] = [
+        0b00, 0b01, 0b00, 0b01, 0b01, 0b01, 0b01, 0b01, 0b01, 0b01, 0b01, 0b01, 0b00, 0b01, 0b00,
+        0b00, 0b00, 0b00, 0b00, 0b00, 0b10, 0b00, 0b00, 0b00, 0b00, 0b00, 0b00, 0b00, 0b00, 0b00,
+    ];
+
+    /// Feeds `samples` through a new debouncer, one per millisecond, returning
+    /// every millisecond at which the debounced state changed, and what to.
+    fn run(algorithm: Algorithm, time: u8, samples: &[u16]) -> Vec<(u64, u16)> {
+        let debounce = Debounce { algorithm, time };
+        let mut debouncer = Debouncer::new();
+        let mut state = 0;
+        let mut changes = Vec::new();
+
+        for (ms, &raw) in samples.iter().enumerate() {
+            let ms = ms as u64 + 100;
+            let new = debouncer.update(debounce, raw, Instant::from_ticks(ms * 1000));
+
+            if new != state {
+                changes.push((ms - 100, new));
+                state = new;
+            }
+        }
+
+        changes
+    }
+
+    #[test]
+    fn zero_time_passes_through() {
+        let raw = run(Algorithm::Symmetric, 0, &RECORDING);
+
+        assert_eq!(raw.len(), 8);
+        assert_eq!(raw[..3], [(1, 0b01), (2, 0b00), (3, 0b01)]);
+    }
+
+    #[test]
+    fn eager_per_key() {
+        assert_eq!(
+            run(Algorithm::EagerPerKey, 5, &RECORDING),
+            [(1, 0b01), (12, 0b00), (20, 0b10), (25, 0b00)]
+        );
+    }
+
+    #[test]
+    fn deferred_per_key() {
+        assert_eq!(
+            run(Algorithm::DeferredPerKey, 5, &RECORDING),
+            [(8, 0b01), (19, 0b00)]
+        );
+    }
+
+    #[test]
+    fn symmetric() {
+        assert_eq!(
+            run(Algorithm::Symmetric, 5, &RECORDING),
+            [(8, 0b01), (19, 0b00)]
+        );
+
+        // A key that keeps chattering holds up the others, but only under the
+        // symmetric algorithm.
+        let chatter = [
+            0b01, 0b11, 0b01, 0b11, 0b01, 0b11, 0b01, 0b11, 0b11, 0b11, 0b11, 0b11, 0b11,
+        ];
+        assert_eq!(run(Algorithm::Symmetric, 3, &chatter), [(10, 0b11)]);
+        assert_eq!(
+            run(Algorithm::DeferredPerKey, 3, &chatter),
+            [(3, 0b01), (10, 0b11)]
+        );
+    }
+}
@@ -0,0 +1,453 @@
+//! The device's main loop, with the USB stack and display behind a [`Host`].
+//!
+//! [`Device::update`] scans the [`Keypad`], runs what happened through the
+//! [`Engine`], and carries out the resulting [`Action`]s, sending reports and
+//! switching screens through the [`Host`]. The firmware calls it over and over
+//! with the real hardware; the simulator does the same with simulated hardware
+//! and a [`FakeClock`](crate::time::FakeClock).
+//!
+//! Configuration sessions aren't handled here, since they only touch the
+//! [`Config`], which the caller owns. After one changes it, the keypad colors and
+//! screen need redoing with [`Device::key_colors`] and [`Device::home`].
+
+use embedded_hal::blocking::{i2c, spi};
+use embedded_hal::digital::v2::OutputPin;
+use heapless::Deque;
+
+use crate::config::Config;
+use crate::consumer::Repeater;
+use crate::engine::{Action, Engine};
+use crate::hid::Report;
+use crate::keyboard::{Keyboard, Output, Protocol, Source};
+use crate::keypad::{self, Color, Keypad, NUM_KEYS};
+use crate::leds::{self, Leds};
+use crate::macros::MacroPlayer;
+use crate::mouse::Mouse;
+use crate::screen::Screen;
+use crate::time::Clock;
+
+/// Where reports go, and where the host's own state comes from.
+pub trait Host {
+    /// Sends a keyboard report in whichever format the host wants, returning
+    /// whether it could be queued.
+    fn send_keyboard(&mut self, output: Output) -> bool;
+
+    /// Sends any other report, returning whether it could be queued.
+    fn send(&mut self, report: Report) -> bool;
+
+    /// The keyboard LEDs the host last asked for.
+    fn leds(&self) -> Leds;
+
+    /// The keyboard report format the host last asked for.
+    fn protocol(&self) -> Protocol;
+
+    /// Switches the display to `screen`.
+    fn show(&mut self, screen: Screen);
+}
+
+pub struct Device {
+    engine: Engine,
+    player: MacroPlayer,
+    repeater: Repeater,
+    mouse: Mouse,
+    keyboard: Keyboard,
+    /// Consumer usages waiting to be sent, so a full report queue can't lose a
+    /// release.
+    consumer: Deque<u16, 8>,
+    /// Consumer usages folded away because `consumer` was full.
+    dropped: u32,
+    leds: Leds,
+}
+
+impl Device {
+    pub const fn new() -> Self {
+        Self {
+            engine: Engine::new(),
+            player: MacroPlayer::new(),
+            repeater: Repeater::new(),
+            mouse: Mouse::new(),
+            keyboard: Keyboard::new(),
+            consumer: Deque::new(),
+            dropped: 0,
+            leds: Leds(0),
+        }
+    }
+
+    /// The colors for every key on the current layer, with lit indicators on top.
+    pub fn key_colors(&self, config: &Config) -> [(Color, Color); NUM_KEYS] {
+        let mut colors = self.engine.colors(config);
+        leds::apply(config, self.leds, &mut colors);
+        colors.map(Color::pair)
+    }
+
+    /// The home screen for the current layer, if there is one.
+    pub fn home(&self, config: &Config) -> Option<Screen> {
+        Screen::home(config, self.engine.layer(config)?, self.leds)
+    }
+
+    /// How many key and media changes have been folded away since the device
+    /// started, for [`Stats::dropped`](crate::queue::Stats::dropped).
+    pub fn dropped(&self) -> u32 {
+        self.keyboard.dropped().wrapping_add(self.dropped)
+    }
+
+    /// Runs one pass of the main loop.
+    #[allow(clippy::type_complexity)]
+    pub fn update<I, S, P, E>(
+        &mut self,
+        config: &Config,
+        keypad: &mut Keypad<I, S, P>,
+        clock: &impl Clock,
+        host: &mut impl Host,
+    ) -> Result<(), keypad::Error<E, S::Error, P::Error>>
+    where
+        I: i2c::Write<Error = E> + i2c::Read<Error = E>,
+        S: spi::Write<u8>,
+        P: OutputPin,
+    {
+        // While the host is behind, the keypad isn't scanned at all, so nothing new
+        // gets queued that we'd have no room to hold on to. The extra poll decides
+        // tap-hold keys whose tapping term has run out, and lets through presses
+        // that were held back for a combo.
+        let events = match self.keyboard.is_full() || self.consumer.is_full() {
+            true => None,
+            false => Some(keypad.update(clock)?.map(Some).chain([None])),
+        };
+
+        for event in events.into_iter().flatten() {
+            let actions = match event {
+                Some((id, event)) => self.engine.handle(config, id, event, clock.now()),
+                None => self.engine.poll(config, clock.now()),
+            };
+
+            for action in actions {
+                self.perform(config, keypad, clock, host, action);
+            }
+        }
+
+        if self.leds != host.leds() {
+            self.leds = host.leds();
+            keypad.set_colors(self.key_colors(config));
+
+            if let Some(screen) = self.home(config) {
+                host.show(screen);
+            }
+        }
+
+        if self.keyboard.protocol() != host.protocol() {
+            self.keyboard.set_protocol(host.protocol());
+        }
+
+        let keyboard = &mut self.keyboard;
+
+        // The player waits on a backed up keyboard the same way the keypad does.
+        self.player.poll(clock.now(), |chord| {
+            if keyboard.is_full() {
+                return false;
+            }
+
+            keyboard.set(Source::Player, chord);
+            keyboard.poll(|output| host.send_keyboard(output))
+        });
+        keyboard.poll(|output| host.send_keyboard(output));
+
+        while self
+            .consumer
+            .front()
+            .is_some_and(|usage| host.send(Report::Consumer(*usage)))
+        {
+            self.consumer.pop_front();
+        }
+
+        self.repeater.poll(clock.now(), config.repeat, |usage| {
+            host.send(Report::Consumer(usage))
+        });
+        self.mouse
+            .poll(clock.now(), |report| host.send(Report::Mouse(report)));
+
+        Ok(())
+    }
+
+    fn perform<I, S, P>(
+        &mut self,
+        config: &Config,
+        keypad: &mut Keypad<I, S, P>,
+        clock: &impl Clock,
+        host: &mut impl Host,
+        action: Action,
+    ) {
+        match action {
+            Action::Keyboard { key, chord } => {
+                self.keyboard.set(Source::Key(key), chord);
+                self.keyboard.poll(|output| host.send_keyboard(output));
+            }
+            Action::Layer(layer) => {
+                keypad.set_colors(self.key_colors(config));
+
+                if let Some(screen) = Screen::home(config, layer, self.leds) {
+                    host.show(screen);
+                }
+            }
+            Action::Macro(index) => {
+                if let Some(steps) = config.macros.get(index as usize) {
+                    self.player.play(steps, clock.now());
+                }
+            }
+            Action::Text(index) => {
+                if let Some(text) = config.texts.get(index as usize) {
+                    self.player
+                        .type_text(text, config.layout, config.unicode, clock.now());
+                }
+            }
+            Action::Unicode(c) => {
+                let mut buf = [0; 4];
+                self.player.type_text(
+                    c.encode_utf8(&mut buf),
+                    config.layout,
+                    config.unicode,
+                    clock.now(),
+                );
+            }
+            // Retried by `update`, so a full report queue can't lose a release.
+            // One scan can still overfill this; the newest usage then replaces the
+            // last one waiting, so the host ends up right.
+            Action::Consumer(usage) => {
+                if let Err(usage) = self.consumer.push_back(usage) {
+                    if let Some(back) = self.consumer.back_mut() {
+                        *back = usage;
+                    }
+
+                    self.dropped = self.dropped.wrapping_add(1);
+                }
+            }
+            Action::ConsumerRepeat(usage) => self.repeater.set(usage, clock.now()),
+            Action::Mouse { action, pressed } => self.mouse.handle(action, pressed, clock.now()),
+        }
+    }
+}
+
+impl Default for Device {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use super::*;
+    use crate::config::{KeyAction, KeyConfig, LayerAction, LayerConfig};
+    use crate::debounce::{Algorithm, Debounce};
+    use crate::hid::KeyboardReport;
+    use crate::leds::Led;
+    use crate::mock::{Apa102, Tca9555};
+    use crate::time::FakeClock;
+
+    const F13: [u8; 8] = [0, 0, 0x68, 0, 0, 0, 0, 0];
+
+    #[derive(Default)]
+    struct Recorder {
+        reports: Vec<Report>,
+        screens: Vec<Screen>,
+        leds: Leds,
+        /// Refuses every report, as if the report queue were full.
+        full: bool,
+        /// Refuses every other report, as if the queue only just had room.
+        flaky: bool,
+        attempts: usize,
+    }
+
+    impl Host for Recorder {
+        fn send_keyboard(&mut self, output: Output) -> bool {
+            match output {
+                Output::Boot(_) => false,
+                Output::Report(report) => self.send(Report::Keyboard(report)),
+            }
+        }
+
+        fn send(&mut self, report: Report) -> bool {
+            self.attempts += 1;
+
+            if self.full || (self.flaky && self.attempts.is_multiple_of(2)) {
+                return false;
+            }
+
+            self.reports.push(report);
+            true
+        }
+
+        fn leds(&self) -> Leds {
+            self.leds
+        }
+
+        fn protocol(&self) -> Protocol {
+            Protocol::Report
+        }
+
+        fn show(&mut self, screen: Screen) {
+            self.screens.push(screen);
+        }
+    }
+
+    fn config() -> Config {
+        let mut base = LayerConfig {
+            name: "Base".into(),
+            ..Default::default()
+        };
+        base.keys[0] = KeyConfig {
+            on_press: Some(KeyAction::Keyboard(F13)),
+            colors: [1, 2, 3, 4, 5, 6],
+            ..Default::default()
+        };
+        base.keys[1].on_press = Some(KeyAction::Layer(LayerAction::Momentary(1)));
+        base.keys[2].on_press = Some(KeyAction::Consumer(0xE9));
+
+        let mut config = Config::default();
+        config.layers[0] = Some(base);
+        config.layers[1] = Some(LayerConfig {
+            name: "Fn".into(),
+            ..Default::default()
+        });
+        config.debounce = Debounce {
+            algorithm: Algorithm::Symmetric,
+            time: 0,
+        };
+
+        config
+    }
+
+    #[test]
+    fn runs_the_main_loop() {
+        let config = config();
+        let expander = Tca9555::new();
+        let (spi, cs) = Apa102::chain();
+        let mut keypad = Keypad::new(expander.clone(), spi, cs);
+        let mut device = Device::new();
+        let mut host = Recorder::default();
+        let clock = FakeClock::new();
+
+        keypad.set_debounce(config.debounce);
+
+        let mut step = |host: &mut Recorder, keypad: &mut Keypad<_, _, _>, pressed: u16| {
+            expander.set_pressed(pressed);
+            clock.advance_ms(1);
+            device.update(&config, keypad, &clock, host).unwrap();
+        };
+
+        step(&mut host, &mut keypad, 0b01);
+        step(&mut host, &mut keypad, 0b00);
+
+        let mut f13 = KeyboardReport::default();
+        f13.press(0x68);
+        assert_eq!(
+            host.reports,
+            [
+                Report::Keyboard(f13),
+                Report::Keyboard(KeyboardReport::default())
+            ]
+        );
+
+        // Momentarily switching layers recolors the keys and shows the layer.
+        step(&mut host, &mut keypad, 0b10);
+        assert_eq!(keypad.keys[0].default_color, Color::default());
+        step(&mut host, &mut keypad, 0b00);
+        assert_eq!(keypad.keys[0].default_color, Color::new(1, 2, 3));
+
+        let names: Vec<_> = host
+            .screens
+            .iter()
+            .map(|screen| match screen {
+                Screen::Home { layer_name, .. } => layer_name.as_str(),
+                _ => "",
+            })
+            .collect();
+        assert_eq!(names, ["Fn", "Base"]);
+
+        // So do the host's LEDs.
+        host.leds = Leds(1 << Led::CapsLock as u8);
+        step(&mut host, &mut keypad, 0b00);
+        assert_eq!(host.screens.len(), 3);
+    }
+
+    /// The consumer usages among `reports`, in order.
+    fn consumer(reports: &[Report]) -> Vec<u16> {
+        reports
+            .iter()
+            .filter_map(|report| match report {
+                Report::Consumer(usage) => Some(*usage),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn retries_refused_reports_in_order() {
+        let config = config();
+        let expander = Tca9555::new();
+        let (spi, cs) = Apa102::chain();
+        let mut keypad = Keypad::new(expander.clone(), spi, cs);
+        let mut device = Device::new();
+        let mut host = Recorder {
+            flaky: true,
+            ..Default::default()
+        };
+        let clock = FakeClock::new();
+
+        keypad.set_debounce(config.debounce);
+
+        for pressed in [0b100, 0b000].repeat(6) {
+            expander.set_pressed(pressed);
+            clock.advance_ms(1);
+            device
+                .update(&config, &mut keypad, &clock, &mut host)
+                .unwrap();
+        }
+
+        // Let the last ones through.
+        for _ in 0..4 {
+            device
+                .update(&config, &mut keypad, &clock, &mut host)
+                .unwrap();
+        }
+
+        assert_eq!(consumer(&host.reports), [0xE9, 0].repeat(6));
+        assert_eq!(device.dropped(), 0);
+    }
+
+    #[test]
+    fn stops_scanning_while_backed_up() {
+        let config = config();
+        let expander = Tca9555::new();
+        let (spi, cs) = Apa102::chain();
+        let mut keypad = Keypad::new(expander.clone(), spi, cs);
+        let mut device = Device::new();
+        let mut host = Recorder {
+            full: true,
+            ..Default::default()
+        };
+        let clock = FakeClock::new();
+
+        keypad.set_debounce(config.debounce);
+
+        // More taps than there's room to hold on to.
+        for pressed in [0b100, 0b000].repeat(6) {
+            expander.set_pressed(pressed);
+            clock.advance_ms(1);
+            device
+                .update(&config, &mut keypad, &clock, &mut host)
+                .unwrap();
+        }
+
+        host.full = false;
+        device
+            .update(&config, &mut keypad, &clock, &mut host)
+            .unwrap();
+
+        // The first four filled the queue, and the keypad wasn't read again until
+        // it drained, so the last two never happened as far as the device knows.
+        // Nothing was folded away, and the host isn't left holding anything down.
+        assert_eq!(consumer(&host.reports), [0xE9, 0].repeat(4));
+        assert_eq!(device.dropped(), 0);
+    }
+}
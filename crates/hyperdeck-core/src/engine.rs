@@ -0,0 +1,1337 @@
+//! Turns key events into actions, as described by the active layers of a [`Config`].
+//!
+//! The engine holds no hardware state: the firmware feeds it `(id, event)` pairs
+//! from the keypad, along with the time, and carries out whatever [`Action`]s come
+//! back. It also needs [`Engine::poll`]ing in between, to notice keys being held.
+//!
+//! A key with only an `on_press` action performs it for as long as the key is down.
+//! If it has [`KeyConfig::repeat`] set, every [`KeyEvent::Repeat`] releases and
+//! presses it again, so that the host sees a fresh keystroke each time.
+//!
+//! # Tap-hold
+//!
+//! A key with an `on_hold` action has to wait and see which one the user meant.
+//! Releasing it within its tapping term (see [`Tapping`]) performs `on_press`,
+//! while holding it any longer performs `on_hold`; either way, the action lasts
+//! until the key is released. Until that's decided, events from other keys are
+//! held back and replayed afterwards, so that a key pressed while a layer-tap key
+//! is held lands on the right layer. Two options, as in QMK, decide on a hold
+//! early:
+//!
+//! - [`Tapping::permissive_hold`], once another key is pressed and released.
+//! - [`Tapping::hold_on_other_key_press`], as soon as another key is pressed.
+//!
+//! # Tap dance
+//!
+//! Keys with [`KeyConfig::on_taps`] or [`KeyConfig::on_tap_hold`] actions also count
+//! their taps. After each release, the key waits another tapping term to be pressed
+//! again, and gives up early once nothing more could happen or another key is
+//! pressed. Whatever the count is then picks the action, using
+//! [`KeyConfig::tap_action`] and [`KeyConfig::hold_action`].
+//!
+//! # Combos
+//!
+//! Before any of that, events go through [`Combos`], which holds back presses of
+//! keys that are part of a [`Combo`] on an active layer for the
+//! [combo term](Tapping::combo_term), in case the rest of its keys follow. A combo
+//! that fires is performed as its lowest key, like a key of its own; see
+//! [`combo`](crate::combo).
+//!
+//! # Layers
+//!
+//! Layers stack QMK-style: a default layer sits at the bottom, and any number of
+//! others can be activated on top of it by [`LayerAction`]s. Keys are looked up
+//! from the highest active layer down, skipping layers where the key is unbound,
+//! and the layer a press was resolved on sticks until the key is released.
+//!
+//! The two [reserved keys](RESERVED_KEYS) step the default layer backwards and
+//! forwards through the configured layers, clearing anything stacked on top.
+
+use heapless::{Deque, Vec};
+
+use crate::combo::{Combos, Event};
+use crate::config::{
+    Combo, Config, KeyAction, KeyConfig, LayerAction, LayerConfig, MouseAction, Tapping,
+    LAYER_KEYS, MAX_COMBOS, MAX_LAYERS,
+};
+use crate::keypad::{KeyEvent, NUM_KEYS, RESERVED_KEYS};
+use crate::time::{Duration, Instant};
+
+/// Colors used for keys without a binding, matching the keypad's own defaults.
+pub const DEFAULT_COLORS: [u8; 6] = [16, 16, 16, 0, 255, 0];
+
+/// An empty chord, releasing everything the key held.
+const RELEASE: [u8; 8] = [0; 8];
+
+/// How many events can wait for a tap-hold key to be decided. Any more, and the
+/// key is taken to be held.
+const MAX_WAITING: usize = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    /// Hold down this chord (modifiers, reserved byte, six keycodes, as in a boot
+    /// report) for `key`, or release it if it's all zeros. See
+    /// [`Keyboard`](crate::keyboard::Keyboard).
+    Keyboard { key: u8, chord: [u8; 8] },
+    /// The highest active layer changed to this one, so the key colors and
+    /// display should be refreshed.
+    Layer(u8),
+    /// Start playing the macro at this index in [`Config::macros`].
+    Macro(u8),
+    /// Start typing the text snippet at this index in [`Config::texts`].
+    Text(u8),
+    /// Start typing this character, like a one-character text snippet.
+    Unicode(char),
+    /// Send a consumer control report with this usage, or 0 to release it.
+    Consumer(u16),
+    /// Start repeatedly tapping this consumer usage, or stop if it's 0.
+    ConsumerRepeat(u16),
+    /// A mouse key was pressed or released.
+    Mouse { action: MouseAction, pressed: bool },
+}
+
+/// The actions produced by a single event or poll.
+///
+/// Deciding a tap-hold key replays every event that waited for it, and a combo
+/// can let several held back presses through at once, so this has room for one
+/// action per event from either, plus a few for the keys themselves and a layer
+/// change.
+pub type Actions = Vec<Action, 32>;
+
+/// What happens to a tap-hold or tap dance key.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Decision {
+    Tap,
+    Hold,
+}
+
+/// A tap-hold or tap dance key that's not yet decided.
+#[derive(Clone, Copy, Debug)]
+struct Undecided {
+    key: usize,
+    /// When the key counts as held, or if it's up, when the taps so far count.
+    deadline: Instant,
+    /// Presses so far, including the current one.
+    taps: u8,
+    /// Whether the key is up, waiting to be tapped again.
+    released: bool,
+}
+
+impl Undecided {
+    /// The decision once the tapping term is up: a key that's still down is held,
+    /// and one that's up was tapped.
+    fn timeout(&self) -> Decision {
+        match self.released {
+            true => Decision::Tap,
+            false => Decision::Hold,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum OneShot {
+    Idle,
+    /// The one-shot key is still down. Pressing another key in the meantime
+    /// turns it into a momentary layer switch.
+    Held {
+        layer: u8,
+        interrupted: bool,
+    },
+    /// The one-shot key was tapped; the layer goes away after the next press.
+    Armed(u8),
+}
+
+pub struct Engine {
+    default_layer: u8,
+    /// Layers active on top of the default one, as a bitmask.
+    layers: u8,
+    one_shot: OneShot,
+    /// The action each key is performing, to be undone when it's released.
+    down: [Option<KeyAction>; NUM_KEYS],
+    /// The layer each key's current press was looked up on.
+    source: [Option<u8>; NUM_KEYS],
+    undecided: Option<Undecided>,
+    /// Events that came in while a key was undecided, oldest first, with when
+    /// they happened.
+    waiting: Deque<(Event, Instant), MAX_WAITING>,
+    combos: Combos,
+}
+
+impl Engine {
+    pub const fn new() -> Self {
+        Self {
+            default_layer: 0,
+            layers: 0,
+            one_shot: OneShot::Idle,
+            down: [None; NUM_KEYS],
+            source: [None; NUM_KEYS],
+            undecided: None,
+            waiting: Deque::new(),
+            combos: Combos::new(),
+        }
+    }
+
+    /// The highest active layer that's actually configured, if any.
+    pub fn layer(&self, config: &Config) -> Option<u8> {
+        self.stack()
+            .find(|&layer| configured(config, layer).is_some())
+    }
+
+    /// Handles an event from key `id` at `now`, returning the actions to carry out.
+    pub fn handle(&mut self, config: &Config, id: u8, event: KeyEvent, now: Instant) -> Actions {
+        self.with_layer_changes(config, |engine, actions| {
+            if id as usize >= NUM_KEYS {
+                return;
+            }
+
+            let combos = engine.combos(config);
+            let term = Duration::millis(config.tapping.combo_term as u64);
+
+            for (event, at) in engine.combos.feed(&combos, term, id, event, now) {
+                engine.dispatch(config, event, at, actions);
+            }
+
+            engine.expire(config, now, actions);
+        })
+    }
+
+    /// Lets through presses that waited for a combo in vain, and decides any
+    /// tap-hold key that's been down for longer than its tapping term.
+    pub fn poll(&mut self, config: &Config, now: Instant) -> Actions {
+        self.with_layer_changes(config, |engine, actions| {
+            let combos = engine.combos(config);
+
+            for (event, at) in engine.combos.poll(&combos, now) {
+                engine.dispatch(config, event, at, actions);
+            }
+
+            engine.expire(config, now, actions)
+        })
+    }
+
+    /// The default and pressed colors of every key on the highest active layer.
+    pub fn colors(&self, config: &Config) -> [[u8; 6]; NUM_KEYS] {
+        let layer = self
+            .layer(config)
+            .and_then(|layer| configured(config, layer));
+
+        core::array::from_fn(|i| match layer {
+            Some(layer) if i < LAYER_KEYS => layer.keys[i].colors,
+            _ => DEFAULT_COLORS,
+        })
+    }
+
+    /// Runs `f`, adding an [`Action::Layer`] to its actions if the highest active
+    /// layer changed.
+    fn with_layer_changes(
+        &mut self,
+        config: &Config,
+        f: impl FnOnce(&mut Self, &mut Actions),
+    ) -> Actions {
+        let mut actions = Actions::new();
+        let before = self.layer(config);
+
+        f(self, &mut actions);
+
+        match self.layer(config) {
+            Some(after) if Some(after) != before => push(&mut actions, Action::Layer(after)),
+            _ => (),
+        }
+
+        actions
+    }
+
+    /// The combos on every active layer, from the top of the stack down.
+    fn combos(&self, config: &Config) -> Vec<Combo, { MAX_LAYERS * MAX_COMBOS }> {
+        (0..MAX_LAYERS as u8)
+            .rev()
+            .filter(|&layer| self.layers & mask(layer) != 0 && layer != self.default_layer)
+            .chain([self.default_layer])
+            .filter_map(|layer| configured(config, layer))
+            .flat_map(|layer| layer.combos.iter().copied())
+            .collect()
+    }
+
+    /// Handles an event that made it past the combos at `at`, holding it back if
+    /// a tap-hold key is undecided.
+    fn dispatch(&mut self, config: &Config, event: Event, at: Instant, actions: &mut Actions) {
+        self.expire(config, at, actions);
+
+        if self.undecided.is_some() && self.waiting.is_full() {
+            // There's no room left to wait, so the key counts as held.
+            self.decide(config, Decision::Hold, actions);
+            self.settle(config, at, actions);
+        }
+
+        match (self.undecided, event) {
+            // Nothing's waiting, so the key's own events carry on the dance.
+            (Some(key), Event::Key(id, event))
+                if key.key == id as usize && self.waiting.is_empty() =>
+            {
+                self.dance(config, key, event, at, actions)
+            }
+            // Only presses and releases can decide anything.
+            (Some(_), Event::Key(_, KeyEvent::Held | KeyEvent::Repeat)) => (),
+            (Some(_), _) => {
+                let _ = self.waiting.push_back((event, at));
+                self.settle(config, at, actions);
+            }
+            (None, _) => self.handle_event(config, event, at, actions),
+        }
+    }
+
+    /// Decides the undecided key, if its tapping term is up.
+    fn expire(&mut self, config: &Config, now: Instant, actions: &mut Actions) {
+        match self.undecided {
+            Some(key) if now >= key.deadline => {
+                self.decide(config, key.timeout(), actions);
+                self.settle(config, now, actions);
+            }
+            _ => (),
+        }
+    }
+
+    /// Handles the undecided key being pressed again or released.
+    fn dance(
+        &mut self,
+        config: &Config,
+        mut key: Undecided,
+        event: KeyEvent,
+        now: Instant,
+        actions: &mut Actions,
+    ) {
+        let Some(binding) = self.binding(config, key.key) else {
+            return;
+        };
+
+        let term = Duration::millis(binding.tapping_term.unwrap_or(config.tapping.term) as u64);
+
+        match event {
+            KeyEvent::Pressed if key.released => {
+                key.taps += 1;
+                key.released = false;
+            }
+            KeyEvent::Released if !key.released && binding.has_more_taps(key.taps) => {
+                key.released = true;
+            }
+            KeyEvent::Released if !key.released => {
+                self.decide(config, Decision::Tap, actions);
+                return self.handle_key(config, key.key as u8, event, now, actions);
+            }
+            _ => return,
+        }
+
+        key.deadline = now + term;
+        self.undecided = Some(key);
+    }
+
+    /// Decides the undecided key if the waiting events are enough to go on, then
+    /// replays them. Replaying can leave another key undecided, in which case the
+    /// rest keep waiting for that one.
+    fn settle(&mut self, config: &Config, now: Instant, actions: &mut Actions) {
+        loop {
+            match self.undecided {
+                Some(key) => match self.decision(&config.tapping, key, now) {
+                    Some(decision) => self.decide(config, decision, actions),
+                    None => return,
+                },
+                None => match self.waiting.pop_front() {
+                    Some((event, at)) => self.handle_event(config, event, at, actions),
+                    None => return,
+                },
+            }
+        }
+    }
+
+    /// What the waiting events say about the undecided key, if anything yet.
+    fn decision(&self, tapping: &Tapping, key: Undecided, now: Instant) -> Option<Decision> {
+        // Keys pressed since the undecided one was.
+        let mut pressed = 0_u16;
+
+        for &(event, at) in self.waiting.iter() {
+            let (id, event) = event.key_event();
+            let bit = 1 << id;
+
+            // Anything after the tapping term happened after it was decided.
+            if at >= key.deadline {
+                return Some(key.timeout());
+            }
+
+            // Another key interrupts the dance.
+            if key.released {
+                match event {
+                    KeyEvent::Pressed => return Some(Decision::Tap),
+                    _ => continue,
+                }
+            }
+
+            match event {
+                KeyEvent::Released if id as usize == key.key => return Some(Decision::Tap),
+                KeyEvent::Pressed if tapping.hold_on_other_key_press => {
+                    return Some(Decision::Hold)
+                }
+                KeyEvent::Pressed => pressed |= bit,
+                KeyEvent::Released if tapping.permissive_hold && pressed & bit != 0 => {
+                    return Some(Decision::Hold)
+                }
+                _ => (),
+            }
+        }
+
+        (now >= key.deadline).then_some(key.timeout())
+    }
+
+    /// Performs the undecided key's action for its tap count, which lasts until
+    /// the key is released, or is tapped if it's already up.
+    fn decide(&mut self, config: &Config, decision: Decision, actions: &mut Actions) {
+        let Some(key) = self.undecided.take() else {
+            return;
+        };
+
+        let i = key.key;
+        let action = self.binding(config, i).and_then(|binding| match decision {
+            Decision::Tap => binding.tap_action(key.taps),
+            Decision::Hold => binding.hold_action(key.taps),
+        });
+
+        if key.released {
+            self.source[i] = None;
+        }
+
+        match (action, key.released) {
+            (Some(action), false) => {
+                self.down[i] = Some(action);
+                self.press(i, action, actions);
+            }
+            (Some(action), true) => {
+                self.press(i, action, actions);
+                self.release(i, action, actions);
+            }
+            (None, _) => (),
+        }
+    }
+
+    fn handle_event(&mut self, config: &Config, event: Event, at: Instant, actions: &mut Actions) {
+        match event {
+            Event::Key(id, event) => self.handle_key(config, id, event, at, actions),
+            Event::ComboPressed { key, action } => {
+                self.interrupt_one_shot();
+                self.down[key as usize] = Some(action);
+                self.press(key as usize, action, actions);
+            }
+            Event::ComboReleased { key } => {
+                if let Some(action) = self.down[key as usize].take() {
+                    self.release(key as usize, action, actions);
+                }
+            }
+        }
+    }
+
+    fn handle_key(
+        &mut self,
+        config: &Config,
+        id: u8,
+        event: KeyEvent,
+        now: Instant,
+        actions: &mut Actions,
+    ) {
+        let i = id as usize;
+
+        if i >= LAYER_KEYS {
+            if event == KeyEvent::Pressed {
+                self.step_default_layer(config, id == RESERVED_KEYS[1]);
+            }
+
+            return;
+        }
+
+        match event {
+            KeyEvent::Pressed => {
+                self.source[i] = self.resolve(config, i);
+
+                // Any press uses up an armed one-shot layer, now that it's been looked up.
+                self.interrupt_one_shot();
+
+                let Some(key) = self.binding(config, i) else {
+                    return;
+                };
+
+                if key.on_hold.is_some() || key.is_tap_dance() {
+                    let term = key.tapping_term.unwrap_or(config.tapping.term);
+
+                    self.undecided = Some(Undecided {
+                        key: i,
+                        deadline: now + Duration::millis(term as u64),
+                        taps: 1,
+                        released: false,
+                    });
+                } else if let Some(action) = key.on_press {
+                    self.down[i] = Some(action);
+                    self.press(i, action, actions);
+                }
+            }
+            // Tap-hold and tap dance keys keep their own time.
+            KeyEvent::Held => (),
+            KeyEvent::Repeat => self.repeat(config, i, actions),
+            KeyEvent::Released => {
+                if let Some(action) = self.down[i].take() {
+                    self.release(i, action, actions);
+                }
+
+                self.source[i] = None;
+            }
+        }
+    }
+
+    /// Performs key `i`'s action again, if it repeats.
+    fn repeat(&mut self, config: &Config, i: usize, actions: &mut Actions) {
+        let repeats = self.binding(config, i).is_some_and(|key| key.repeat);
+
+        match self.down[i] {
+            // Anything else would just be undone and redone.
+            Some(
+                action @ (KeyAction::Keyboard(_)
+                | KeyAction::Consumer(_)
+                | KeyAction::Macro(_)
+                | KeyAction::Text(_)
+                | KeyAction::Unicode(_)),
+            ) if repeats => {
+                self.release(i, action, actions);
+                self.press(i, action, actions);
+            }
+            _ => (),
+        }
+    }
+
+    /// Tells a one-shot layer that another key was pressed.
+    fn interrupt_one_shot(&mut self) {
+        match self.one_shot {
+            OneShot::Held { layer, .. } => {
+                self.one_shot = OneShot::Held {
+                    layer,
+                    interrupted: true,
+                }
+            }
+            OneShot::Armed(layer) => {
+                self.one_shot = OneShot::Idle;
+                self.layer_off(layer);
+            }
+            OneShot::Idle => (),
+        }
+    }
+
+    fn press(&mut self, i: usize, action: KeyAction, actions: &mut Actions) {
+        use LayerAction::*;
+
+        let action = match action {
+            KeyAction::Keyboard(chord) => {
+                return push(
+                    actions,
+                    Action::Keyboard {
+                        key: i as u8,
+                        chord,
+                    },
+                )
+            }
+            KeyAction::Macro(index) => return push(actions, Action::Macro(index)),
+            KeyAction::Text(index) => return push(actions, Action::Text(index)),
+            KeyAction::Unicode(c) => return push(actions, Action::Unicode(c)),
+            KeyAction::Consumer(usage) => return push(actions, Action::Consumer(usage)),
+            KeyAction::ConsumerRepeat(usage) => {
+                return push(actions, Action::ConsumerRepeat(usage))
+            }
+            KeyAction::Mouse(action) => {
+                return push(
+                    actions,
+                    Action::Mouse {
+                        action,
+                        pressed: true,
+                    },
+                )
+            }
+            KeyAction::Layer(action) => action,
+        };
+
+        match action {
+            Momentary(layer) => self.layer_on(layer),
+            Toggle(layer) => self.layers ^= mask(layer),
+            OneShot(layer) => {
+                self.layer_on(layer);
+                self.one_shot = self::OneShot::Held {
+                    layer,
+                    interrupted: false,
+                };
+            }
+            To(layer) => {
+                self.layers = mask(layer);
+                self.one_shot = self::OneShot::Idle;
+            }
+            Default(layer) if (layer as usize) < MAX_LAYERS => self.default_layer = layer,
+            Default(_) => (),
+        }
+    }
+
+    fn release(&mut self, i: usize, action: KeyAction, actions: &mut Actions) {
+        match action {
+            KeyAction::Keyboard(_) => push(
+                actions,
+                Action::Keyboard {
+                    key: i as u8,
+                    chord: RELEASE,
+                },
+            ),
+            KeyAction::Consumer(_) => push(actions, Action::Consumer(0)),
+            KeyAction::ConsumerRepeat(_) => push(actions, Action::ConsumerRepeat(0)),
+            KeyAction::Mouse(action) => push(
+                actions,
+                Action::Mouse {
+                    action,
+                    pressed: false,
+                },
+            ),
+            KeyAction::Layer(LayerAction::Momentary(layer)) => self.layer_off(layer),
+            KeyAction::Layer(LayerAction::OneShot(layer)) => match self.one_shot {
+                OneShot::Held {
+                    interrupted: false, ..
+                } => self.one_shot = OneShot::Armed(layer),
+                _ => {
+                    self.one_shot = OneShot::Idle;
+                    self.layer_off(layer);
+                }
+            },
+            // Macros and text play to the end regardless of the key.
+            KeyAction::Layer(_)
+            | KeyAction::Macro(_)
+            | KeyAction::Text(_)
+            | KeyAction::Unicode(_) => (),
+        }
+    }
+
+    fn step_default_layer(&mut self, config: &Config, forwards: bool) {
+        let count = MAX_LAYERS as u8;
+        let offset = |step: u8| match forwards {
+            true => (self.default_layer + step) % count,
+            false => (self.default_layer + count - step) % count,
+        };
+
+        if let Some(layer) = (1..=count)
+            .map(offset)
+            .find(|&l| configured(config, l).is_some())
+        {
+            self.default_layer = layer;
+            self.layers = 0;
+            self.one_shot = OneShot::Idle;
+        }
+    }
+
+    fn layer_on(&mut self, layer: u8) {
+        self.layers |= mask(layer);
+    }
+
+    fn layer_off(&mut self, layer: u8) {
+        self.layers &= !mask(layer);
+    }
+
+    /// Active layers, from the top of the stack down to the default layer.
+    fn stack(&self) -> impl Iterator<Item = u8> + '_ {
+        (0..MAX_LAYERS as u8)
+            .rev()
+            .filter(|&layer| self.layers & mask(layer) != 0)
+            .chain([self.default_layer])
+    }
+
+    /// Finds the layer that key `i` should be looked up on.
+    fn resolve(&self, config: &Config, i: usize) -> Option<u8> {
+        self.stack()
+            .find(|&layer| configured(config, layer).is_some_and(|layer| layer.keys[i].is_bound()))
+    }
+
+    /// The binding for key `i` on the layer its current press was resolved on.
+    fn binding<'a>(&self, config: &'a Config, i: usize) -> Option<&'a KeyConfig> {
+        self.source[i]
+            .and_then(|layer| configured(config, layer))
+            .map(|layer| &layer.keys[i])
+    }
+}
+
+impl Default for Engine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn configured(config: &Config, layer: u8) -> Option<&LayerConfig> {
+    config.layers.get(layer as usize)?.as_ref()
+}
+
+/// The bit for `layer` in [`Engine::layers`]; zero for out-of-range layers.
+fn mask(layer: u8) -> u8 {
+    match (layer as usize) < MAX_LAYERS {
+        true => 1 << layer,
+        false => 0,
+    }
+}
+
+fn push(actions: &mut Actions, action: Action) {
+    // See [`Actions`] for why this always fits.
+    let _ = actions.push(action);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keypad::KeyEvent::*;
+
+    const CTRL_T: [u8; 8] = [0x01, 0, 0x17, 0, 0, 0, 0, 0];
+    const CTRL_W: [u8; 8] = [0x01, 0, 0x1A, 0, 0, 0, 0, 0];
+    const F13: [u8; 8] = [0, 0, 0x68, 0, 0, 0, 0, 0];
+    const F14: [u8; 8] = [0, 0, 0x69, 0, 0, 0, 0, 0];
+    const F15: [u8; 8] = [0, 0, 0x6A, 0, 0, 0, 0, 0];
+
+    fn key(on_press: Option<KeyAction>, on_hold: Option<KeyAction>) -> KeyConfig {
+        KeyConfig {
+            on_press,
+            on_hold,
+            colors: DEFAULT_COLORS,
+            ..Default::default()
+        }
+    }
+
+    fn keyboard(report: [u8; 8]) -> Option<KeyAction> {
+        Some(KeyAction::Keyboard(report))
+    }
+
+    fn layer(action: LayerAction) -> Option<KeyAction> {
+        Some(KeyAction::Layer(action))
+    }
+
+    /// Layer 0 has plain keys on 0-2 and layer keys on 3-9; layers 1 and 2 only
+    /// rebind key 0, and layer 4 is left unconfigured.
+    fn config() -> Config {
+        use LayerAction::*;
+
+        let mut base = LayerConfig::default();
+
+        base.keys[0] = key(keyboard(F13), None);
+        base.keys[0].colors = [1, 2, 3, 4, 5, 6];
+        base.keys[1] = key(keyboard(CTRL_T), keyboard(CTRL_W));
+        base.keys[2] = key(None, keyboard(CTRL_W));
+        base.keys[3] = key(layer(Momentary(1)), None);
+        base.keys[4] = key(layer(Toggle(2)), None);
+        base.keys[5] = key(layer(OneShot(1)), None);
+        base.keys[6] = key(layer(To(2)), None);
+        base.keys[7] = key(layer(Default(1)), None);
+        base.keys[8] = key(keyboard(F15), layer(Momentary(2)));
+        base.keys[9] = key(layer(Momentary(4)), None);
+
+        let mut one = LayerConfig::default();
+        one.keys[0] = key(keyboard(F14), None);
+        one.keys[0].colors = [7; 6];
+
+        let mut two = LayerConfig::default();
+        two.keys[0] = key(keyboard(F15), None);
+
+        let mut config = Config::default();
+        config.layers[0] = Some(base);
+        config.layers[1] = Some(one);
+        config.layers[2] = Some(two);
+        config
+    }
+
+    fn at(ms: u64) -> Instant {
+        Instant::from_ticks(ms * 1000)
+    }
+
+    /// Feeds `events` through `engine` all at once, collecting every action.
+    fn feed(
+        engine: &mut Engine,
+        config: &Config,
+        events: &[(u8, KeyEvent)],
+    ) -> std::vec::Vec<Action> {
+        events
+            .iter()
+            .flat_map(|(id, event)| engine.handle(config, *id, *event, at(0)))
+            .collect()
+    }
+
+    fn run(config: &Config, events: &[(u8, KeyEvent)]) -> std::vec::Vec<Action> {
+        feed(&mut Engine::new(), config, events)
+    }
+
+    /// Feeds `events` through a new engine at the given times in milliseconds,
+    /// polling it every millisecond in between like the firmware's main loop.
+    fn play(config: &Config, events: &[(u64, u8, KeyEvent)]) -> std::vec::Vec<Action> {
+        play_on(&mut Engine::new(), config, events)
+    }
+
+    fn play_on(
+        engine: &mut Engine,
+        config: &Config,
+        events: &[(u64, u8, KeyEvent)],
+    ) -> std::vec::Vec<Action> {
+        let mut actions = std::vec::Vec::new();
+        let mut clock = events.first().map_or(0, |event| event.0);
+
+        for &(time, id, event) in events {
+            while clock < time {
+                clock += 1;
+                actions.extend(engine.poll(config, at(clock)));
+            }
+
+            actions.extend(engine.handle(config, id, event, at(time)));
+        }
+
+        actions
+    }
+
+    fn report(key: u8, chord: [u8; 8]) -> Action {
+        Action::Keyboard { key, chord }
+    }
+
+    fn reports(key: u8, chords: &[[u8; 8]]) -> std::vec::Vec<Action> {
+        chords.iter().map(|chord| report(key, *chord)).collect()
+    }
+
+    #[test]
+    fn press_follows_key() {
+        let events = [(0, Pressed), (0, Held), (0, Repeat), (0, Released)];
+        assert_eq!(run(&config(), &events), reports(0, &[F13, RELEASE]));
+    }
+
+    #[test]
+    fn auto_repeat() {
+        let mut config = config();
+        let base = config.layers[0].as_mut().unwrap();
+        base.keys[0].repeat = true;
+        base.keys[3].repeat = true;
+
+        let events = [
+            (0, Pressed),
+            (0, Repeat),
+            (0, Held),
+            (0, Repeat),
+            (0, Released),
+        ];
+        assert_eq!(
+            run(&config, &events),
+            reports(0, &[F13, RELEASE, F13, RELEASE, F13, RELEASE])
+        );
+
+        // Layer keys don't repeat.
+        let events = [(3, Pressed), (3, Repeat), (3, Released)];
+        assert_eq!(run(&config, &events), [Action::Layer(1), Action::Layer(0)]);
+    }
+
+    #[test]
+    fn tap_or_hold() {
+        let tap = [(0, 1, Pressed), (199, 1, Released)];
+        assert_eq!(play(&config(), &tap), reports(1, &[CTRL_T, RELEASE]));
+
+        let hold = [(0, 1, Pressed), (500, 1, Released)];
+        assert_eq!(play(&config(), &hold), reports(1, &[CTRL_W, RELEASE]));
+
+        // The hold starts as soon as the tapping term is up, not on release.
+        let mut engine = Engine::new();
+        let config = config();
+
+        assert_eq!(engine.handle(&config, 1, Pressed, at(0))[..], []);
+        assert_eq!(engine.poll(&config, at(199))[..], []);
+        assert_eq!(engine.poll(&config, at(200))[..], reports(1, &[CTRL_W]));
+
+        // Without an `on_press`, tapping does nothing.
+        let tap = [(0, 2, Pressed), (100, 2, Released)];
+        assert_eq!(play(&config, &tap), []);
+    }
+
+    #[test]
+    fn tapping_terms() {
+        let mut config = config();
+        let events = [(0, 1, Pressed), (300, 1, Released)];
+
+        config.tapping.term = 400;
+        assert_eq!(play(&config, &events), reports(1, &[CTRL_T, RELEASE]));
+
+        // Keys can override the global term.
+        config.layers[0].as_mut().unwrap().keys[1].tapping_term = Some(250);
+        assert_eq!(play(&config, &events), reports(1, &[CTRL_W, RELEASE]));
+    }
+
+    #[test]
+    fn other_keys_wait_for_the_decision() {
+        // Key 8 taps F15 or holds layer 2, where key 0 is F15 rather than F13.
+        let events = [
+            (0, 8, Pressed),
+            (50, 0, Pressed),
+            (80, 0, Released),
+            (100, 8, Released),
+        ];
+
+        assert_eq!(play(&config(), &events[..3]), []);
+        assert_eq!(
+            play(&config(), &events),
+            [
+                report(8, F15),
+                report(0, F13),
+                report(0, RELEASE),
+                report(8, RELEASE),
+            ]
+        );
+
+        // Once the term is up, the waiting press lands on the layer.
+        let events = [(0, 8, Pressed), (50, 0, Pressed), (250, 0, Released)];
+        assert_eq!(
+            play(&config(), &events),
+            [report(0, F15), Action::Layer(2), report(0, RELEASE)]
+        );
+    }
+
+    #[test]
+    fn permissive_hold() {
+        let mut config = config();
+        config.tapping.permissive_hold = true;
+
+        // Another key tapped inside the term makes it a hold.
+        let events = [
+            (0, 8, Pressed),
+            (50, 0, Pressed),
+            (80, 0, Released),
+            (100, 8, Released),
+        ];
+        assert_eq!(
+            play(&config, &events),
+            [
+                report(0, F15),
+                report(0, RELEASE),
+                Action::Layer(2),
+                Action::Layer(0),
+            ]
+        );
+
+        // Rolling over from it to another key is still a tap.
+        let events = [
+            (0, 8, Pressed),
+            (50, 0, Pressed),
+            (80, 8, Released),
+            (100, 0, Released),
+        ];
+        assert_eq!(
+            play(&config, &events),
+            [
+                report(8, F15),
+                report(0, F13),
+                report(8, RELEASE),
+                report(0, RELEASE),
+            ]
+        );
+    }
+
+    #[test]
+    fn hold_on_other_key_press() {
+        let mut config = config();
+        config.tapping.hold_on_other_key_press = true;
+
+        let events = [(0, 8, Pressed), (50, 0, Pressed)];
+        assert_eq!(play(&config, &events), [report(0, F15), Action::Layer(2)]);
+
+        // On its own, it's still a tap.
+        let events = [(0, 8, Pressed), (50, 8, Released)];
+        assert_eq!(play(&config, &events), reports(8, &[F15, RELEASE]));
+    }
+
+    #[test]
+    fn unbound_keys_do_nothing() {
+        let events = [(12, Pressed), (12, Held), (12, Released)];
+        assert_eq!(run(&config(), &events), []);
+
+        // No layers at all.
+        let events = [(0, Pressed), (0, Released), (15, Pressed), (15, Released)];
+        assert_eq!(run(&Config::default(), &events), []);
+    }
+
+    #[test]
+    fn releases_survive_config_changes() {
+        let mut engine = Engine::new();
+        let mut config = config();
+
+        assert_eq!(
+            engine.handle(&config, 0, Pressed, at(0))[..],
+            reports(0, &[F13])
+        );
+
+        config.layers[0] = None;
+        assert_eq!(
+            engine.handle(&config, 0, Released, at(0))[..],
+            reports(0, &[RELEASE])
+        );
+    }
+
+    #[test]
+    fn momentary() {
+        let events = [
+            (3, Pressed),
+            (0, Pressed),
+            (0, Released),
+            (3, Released),
+            (0, Pressed),
+        ];
+
+        assert_eq!(
+            run(&config(), &events),
+            [
+                Action::Layer(1),
+                report(0, F14),
+                report(0, RELEASE),
+                Action::Layer(0),
+                report(0, F13),
+            ]
+        );
+    }
+
+    #[test]
+    fn presses_stick_to_their_layer() {
+        // Key 0 goes down on layer 1, so letting go of the layer doesn't re-resolve it.
+        let mut engine = Engine::new();
+        let config = config();
+
+        feed(
+            &mut engine,
+            &config,
+            &[(3, Pressed), (0, Pressed), (3, Released)],
+        );
+        assert_eq!(engine.layer(&config), Some(0));
+
+        assert_eq!(
+            engine.handle(&config, 0, Released, at(0))[..],
+            reports(0, &[RELEASE])
+        );
+    }
+
+    #[test]
+    fn toggle() {
+        let mut engine = Engine::new();
+        let config = config();
+
+        feed(&mut engine, &config, &[(4, Pressed), (4, Released)]);
+        assert_eq!(engine.layer(&config), Some(2));
+
+        // Layer 2 doesn't bind key 1, so it falls through to layer 0.
+        assert_eq!(
+            feed(&mut engine, &config, &[(1, Pressed), (1, Released)]),
+            reports(1, &[CTRL_T, RELEASE])
+        );
+
+        feed(&mut engine, &config, &[(4, Pressed), (4, Released)]);
+        assert_eq!(engine.layer(&config), Some(0));
+    }
+
+    #[test]
+    fn one_shot() {
+        let mut engine = Engine::new();
+        let config = config();
+
+        feed(&mut engine, &config, &[(5, Pressed), (5, Released)]);
+        assert_eq!(engine.layer(&config), Some(1));
+
+        // The next press lands on layer 1, then the layer goes away.
+        let events = [(0, Pressed), (0, Released), (0, Pressed), (0, Released)];
+        assert_eq!(
+            feed(&mut engine, &config, &events),
+            [
+                report(0, F14),
+                Action::Layer(0),
+                report(0, RELEASE),
+                report(0, F13),
+                report(0, RELEASE),
+            ]
+        );
+    }
+
+    #[test]
+    fn one_shot_held_is_momentary() {
+        let mut engine = Engine::new();
+        let config = config();
+
+        let events = [(5, Pressed), (0, Pressed), (0, Released), (5, Released)];
+        assert_eq!(
+            feed(&mut engine, &config, &events),
+            [
+                Action::Layer(1),
+                report(0, F14),
+                report(0, RELEASE),
+                Action::Layer(0),
+            ]
+        );
+    }
+
+    #[test]
+    fn to_and_default() {
+        let mut engine = Engine::new();
+        let config = config();
+
+        // TO(2) replaces the toggled layer.
+        feed(
+            &mut engine,
+            &config,
+            &[(3, Pressed), (6, Pressed), (6, Released)],
+        );
+        assert_eq!(engine.layer(&config), Some(2));
+        assert_eq!(engine.layers, mask(2));
+
+        feed(
+            &mut engine,
+            &config,
+            &[(3, Released), (4, Pressed), (4, Released)],
+        );
+        assert_eq!(engine.layer(&config), Some(0));
+
+        let mut engine = Engine::new();
+        assert_eq!(
+            feed(&mut engine, &config, &[(7, Pressed), (7, Released)]),
+            [Action::Layer(1)]
+        );
+
+        // Layer 1 is now the bottom of the stack; key 3 is unbound there.
+        assert_eq!(feed(&mut engine, &config, &[(3, Pressed)]), []);
+    }
+
+    #[test]
+    fn layer_tap() {
+        let tap = [(0, 8, Pressed), (100, 8, Released)];
+        assert_eq!(play(&config(), &tap), reports(8, &[F15, RELEASE]));
+
+        let hold = [
+            (0, 8, Pressed),
+            (300, 0, Pressed),
+            (350, 0, Released),
+            (400, 8, Released),
+        ];
+        assert_eq!(
+            play(&config(), &hold),
+            [
+                Action::Layer(2),
+                report(0, F15),
+                report(0, RELEASE),
+                Action::Layer(0),
+            ]
+        );
+    }
+
+    #[test]
+    fn tap_dance() {
+        let mut config = config();
+        let keys = &mut config.layers[0].as_mut().unwrap().keys;
+
+        // Mute, deafen, or push to talk.
+        keys[10] = key(Some(KeyAction::Consumer(0xE2)), None);
+        keys[10].on_taps[0] = keyboard(F14);
+        keys[10].on_tap_hold = keyboard(F13);
+
+        // One tap waits to see if another follows.
+        let mut engine = Engine::new();
+        let once = [(0, 10, Pressed), (50, 10, Released)];
+
+        assert_eq!(play_on(&mut engine, &config, &once), []);
+        assert_eq!(engine.poll(&config, at(249))[..], []);
+        assert_eq!(
+            engine.poll(&config, at(250))[..],
+            [Action::Consumer(0xE2), Action::Consumer(0)]
+        );
+
+        // There's no triple tap, so the second doesn't wait.
+        let twice = [
+            (0, 10, Pressed),
+            (50, 10, Released),
+            (100, 10, Pressed),
+            (150, 10, Released),
+        ];
+        assert_eq!(play(&config, &twice), reports(10, &[F14, RELEASE]));
+
+        let tap_hold = [
+            (0, 10, Pressed),
+            (50, 10, Released),
+            (100, 10, Pressed),
+            (400, 10, Released),
+        ];
+        assert_eq!(play(&config, &tap_hold), reports(10, &[F13, RELEASE]));
+
+        // Without an `on_hold`, holding it performs the single tap action.
+        let hold = [(0, 10, Pressed), (400, 10, Released)];
+        assert_eq!(
+            play(&config, &hold),
+            [Action::Consumer(0xE2), Action::Consumer(0)]
+        );
+
+        // Pressing another key ends the dance.
+        let interrupted = [(0, 10, Pressed), (50, 10, Released), (100, 0, Pressed)];
+        assert_eq!(
+            play(&config, &interrupted),
+            [Action::Consumer(0xE2), Action::Consumer(0), report(0, F13)]
+        );
+    }
+
+    #[test]
+    fn combos() {
+        let keys = 1 << 10 | 1 << 11;
+        let mut config = config();
+
+        let base = config.layers[0].as_mut().unwrap();
+        base.keys[10] = key(keyboard(F13), None);
+        base.keys[11] = key(keyboard(F14), None);
+        base.combos
+            .push(Combo {
+                keys,
+                action: KeyAction::Keyboard(F15),
+            })
+            .unwrap();
+
+        let one = config.layers[1].as_mut().unwrap();
+        one.combos
+            .push(Combo {
+                keys,
+                action: KeyAction::Keyboard(CTRL_T),
+            })
+            .unwrap();
+
+        // Pressed together, the keys perform the combo as the lowest of them.
+        let together = [
+            (0, 10, Pressed),
+            (20, 11, Pressed),
+            (100, 11, Released),
+            (120, 10, Released),
+        ];
+        assert_eq!(play(&config, &together), reports(10, &[F15, RELEASE]));
+
+        // Pressed apart, they perform their own actions once the combo term is up.
+        let apart = [
+            (0, 10, Pressed),
+            (100, 11, Pressed),
+            (200, 11, Released),
+            (210, 10, Released),
+        ];
+        assert_eq!(
+            play(&config, &apart),
+            [
+                report(10, F13),
+                report(11, F14),
+                report(11, RELEASE),
+                report(10, RELEASE),
+            ]
+        );
+
+        // Higher layers' combos come first.
+        let layered = [
+            (0, 3, Pressed),
+            (10, 10, Pressed),
+            (20, 11, Pressed),
+            (30, 11, Released),
+            (40, 10, Released),
+            (50, 3, Released),
+        ];
+        assert_eq!(
+            play(&config, &layered),
+            [
+                Action::Layer(1),
+                report(10, CTRL_T),
+                report(10, RELEASE),
+                Action::Layer(0),
+            ]
+        );
+    }
+
+    #[test]
+    fn macros_start_on_press() {
+        let mut config = config();
+        config.layers[0].as_mut().unwrap().keys[10] = key(Some(KeyAction::Macro(3)), None);
+
+        let events = [(10, Pressed), (10, Held), (10, Released)];
+        assert_eq!(run(&config, &events), [Action::Macro(3)]);
+    }
+
+    #[test]
+    fn consumer_keys() {
+        let mut config = config();
+        let keys = &mut config.layers[0].as_mut().unwrap().keys;
+
+        keys[10] = key(Some(KeyAction::Consumer(0xCD)), None);
+        keys[11] = key(
+            Some(KeyAction::Consumer(0xE2)),
+            Some(KeyAction::ConsumerRepeat(0xEA)),
+        );
+
+        let events = [(10, Pressed), (10, Held), (10, Released)];
+        assert_eq!(
+            run(&config, &events),
+            [Action::Consumer(0xCD), Action::Consumer(0)]
+        );
+
+        let events = [(0, 11, Pressed), (500, 11, Released)];
+        assert_eq!(
+            play(&config, &events),
+            [Action::ConsumerRepeat(0xEA), Action::ConsumerRepeat(0)]
+        );
+    }
+
+    #[test]
+    fn mouse_keys() {
+        let mut config = config();
+        let keys = &mut config.layers[0].as_mut().unwrap().keys;
+        let action = MouseAction::Button(1);
+
+        keys[10] = key(Some(KeyAction::Mouse(action)), None);
+
+        let events = [(10, Pressed), (10, Held), (10, Released)];
+        assert_eq!(
+            run(&config, &events),
+            [
+                Action::Mouse {
+                    action,
+                    pressed: true
+                },
+                Action::Mouse {
+                    action,
+                    pressed: false
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn unconfigured_layers_are_skipped() {
+        let events = [(9, Pressed), (0, Pressed), (0, Released), (9, Released)];
+        assert_eq!(run(&config(), &events), reports(0, &[F13, RELEASE]));
+    }
+
+    #[test]
+    fn reserved_keys_cycle_default_layer() {
+        let mut engine = Engine::new();
+        let config = config();
+
+        let next = [(15, Pressed), (15, Released)];
+        let previous = [(14, Pressed), (14, Released)];
+
+        assert_eq!(feed(&mut engine, &config, &next), [Action::Layer(1)]);
+        assert_eq!(feed(&mut engine, &config, &next), [Action::Layer(2)]);
+        assert_eq!(feed(&mut engine, &config, &next), [Action::Layer(0)]);
+        assert_eq!(feed(&mut engine, &config, &previous), [Action::Layer(2)]);
+
+        // Stepping clears anything stacked on top.
+        feed(&mut engine, &config, &next);
+        feed(&mut engine, &config, &[(4, Pressed), (4, Released)]);
+        assert_eq!(engine.layer(&config), Some(2));
+
+        assert_eq!(feed(&mut engine, &config, &next), [Action::Layer(1)]);
+        assert_eq!(engine.layers, 0);
+    }
+
+    #[test]
+    fn colors_follow_layer() {
+        let mut engine = Engine::new();
+        let config = config();
+
+        assert_eq!(engine.colors(&config)[0], [1, 2, 3, 4, 5, 6]);
+        assert_eq!(engine.colors(&config)[15], DEFAULT_COLORS);
+
+        engine.handle(&config, 3, Pressed, at(0));
+        assert_eq!(engine.colors(&config)[0], [7; 6]);
+    }
+}
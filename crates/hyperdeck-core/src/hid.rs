@@ -0,0 +1,404 @@
+//! The keypad's HID report descriptor, and the reports it describes.
+//!
+//! A single HID interface carries four kinds of input report, told apart by the
+//! report ID in their first byte:
+//!
+//! | ID | Report                                                         | Length |
+//! |----|----------------------------------------------------------------|--------|
+//! | 1  | Keyboard: modifiers, then one bit for every other key (NKRO)   | 30     |
+//! | 2  | Consumer control: one 16-bit usage, 0 for none                 | 3      |
+//! | 3  | System control: one generic desktop usage, 0 for none          | 2      |
+//! | 4  | Mouse: five buttons, X, Y, wheel and horizontal pan            | 6      |
+//!
+//! The keyboard collection also has an output report (ID 1) for the host's LED
+//! state: num lock, caps lock, scroll lock, compose and kana, in that bit order.
+//!
+//! BIOSes and other simple hosts don't parse report descriptors, so there's also a
+//! separate boot keyboard interface described by [`BOOT_KEYBOARD_DESCRIPTOR`]. It
+//! only carries reports while the host has asked for the boot protocol; see
+//! [`keyboard`](crate::keyboard).
+
+/// Maximum length of an encoded report, ID included.
+pub const MAX_REPORT_LEN: usize = 30;
+
+/// Number of keys in the keyboard report's bitmap: every usage below the modifiers.
+pub const NKRO_KEYS: usize = 0xE0;
+
+#[rustfmt::skip]
+pub const REPORT_DESCRIPTOR: &[u8] = &[
+    // Keyboard
+    0x05, 0x01,       // Usage Page (Generic Desktop)
+    0x09, 0x06,       // Usage (Keyboard)
+    0xA1, 0x01,       // Collection (Application)
+    0x85, 0x01,       //   Report ID (1)
+    0x05, 0x07,       //   Usage Page (Keyboard/Keypad)
+    0x19, 0xE0,       //   Usage Minimum (Left Control)
+    0x29, 0xE7,       //   Usage Maximum (Right GUI)
+    0x15, 0x00,       //   Logical Minimum (0)
+    0x25, 0x01,       //   Logical Maximum (1)
+    0x75, 0x01,       //   Report Size (1)
+    0x95, 0x08,       //   Report Count (8)
+    0x81, 0x02,       //   Input (Data, Variable, Absolute): modifiers
+    0x05, 0x08,       //   Usage Page (LEDs)
+    0x19, 0x01,       //   Usage Minimum (Num Lock)
+    0x29, 0x05,       //   Usage Maximum (Kana)
+    0x75, 0x01,       //   Report Size (1)
+    0x95, 0x05,       //   Report Count (5)
+    0x91, 0x02,       //   Output (Data, Variable, Absolute): LEDs
+    0x75, 0x03,       //   Report Size (3)
+    0x95, 0x01,       //   Report Count (1)
+    0x91, 0x01,       //   Output (Constant): padding
+    0x05, 0x07,       //   Usage Page (Keyboard/Keypad)
+    0x19, 0x00,       //   Usage Minimum (0)
+    0x29, 0xDF,       //   Usage Maximum (0xDF)
+    0x15, 0x00,       //   Logical Minimum (0)
+    0x25, 0x01,       //   Logical Maximum (1)
+    0x75, 0x01,       //   Report Size (1)
+    0x95, 0xE0,       //   Report Count (224)
+    0x81, 0x02,       //   Input (Data, Variable, Absolute): keys
+    0xC0,             // End Collection
+
+    // Consumer control
+    0x05, 0x0C,       // Usage Page (Consumer)
+    0x09, 0x01,       // Usage (Consumer Control)
+    0xA1, 0x01,       // Collection (Application)
+    0x85, 0x02,       //   Report ID (2)
+    0x19, 0x00,       //   Usage Minimum (0)
+    0x2A, 0xFF, 0x03, //   Usage Maximum (0x3FF)
+    0x15, 0x00,       //   Logical Minimum (0)
+    0x26, 0xFF, 0x03, //   Logical Maximum (0x3FF)
+    0x75, 0x10,       //   Report Size (16)
+    0x95, 0x01,       //   Report Count (1)
+    0x81, 0x00,       //   Input (Data, Array, Absolute)
+    0xC0,             // End Collection
+
+    // System control
+    0x05, 0x01,       // Usage Page (Generic Desktop)
+    0x09, 0x80,       // Usage (System Control)
+    0xA1, 0x01,       // Collection (Application)
+    0x85, 0x03,       //   Report ID (3)
+    0x19, 0x81,       //   Usage Minimum (System Power Down)
+    0x29, 0xB7,       //   Usage Maximum (System Display LCD Autoscale)
+    0x16, 0x81, 0x00, //   Logical Minimum (0x81)
+    0x26, 0xB7, 0x00, //   Logical Maximum (0xB7)
+    0x75, 0x08,       //   Report Size (8)
+    0x95, 0x01,       //   Report Count (1)
+    0x81, 0x00,       //   Input (Data, Array, Absolute)
+    0xC0,             // End Collection
+
+    // Mouse
+    0x05, 0x01,       // Usage Page (Generic Desktop)
+    0x09, 0x02,       // Usage (Mouse)
+    0xA1, 0x01,       // Collection (Application)
+    0x85, 0x04,       //   Report ID (4)
+    0x09, 0x01,       //   Usage (Pointer)
+    0xA1, 0x00,       //   Collection (Physical)
+    0x05, 0x09,       //     Usage Page (Button)
+    0x19, 0x01,       //     Usage Minimum (1)
+    0x29, 0x05,       //     Usage Maximum (5)
+    0x15, 0x00,       //     Logical Minimum (0)
+    0x25, 0x01,       //     Logical Maximum (1)
+    0x75, 0x01,       //     Report Size (1)
+    0x95, 0x05,       //     Report Count (5)
+    0x81, 0x02,       //     Input (Data, Variable, Absolute): buttons
+    0x75, 0x03,       //     Report Size (3)
+    0x95, 0x01,       //     Report Count (1)
+    0x81, 0x01,       //     Input (Constant): padding
+    0x05, 0x01,       //     Usage Page (Generic Desktop)
+    0x09, 0x30,       //     Usage (X)
+    0x09, 0x31,       //     Usage (Y)
+    0x09, 0x38,       //     Usage (Wheel)
+    0x15, 0x81,       //     Logical Minimum (-127)
+    0x25, 0x7F,       //     Logical Maximum (127)
+    0x75, 0x08,       //     Report Size (8)
+    0x95, 0x03,       //     Report Count (3)
+    0x81, 0x06,       //     Input (Data, Variable, Relative)
+    0x05, 0x0C,       //     Usage Page (Consumer)
+    0x0A, 0x38, 0x02, //     Usage (AC Pan)
+    0x95, 0x01,       //     Report Count (1)
+    0x81, 0x06,       //     Input (Data, Variable, Relative)
+    0xC0,             //   End Collection
+    0xC0,             // End Collection
+];
+
+/// The standard boot keyboard descriptor (HID 1.11, appendix B.1), for the boot
+/// interface. Its reports have no ID.
+#[rustfmt::skip]
+pub const BOOT_KEYBOARD_DESCRIPTOR: &[u8] = &[
+    0x05, 0x01,       // Usage Page (Generic Desktop)
+    0x09, 0x06,       // Usage (Keyboard)
+    0xA1, 0x01,       // Collection (Application)
+    0x05, 0x07,       //   Usage Page (Keyboard/Keypad)
+    0x19, 0xE0,       //   Usage Minimum (Left Control)
+    0x29, 0xE7,       //   Usage Maximum (Right GUI)
+    0x15, 0x00,       //   Logical Minimum (0)
+    0x25, 0x01,       //   Logical Maximum (1)
+    0x75, 0x01,       //   Report Size (1)
+    0x95, 0x08,       //   Report Count (8)
+    0x81, 0x02,       //   Input (Data, Variable, Absolute): modifiers
+    0x75, 0x08,       //   Report Size (8)
+    0x95, 0x01,       //   Report Count (1)
+    0x81, 0x01,       //   Input (Constant): reserved
+    0x05, 0x08,       //   Usage Page (LEDs)
+    0x19, 0x01,       //   Usage Minimum (Num Lock)
+    0x29, 0x05,       //   Usage Maximum (Kana)
+    0x75, 0x01,       //   Report Size (1)
+    0x95, 0x05,       //   Report Count (5)
+    0x91, 0x02,       //   Output (Data, Variable, Absolute): LEDs
+    0x75, 0x03,       //   Report Size (3)
+    0x95, 0x01,       //   Report Count (1)
+    0x91, 0x01,       //   Output (Constant): padding
+    0x05, 0x07,       //   Usage Page (Keyboard/Keypad)
+    0x19, 0x00,       //   Usage Minimum (0)
+    0x29, 0xFF,       //   Usage Maximum (255)
+    0x15, 0x00,       //   Logical Minimum (0)
+    0x26, 0xFF, 0x00, //   Logical Maximum (255)
+    0x75, 0x08,       //   Report Size (8)
+    0x95, 0x06,       //   Report Count (6)
+    0x81, 0x00,       //   Input (Data, Array, Absolute): keys
+    0xC0,             // End Collection
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ReportId {
+    Keyboard = 1,
+    Consumer = 2,
+    System = 3,
+    Mouse = 4,
+}
+
+/// An input report, ready to be sent to the host.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Report {
+    Keyboard(KeyboardReport),
+    /// A consumer page usage (e.g. 0xE9 for volume up), or 0 for none.
+    Consumer(u16),
+    /// A generic desktop usage (e.g. 0x82 for sleep), or 0 for none.
+    System(u8),
+    Mouse(MouseReport),
+}
+
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub struct KeyboardReport {
+    /// Left Ctrl, Shift, Alt and GUI, then the same on the right, from bit 0.
+    pub modifiers: u8,
+    /// One bit per usage below [`NKRO_KEYS`], least significant bit first.
+    pub keys: [u8; NKRO_KEYS / 8],
+}
+
+impl KeyboardReport {
+    /// Marks `usage` as pressed, if it's in range.
+    pub fn press(&mut self, usage: u8) {
+        if (usage as usize) < NKRO_KEYS {
+            self.keys[usage as usize / 8] |= 1 << (usage % 8);
+        }
+    }
+
+    /// The pressed usages, in ascending order.
+    pub fn pressed(&self) -> impl Iterator<Item = u8> + '_ {
+        (0..NKRO_KEYS as u8).filter(|usage| self.keys[*usage as usize / 8] & 1 << (usage % 8) != 0)
+    }
+}
+
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub struct MouseReport {
+    /// Buttons 1 to 5 (left, right, middle, back, forward) in bits 0 to 4.
+    pub buttons: u8,
+    pub x: i8,
+    pub y: i8,
+    /// Vertical scrolling; positive is up.
+    pub wheel: i8,
+    /// Horizontal scrolling; positive is right.
+    pub pan: i8,
+}
+
+impl Report {
+    pub fn id(&self) -> ReportId {
+        match self {
+            Self::Keyboard(_) => ReportId::Keyboard,
+            Self::Consumer(_) => ReportId::Consumer,
+            Self::System(_) => ReportId::System,
+            Self::Mouse(_) => ReportId::Mouse,
+        }
+    }
+
+    /// Writes the report, ID first, into `buf`, returning the part that was used.
+    pub fn encode<'a>(&self, buf: &'a mut [u8; MAX_REPORT_LEN]) -> &'a [u8] {
+        buf[0] = self.id() as u8;
+
+        let length = match *self {
+            Self::Keyboard(report) => {
+                buf[1] = report.modifiers;
+                buf[2..].copy_from_slice(&report.keys);
+                1 + report.keys.len()
+            }
+            Self::Consumer(usage) => {
+                buf[1..3].copy_from_slice(&usage.to_le_bytes());
+                2
+            }
+            Self::System(usage) => {
+                buf[1] = usage;
+                1
+            }
+            Self::Mouse(mouse) => {
+                // -128 is outside the logical range, so it's clamped.
+                let axis = |value: i8| value.max(-127) as u8;
+
+                buf[1] = mouse.buttons & 0x1F;
+                buf[2] = axis(mouse.x);
+                buf[3] = axis(mouse.y);
+                buf[4] = axis(mouse.wheel);
+                buf[5] = axis(mouse.pan);
+                5
+            }
+        };
+
+        &buf[..length + 1]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    /// What a (very small) HID descriptor parser makes of [`REPORT_DESCRIPTOR`].
+    #[derive(Default)]
+    struct Parsed {
+        /// Input report sizes in bits, by report ID.
+        inputs: HashMap<u8, u32>,
+        /// Output report sizes in bits, by report ID.
+        outputs: HashMap<u8, u32>,
+        /// `(report ID, usage page)` for every top-level collection.
+        applications: Vec<(u8, u16)>,
+    }
+
+    fn parse(descriptor: &[u8]) -> Parsed {
+        let mut parsed = Parsed::default();
+        let (mut page, mut size, mut count, mut id) = (0, 0, 0, 0);
+        let mut depth = 0;
+        let mut application_page = None;
+        let mut i = 0;
+
+        while i < descriptor.len() {
+            let prefix = descriptor[i];
+            let length = match prefix & 0x03 {
+                3 => 4,
+                n => n as usize,
+            };
+
+            assert_ne!(prefix, 0xFE, "long items aren't used");
+            assert!(i + length < descriptor.len(), "item at {i} is cut short");
+
+            let data = descriptor[i + 1..i + 1 + length]
+                .iter()
+                .rev()
+                .fold(0_u32, |acc, byte| acc << 8 | *byte as u32);
+
+            match prefix & 0xFC {
+                0x04 => page = data as u16,
+                0x74 => size = data,
+                0x94 => count = data,
+                0x84 => {
+                    id = data as u8;
+
+                    if let Some(page) = application_page.take() {
+                        parsed.applications.push((id, page));
+                    }
+                }
+                0xA0 => {
+                    if depth == 0 {
+                        application_page = Some(page);
+                    }
+
+                    depth += 1;
+                }
+                0xC0 => depth -= 1,
+                0x80 => *parsed.inputs.entry(id).or_default() += size * count,
+                0x90 => *parsed.outputs.entry(id).or_default() += size * count,
+                _ => (),
+            }
+
+            assert!(depth >= 0, "unbalanced End Collection at {i}");
+            i += 1 + length;
+        }
+
+        assert_eq!(depth, 0, "unclosed collection");
+        parsed
+    }
+
+    #[test]
+    fn descriptor_structure() {
+        let parsed = parse(REPORT_DESCRIPTOR);
+
+        // Every application collection sets its own report ID.
+        assert_eq!(parsed.applications, [(1, 0x01), (2, 0x0C), (3, 0x01), (4, 0x01)]);
+
+        // Only the keyboard has an output report: five LEDs, padded to a byte.
+        assert_eq!(parsed.outputs, HashMap::from([(1, 8)]));
+    }
+
+    #[test]
+    fn boot_descriptor_structure() {
+        let parsed = parse(BOOT_KEYBOARD_DESCRIPTOR);
+
+        // No report IDs, an 8 byte input report and the same LEDs as usual.
+        assert_eq!(parsed.applications, []);
+        assert_eq!(parsed.inputs, HashMap::from([(0, 64)]));
+        assert_eq!(parsed.outputs, HashMap::from([(0, 8)]));
+    }
+
+    #[test]
+    fn reports_match_descriptor() {
+        let inputs = parse(REPORT_DESCRIPTOR).inputs;
+        let reports = [
+            Report::Keyboard(KeyboardReport::default()),
+            Report::Consumer(0xE9),
+            Report::System(0x82),
+            Report::Mouse(MouseReport::default()),
+        ];
+
+        for report in reports {
+            let mut buf = [0; MAX_REPORT_LEN];
+            let encoded = report.encode(&mut buf);
+            let bits = inputs[&(report.id() as u8)];
+
+            assert_eq!(bits % 8, 0, "{report:?} isn't a whole number of bytes");
+            assert_eq!(encoded.len(), 1 + bits as usize / 8, "{report:?}");
+        }
+    }
+
+    #[test]
+    fn encodes_reports() {
+        let mut buf = [0; MAX_REPORT_LEN];
+
+        let mut keyboard = KeyboardReport {
+            modifiers: 0x02,
+            ..Default::default()
+        };
+        keyboard.press(0x04);
+        keyboard.press(0x0F);
+        keyboard.press(0xDF);
+        keyboard.press(0xE0);
+
+        let encoded = Report::Keyboard(keyboard).encode(&mut buf);
+        assert_eq!(encoded[..4], [1, 0x02, 0x10, 0x80]);
+        assert!(encoded[4..29].iter().all(|byte| *byte == 0));
+        assert_eq!(encoded[29], 0x80);
+        assert_eq!(keyboard.pressed().collect::<Vec<_>>(), [0x04, 0x0F, 0xDF]);
+
+        assert_eq!(Report::Consumer(0x0223).encode(&mut buf), [2, 0x23, 0x02]);
+        assert_eq!(Report::System(0x82).encode(&mut buf), [3, 0x82]);
+
+        let report = Report::Mouse(MouseReport {
+            buttons: 0b1000_0001,
+            x: -128,
+            y: 5,
+            wheel: -1,
+            pan: 0,
+        });
+        assert_eq!(report.encode(&mut buf), [4, 0x01, 0x81, 0x05, 0xFF, 0x00]);
+    }
+}
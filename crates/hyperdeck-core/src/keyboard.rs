@@ -0,0 +1,374 @@
+//! The keyboard state as the host sees it.
+//!
+//! Every key on the keypad, plus the [`MacroPlayer`](crate::macros::MacroPlayer),
+//! holds down its own chord. [`Keyboard`] merges them, so releasing one key only
+//! lifts what that key was holding: hold two keys bound to `ctrl+a` and `ctrl+b`,
+//! let go of the first, and the host still sees Ctrl and B.
+//!
+//! Hosts normally get an N-key rollover report with a bit for every key. Ones that
+//! ask for the boot protocol (BIOSes, mostly) get the fixed six-key boot report
+//! instead, with every key slot set to ErrorRollOver if more than six are down.
+//!
+//! Every change is queued until the host accepts it, so a key tapped while the
+//! report queue is full still reaches the host as a press and then a release.
+//! Callers should stop feeding in changes while [`Keyboard::is_full`]; any that
+//! arrive anyway fold into the newest queued one, and are counted in
+//! [`Keyboard::dropped`].
+
+use heapless::Deque;
+
+use crate::hid::{KeyboardReport, NKRO_KEYS};
+use crate::keypad::NUM_KEYS;
+
+/// The usage hosts get in every key slot when too many keys are down.
+const ERROR_ROLL_OVER: u8 = 0x01;
+
+/// Something holding down keys.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Source {
+    /// A key on the keypad, by ID.
+    Key(u8),
+    /// The macro player.
+    Player,
+}
+
+/// The report format the host asked for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Protocol {
+    Boot,
+    Report,
+}
+
+/// A keyboard report in the current [`Protocol`]'s format.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Output {
+    /// Modifiers, reserved byte, then six keys.
+    Boot([u8; 8]),
+    Report(KeyboardReport),
+}
+
+pub struct Keyboard {
+    /// The chord each source is holding, laid out like a boot report: modifier
+    /// bits, reserved byte, then up to six keys. The last one is the player's.
+    chords: [[u8; 8]; NUM_KEYS + 1],
+    protocol: Protocol,
+    /// Merged states the host hasn't accepted yet, oldest first.
+    pending: Deque<KeyboardReport, 16>,
+    /// The newest merged state.
+    state: KeyboardReport,
+    /// The last report the host accepted.
+    sent: Option<Output>,
+    /// Changes folded away because `pending` was full.
+    dropped: u32,
+}
+
+impl Keyboard {
+    pub const fn new() -> Self {
+        Self {
+            chords: [[0; 8]; NUM_KEYS + 1],
+            protocol: Protocol::Report,
+            pending: Deque::new(),
+            state: KeyboardReport {
+                modifiers: 0,
+                keys: [0; NKRO_KEYS / 8],
+            },
+            sent: None,
+            dropped: 0,
+        }
+    }
+
+    /// Sets the chord `source` is holding down; all zeros releases it.
+    pub fn set(&mut self, source: Source, chord: [u8; 8]) {
+        let index = match source {
+            Source::Key(id) => id as usize,
+            Source::Player => NUM_KEYS,
+        };
+
+        if let Some(slot) = self.chords.get_mut(index) {
+            *slot = chord;
+        }
+
+        let state = self.merge();
+
+        if state == self.state {
+            return;
+        }
+
+        self.state = state;
+
+        // Once full, changes fold into the newest one, so the host still ends up
+        // with the right state even if it misses a tap along the way.
+        if let Err(state) = self.pending.push_back(state) {
+            if let Some(back) = self.pending.back_mut() {
+                *back = state;
+            }
+
+            self.dropped = self.dropped.wrapping_add(1);
+        }
+    }
+
+    /// Whether another change would have to be folded into the last one.
+    pub fn is_full(&self) -> bool {
+        self.pending.is_full()
+    }
+
+    /// How many changes have been folded away since the device started.
+    pub fn dropped(&self) -> u32 {
+        self.dropped
+    }
+
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    /// Switches report format. The current state is sent again in the new one.
+    pub fn set_protocol(&mut self, protocol: Protocol) {
+        self.protocol = protocol;
+    }
+
+    /// Sends every queued change in order, then the current state if the format
+    /// changed since, returning whether the host is up to date. Rejected reports
+    /// are retried on the next call.
+    pub fn poll(&mut self, mut send: impl FnMut(Output) -> bool) -> bool {
+        while let Some(&state) = self.pending.front() {
+            if !self.send(state, &mut send) {
+                return false;
+            }
+
+            self.pending.pop_front();
+        }
+
+        self.send(self.state, &mut send)
+    }
+
+    /// Sends `state` in the current format, unless it's what the host last got.
+    fn send(&mut self, state: KeyboardReport, send: impl FnOnce(Output) -> bool) -> bool {
+        let output = self.format(state);
+
+        if self.sent == Some(output) {
+            return true;
+        }
+
+        if !send(output) {
+            return false;
+        }
+
+        self.sent = Some(output);
+        true
+    }
+
+    /// The merged state of every source.
+    fn merge(&self) -> KeyboardReport {
+        let mut report = KeyboardReport::default();
+
+        for chord in &self.chords {
+            report.modifiers |= chord[0];
+
+            for &usage in &chord[2..] {
+                match usage {
+                    // Nothing, or one of the error codes.
+                    0x00..=0x03 => (),
+                    0xE0..=0xE7 => report.modifiers |= 1 << (usage - 0xE0),
+                    usage => report.press(usage),
+                }
+            }
+        }
+
+        report
+    }
+
+    /// `report` in the current format.
+    fn format(&self, report: KeyboardReport) -> Output {
+        if self.protocol == Protocol::Report {
+            return Output::Report(report);
+        }
+
+        let mut boot = [report.modifiers, 0, 0, 0, 0, 0, 0, 0];
+
+        for (i, usage) in report.pressed().enumerate() {
+            match boot.get_mut(2 + i) {
+                Some(slot) => *slot = usage,
+                None => {
+                    boot[2..].fill(ERROR_ROLL_OVER);
+                    break;
+                }
+            }
+        }
+
+        Output::Boot(boot)
+    }
+}
+
+impl Default for Keyboard {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use super::*;
+
+    const CTRL: u8 = 0x01;
+    const SHIFT: u8 = 0x02;
+    const A: u8 = 0x04;
+    const B: u8 = 0x05;
+
+    fn chord(modifiers: u8, keys: &[u8]) -> [u8; 8] {
+        let mut chord = [modifiers, 0, 0, 0, 0, 0, 0, 0];
+        chord[2..2 + keys.len()].copy_from_slice(keys);
+        chord
+    }
+
+    fn nkro(modifiers: u8, keys: &[u8]) -> Output {
+        let mut report = KeyboardReport {
+            modifiers,
+            ..Default::default()
+        };
+
+        keys.iter().for_each(|key| report.press(*key));
+        Output::Report(report)
+    }
+
+    /// Polls once, returning whatever was sent.
+    fn poll(keyboard: &mut Keyboard) -> Option<Output> {
+        let mut sent = None;
+
+        keyboard.poll(|output| {
+            sent = Some(output);
+            true
+        });
+
+        sent
+    }
+
+    #[test]
+    fn releasing_one_key_keeps_the_others() {
+        let mut keyboard = Keyboard::new();
+
+        keyboard.set(Source::Key(0), chord(CTRL, &[A]));
+        assert_eq!(poll(&mut keyboard), Some(nkro(CTRL, &[A])));
+
+        keyboard.set(Source::Key(1), chord(CTRL | SHIFT, &[B]));
+        assert_eq!(poll(&mut keyboard), Some(nkro(CTRL | SHIFT, &[A, B])));
+
+        keyboard.set(Source::Key(0), [0; 8]);
+        assert_eq!(poll(&mut keyboard), Some(nkro(CTRL | SHIFT, &[B])));
+
+        keyboard.set(Source::Key(1), [0; 8]);
+        assert_eq!(poll(&mut keyboard), Some(nkro(0, &[])));
+    }
+
+    #[test]
+    fn only_sends_changes() {
+        let mut keyboard = Keyboard::new();
+
+        keyboard.set(Source::Key(0), chord(CTRL, &[A]));
+        keyboard.set(Source::Player, chord(CTRL, &[]));
+        assert!(poll(&mut keyboard).is_some());
+
+        // Ctrl is still held by the player, so the host needn't hear about it.
+        keyboard.set(Source::Key(0), chord(0, &[A]));
+        assert_eq!(poll(&mut keyboard), None);
+    }
+
+    #[test]
+    fn modifier_usages_become_bits() {
+        let mut keyboard = Keyboard::new();
+
+        keyboard.set(Source::Player, chord(0, &[0xE1, A]));
+        assert_eq!(poll(&mut keyboard), Some(nkro(SHIFT, &[A])));
+    }
+
+    #[test]
+    fn boot_protocol() {
+        let mut keyboard = Keyboard::new();
+
+        keyboard.set(Source::Key(0), chord(CTRL, &[B, A]));
+        poll(&mut keyboard);
+
+        // Switching resends the same state in the new format.
+        keyboard.set_protocol(Protocol::Boot);
+        assert_eq!(
+            poll(&mut keyboard),
+            Some(Output::Boot([CTRL, 0, A, B, 0, 0, 0, 0]))
+        );
+    }
+
+    #[test]
+    fn boot_protocol_rolls_over() {
+        let mut keyboard = Keyboard::new();
+        let keys: Vec<u8> = (0x04..0x0B).collect();
+
+        keyboard.set_protocol(Protocol::Boot);
+        keyboard.set(Source::Key(0), chord(0, &keys[..6]));
+        keyboard.set(Source::Key(1), chord(SHIFT, &keys[6..]));
+
+        assert_eq!(
+            poll(&mut keyboard),
+            Some(Output::Boot([SHIFT, 0, 1, 1, 1, 1, 1, 1]))
+        );
+
+        // NKRO has room for all of them.
+        keyboard.set_protocol(Protocol::Report);
+        assert_eq!(poll(&mut keyboard), Some(nkro(SHIFT, &keys)));
+    }
+
+    #[test]
+    fn retries_rejected_reports() {
+        let mut keyboard = Keyboard::new();
+
+        keyboard.set(Source::Key(3), chord(0, &[A]));
+        assert!(!keyboard.poll(|_| false));
+        assert_eq!(poll(&mut keyboard), Some(nkro(0, &[A])));
+        assert!(keyboard.poll(|_| unreachable!()));
+    }
+
+    #[test]
+    fn rejected_taps_are_not_lost() {
+        let mut keyboard = Keyboard::new();
+        poll(&mut keyboard);
+
+        keyboard.set(Source::Key(0), chord(0, &[A]));
+        assert!(!keyboard.poll(|_| false));
+        keyboard.set(Source::Key(0), [0; 8]);
+
+        let mut sent = Vec::new();
+        assert!(keyboard.poll(|output| {
+            sent.push(output);
+            true
+        }));
+        assert_eq!(sent, [nkro(0, &[A]), nkro(0, &[])]);
+    }
+
+    #[test]
+    fn counts_folded_changes() {
+        let mut keyboard = Keyboard::new();
+        poll(&mut keyboard);
+
+        // Nothing is polled, so every change stays queued.
+        for i in 0..8 {
+            keyboard.set(Source::Key(i), chord(0, &[A + i]));
+            keyboard.set(Source::Key(i), [0; 8]);
+        }
+
+        assert!(keyboard.is_full());
+        assert_eq!(keyboard.dropped(), 0);
+
+        keyboard.set(Source::Key(0), chord(0, &[A]));
+        assert_eq!(keyboard.dropped(), 1);
+
+        let mut sent = Vec::new();
+        assert!(keyboard.poll(|output| {
+            sent.push(output);
+            true
+        }));
+
+        // The last release was replaced by the press that didn't fit.
+        assert_eq!(sent.len(), 16);
+        assert_eq!(sent.last(), Some(&nkro(0, &[A])));
+        assert!(!keyboard.is_full());
+    }
+}
@@ -0,0 +1,552 @@
+//! The keypad: sixteen keys read through a TCA9555 I/O expander on I2C, each lit
+//! by an APA102 LED on SPI, as on the Pimoroni Pico RGB Keypad.
+//!
+//! [`Keypad`] only needs the `embedded-hal` traits, so it runs just as well
+//! against the test doubles in `mock`.
+
+use embedded_hal::blocking::{i2c, spi};
+use embedded_hal::digital::v2::OutputPin;
+
+use crate::config::{Repeat, LAYER_KEYS};
+use crate::debounce::{Debounce, Debouncer};
+use crate::time::{Clock, Duration, Instant};
+
+/// Number of physical keys on the keypad.
+pub const NUM_KEYS: usize = 16;
+
+/// The physical keys that aren't part of any layer.
+pub const RESERVED_KEYS: [u8; NUM_KEYS - LAYER_KEYS] = [14, 15];
+
+/// How long a key has to be down before it counts as [held](KeyEvent::Held).
+pub const HOLD_TIME: Duration = Duration::millis(750);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyEvent {
+    Pressed,
+    /// The key has been down for [`HOLD_TIME`]. Sent once per press.
+    Held,
+    Released,
+    /// The key is still down. Sent over and over at the [`Repeat`] rate, once it's
+    /// been down for the repeat delay.
+    Repeat,
+}
+
+/// The I2C address of the TCA9555.
+pub const EXPANDER_ADDR: u8 = 0x20;
+
+/// APA102 frames are 32 zero bits to start...
+const START_FRAME: [u8; 4] = [0x00, 0x00, 0x00, 0x00];
+/// ...and 32 one bits to end. That's not quite enough for long chains (see
+/// <https://cpldcpu.wordpress.com/2014/11/30/understanding-the-apa102-superled/>),
+/// but plenty for sixteen LEDs.
+const END_FRAME: [u8; 4] = [0xFF, 0xFF, 0xFF, 0xFF];
+
+/// Something went wrong talking to the keypad.
+#[derive(Debug)]
+pub enum Error<I, S, P> {
+    I2c(I),
+    Spi(S),
+    Pin(P),
+}
+
+pub struct Keypad<I, S, P> {
+    pub keys: [Key; NUM_KEYS],
+    /// The APA102 brightness byte; see [`Keypad::set_brightness`].
+    brightness: u8,
+    debounce: Debounce,
+    debouncer: Debouncer,
+    repeat: Repeat,
+    /// When a key was last down.
+    active_at: Instant,
+    i2c: I,
+    spi: S,
+    cs: P,
+}
+
+impl<I, S, P> Keypad<I, S, P> {
+    pub fn new(i2c: I, spi: S, cs: P) -> Self {
+        Self {
+            keys: core::array::from_fn(|_| Key::new()),
+            brightness: 0,
+            debounce: Debounce::default(),
+            debouncer: Debouncer::new(),
+            repeat: Repeat::default(),
+            active_at: Instant::from_ticks(0),
+            i2c,
+            spi,
+            cs,
+        }
+    }
+
+    /// Sets the default and pressed colors of every key.
+    pub fn set_colors(&mut self, colors: [(Color, Color); NUM_KEYS]) {
+        for (key, (default, pressed)) in self.keys.iter_mut().zip(colors) {
+            key.default_color = default;
+            key.pressed_color = pressed;
+        }
+    }
+
+    /// Sets the brightness of the keypad LEDs.
+    ///
+    /// Values lower than 0.0 or higher than 1.0 will be clamped to within that range.
+    pub fn set_brightness(&mut self, brightness: f32) {
+        let brightness = brightness.clamp(0.0, 1.0);
+        // Map a percentage value (between 0.0 and 1.0) to a u8 between 224 and 255
+        // (the brightness range accepted by the keypad LED protocol)
+        self.brightness = 0b11100000 | (brightness * 0b11111 as f32) as u8;
+    }
+
+    /// Sets how the raw key states are debounced.
+    pub fn set_debounce(&mut self, debounce: Debounce) {
+        self.debounce = debounce;
+    }
+
+    /// Sets how often held keys send [`KeyEvent::Repeat`]s.
+    pub fn set_repeat(&mut self, repeat: Repeat) {
+        self.repeat = repeat;
+    }
+
+    /// How long it's been since a key was down, as of the last update.
+    pub fn idle_time(&self, clock: &impl Clock) -> Duration {
+        clock.now() - self.active_at
+    }
+}
+
+impl<I, S, P, E> Keypad<I, S, P>
+where
+    I: i2c::Write<Error = E> + i2c::Read<Error = E>,
+    S: spi::Write<u8>,
+    P: OutputPin,
+{
+    /// Refreshes the LEDs and scans the keys, returning what happened to them
+    /// since the last scan.
+    #[allow(clippy::type_complexity)]
+    pub fn update(
+        &mut self,
+        clock: &impl Clock,
+    ) -> Result<impl Iterator<Item = (u8, KeyEvent)>, Error<E, S::Error, P::Error>> {
+        // Yes, this is *technically* out of order, but updates happen
+        // so fast that it doesn't really matter.
+        self.update_leds()?;
+        self.update_state(clock.now()).map_err(Error::I2c)
+    }
+
+    fn update_leds(&mut self) -> Result<(), Error<E, S::Error, P::Error>> {
+        self.cs.set_low().map_err(Error::Pin)?;
+
+        let mut write = |bytes: &[u8]| self.spi.write(bytes).map_err(Error::Spi);
+
+        write(&START_FRAME)?;
+
+        // One 32-bit frame per LED: brightness, then blue, green and red
+        for key in &self.keys {
+            write(&[self.brightness])?;
+            write(&key.color().as_bgr())?;
+        }
+
+        write(&END_FRAME)?;
+
+        self.cs.set_high().map_err(Error::Pin)
+    }
+
+    fn update_state(&mut self, now: Instant) -> Result<impl Iterator<Item = (u8, KeyEvent)>, E> {
+        let mut buffer = [0_u8; 2];
+
+        // Point the expander at its first input port register, then read both
+        // ports
+        self.i2c.write(EXPANDER_ADDR, &[0x0])?;
+        self.i2c.read(EXPANDER_ADDR, &mut buffer)?;
+
+        // The inputs are pulled up, so pressed keys read as zeros
+        let raw = !u16::from_le_bytes(buffer);
+
+        // Contacts bounce, so the raw state can't be trusted straight away
+        let state = self.debouncer.update(self.debounce, raw, now);
+
+        if state != 0 {
+            self.active_at = now;
+        }
+
+        let mut events = [None; NUM_KEYS];
+
+        for (i, key) in self.keys.iter_mut().enumerate() {
+            let pressed = state & 1 << i != 0;
+            events[i] = key.state.update(pressed, self.repeat, now);
+        }
+
+        Ok(events
+            .into_iter()
+            .enumerate()
+            .filter_map(|(i, event)| event.map(|e| (i as u8, e))))
+    }
+}
+
+pub struct Key {
+    pub default_color: Color,
+    pub pressed_color: Color,
+    pub state: KeyState,
+}
+
+impl Key {
+    pub fn new() -> Self {
+        Self {
+            default_color: Color::new(16, 16, 16),
+            pressed_color: Color::new(0, 255, 0),
+            state: KeyState::new(),
+        }
+    }
+
+    pub fn color(&self) -> Color {
+        match self.state.is_pressed() {
+            true => self.pressed_color,
+            false => self.default_color,
+        }
+    }
+}
+
+impl Default for Key {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub struct Color {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Color {
+    pub const fn new(r: u8, g: u8, b: u8) -> Self {
+        Self { r, g, b }
+    }
+
+    /// Splits a `[r, g, b, r, g, b]` color pair, as stored in a
+    /// [`KeyConfig`](crate::config::KeyConfig), into default and pressed colors.
+    pub fn pair(colors: [u8; 6]) -> (Self, Self) {
+        (
+            Self::new(colors[0], colors[1], colors[2]),
+            Self::new(colors[3], colors[4], colors[5]),
+        )
+    }
+
+    pub fn as_bgr(&self) -> [u8; 3] {
+        [self.b, self.g, self.r]
+    }
+}
+
+/// Turns a key's debounced state, sampled over and over, into [`KeyEvent`]s.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct KeyState {
+    /// When the key went down, if it's down.
+    pressed_at: Option<Instant>,
+    held: bool,
+    /// When the next [`KeyEvent::Repeat`] is due.
+    next_repeat: Option<Instant>,
+}
+
+impl KeyState {
+    pub const fn new() -> Self {
+        Self {
+            pressed_at: None,
+            held: false,
+            next_repeat: None,
+        }
+    }
+
+    pub fn is_pressed(&self) -> bool {
+        self.pressed_at.is_some()
+    }
+
+    /// Takes whether the key is down at `now`, returning what that amounts to, if
+    /// anything. Each call returns at most one event, so anything else that's due
+    /// comes out of the next one.
+    pub fn update(&mut self, pressed: bool, repeat: Repeat, now: Instant) -> Option<KeyEvent> {
+        let Some(pressed_at) = self.pressed_at else {
+            if pressed {
+                self.pressed_at = Some(now);
+                self.next_repeat = repeat.period().map(|_| now + repeat.delay());
+            }
+
+            return pressed.then_some(KeyEvent::Pressed);
+        };
+
+        if !pressed {
+            *self = Self::new();
+            return Some(KeyEvent::Released);
+        }
+
+        if !self.held && now - pressed_at >= HOLD_TIME {
+            self.held = true;
+            return Some(KeyEvent::Held);
+        }
+
+        match (self.next_repeat, repeat.period()) {
+            (Some(due), Some(period)) if now >= due => {
+                // Don't try to catch up on repeats missed by a slow caller.
+                self.next_repeat = Some((due + period).max(now));
+                Some(KeyEvent::Repeat)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec;
+    use std::vec::Vec;
+
+    use super::*;
+    use crate::debounce::Algorithm;
+    use crate::mock::{Apa102, Apa102Cs, Apa102Spi, Tca9555};
+    use crate::time::FakeClock;
+
+    /// Samples a key that's down from 0 to `release` milliseconds, once per
+    /// millisecond, returning when each event came out.
+    fn sample(repeat: Repeat, release: u64) -> Vec<(u64, KeyEvent)> {
+        let mut key = KeyState::new();
+
+        (0..=release)
+            .filter_map(|ms| {
+                let now = Instant::from_ticks(ms * 1000);
+                key.update(ms < release, repeat, now)
+                    .map(|event| (ms, event))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn held_fires_once() {
+        let repeat = Repeat {
+            delay: 500,
+            rate: 0,
+        };
+
+        assert_eq!(
+            sample(repeat, 2000),
+            [
+                (0, KeyEvent::Pressed),
+                (750, KeyEvent::Held),
+                (2000, KeyEvent::Released),
+            ]
+        );
+    }
+
+    #[test]
+    fn repeats_after_the_delay() {
+        let repeat = Repeat {
+            delay: 600,
+            rate: 10,
+        };
+
+        assert_eq!(
+            sample(repeat, 920),
+            [
+                (0, KeyEvent::Pressed),
+                (600, KeyEvent::Repeat),
+                (700, KeyEvent::Repeat),
+                (750, KeyEvent::Held),
+                (800, KeyEvent::Repeat),
+                (900, KeyEvent::Repeat),
+                (920, KeyEvent::Released),
+            ]
+        );
+
+        // A repeat due at the same time as the hold comes out right after it.
+        let repeat = Repeat {
+            delay: 750,
+            rate: 10,
+        };
+        assert_eq!(
+            sample(repeat, 760)[1..3],
+            [(750, KeyEvent::Held), (751, KeyEvent::Repeat)]
+        );
+    }
+
+    fn keypad() -> (Keypad<Tca9555, Apa102Spi, Apa102Cs>, Tca9555, Apa102Spi) {
+        let expander = Tca9555::new();
+        let (spi, cs) = Apa102::chain();
+        let mut keypad = Keypad::new(expander.clone(), spi.clone(), cs);
+
+        keypad.set_debounce(Debounce {
+            algorithm: Algorithm::Symmetric,
+            time: 0,
+        });
+        keypad.set_repeat(Repeat {
+            delay: 500,
+            rate: 0,
+        });
+
+        (keypad, expander, spi)
+    }
+
+    fn scan(
+        keypad: &mut Keypad<Tca9555, Apa102Spi, Apa102Cs>,
+        clock: &FakeClock,
+    ) -> Vec<(u8, KeyEvent)> {
+        keypad.update(clock).unwrap().collect()
+    }
+
+    #[test]
+    fn scans_the_expander() {
+        let (mut keypad, expander, _) = keypad();
+        let clock = FakeClock::new();
+
+        assert_eq!(scan(&mut keypad, &clock), []);
+
+        // One key on each port.
+        expander.set_pressed(1 << 3 | 1 << 12);
+        clock.advance_ms(10);
+        assert_eq!(
+            scan(&mut keypad, &clock),
+            [(3, KeyEvent::Pressed), (12, KeyEvent::Pressed)]
+        );
+        clock.advance_ms(10);
+        assert_eq!(scan(&mut keypad, &clock), []);
+
+        expander.set_pressed(1 << 12);
+        clock.advance_ms(740);
+        assert_eq!(
+            scan(&mut keypad, &clock),
+            [(3, KeyEvent::Released), (12, KeyEvent::Held)]
+        );
+        clock.advance_ms(1000);
+        assert_eq!(scan(&mut keypad, &clock), []);
+
+        expander.set_pressed(0);
+        clock.advance_ms(10);
+        assert_eq!(scan(&mut keypad, &clock), [(12, KeyEvent::Released)]);
+    }
+
+    #[test]
+    fn scans_are_debounced() {
+        let (mut keypad, expander, _) = keypad();
+        let clock = FakeClock::new();
+
+        keypad.set_debounce(Debounce {
+            algorithm: Algorithm::DeferredPerKey,
+            time: 5,
+        });
+
+        // A one-sample glitch never gets through...
+        clock.advance_ms(100);
+        expander.set_pressed(1);
+        assert_eq!(scan(&mut keypad, &clock), []);
+        clock.advance_ms(1);
+        expander.set_pressed(0);
+        assert_eq!(scan(&mut keypad, &clock), []);
+        clock.advance_ms(9);
+        assert_eq!(scan(&mut keypad, &clock), []);
+
+        // ...but a press that holds still does, once it's settled.
+        clock.advance_ms(90);
+        expander.set_pressed(1);
+        assert_eq!(scan(&mut keypad, &clock), []);
+        clock.advance_ms(5);
+        assert_eq!(scan(&mut keypad, &clock), [(0, KeyEvent::Pressed)]);
+    }
+
+    #[test]
+    fn repeats_while_held() {
+        let (mut keypad, expander, _) = keypad();
+        let clock = FakeClock::new();
+
+        keypad.set_repeat(Repeat {
+            delay: 300,
+            rate: 5,
+        });
+
+        expander.set_pressed(1 << 7);
+
+        // Scan every 10ms for a second.
+        let events: Vec<_> = (0..100)
+            .flat_map(|_| {
+                let events = scan(&mut keypad, &clock);
+                let at = clock.now().duration_since_epoch().to_millis();
+                clock.advance_ms(10);
+                events.into_iter().map(move |(_, event)| (at, event))
+            })
+            .collect();
+
+        assert_eq!(
+            events,
+            [
+                (0, KeyEvent::Pressed),
+                (300, KeyEvent::Repeat),
+                (500, KeyEvent::Repeat),
+                (700, KeyEvent::Repeat),
+                (750, KeyEvent::Held),
+                (900, KeyEvent::Repeat),
+            ]
+        );
+    }
+
+    #[test]
+    fn idle_time() {
+        let (mut keypad, expander, _) = keypad();
+        let clock = FakeClock::new();
+
+        clock.advance_ms(50);
+        scan(&mut keypad, &clock);
+        assert_eq!(keypad.idle_time(&clock), Duration::millis(50));
+
+        // Holding a key down keeps the keypad active...
+        expander.set_pressed(1);
+        clock.advance_ms(1000);
+        scan(&mut keypad, &clock);
+        clock.advance_ms(20);
+        assert_eq!(keypad.idle_time(&clock), Duration::millis(20));
+
+        // ...until it's released.
+        expander.set_pressed(0);
+        scan(&mut keypad, &clock);
+        clock.wait(Duration::secs(60));
+        scan(&mut keypad, &clock);
+        assert_eq!(keypad.idle_time(&clock), Duration::millis(60_020));
+    }
+
+    #[test]
+    fn frames_the_leds() {
+        let (mut keypad, expander, spi) = keypad();
+        let clock = FakeClock::new();
+
+        let mut colors = [(Color::new(1, 2, 3), Color::new(4, 5, 6)); NUM_KEYS];
+        colors[15] = Color::pair([0x10, 0x20, 0x30, 0x40, 0x50, 0x60]);
+        keypad.set_colors(colors);
+        keypad.set_brightness(0.5);
+
+        expander.set_pressed(1 << 15);
+        scan(&mut keypad, &clock);
+        scan(&mut keypad, &clock);
+
+        let frames = spi.frames();
+        assert_eq!(frames.len(), 2);
+
+        let mut expected = vec![0x00; 4];
+        for _ in 0..NUM_KEYS - 1 {
+            expected.extend([0xEF, 3, 2, 1]);
+        }
+        expected.extend([0xEF, 0x30, 0x20, 0x10]);
+        expected.extend([0xFF; 4]);
+
+        // The LEDs are sent before the keys are scanned, so a press only lights
+        // up on the next update.
+        assert_eq!(frames[0], expected);
+
+        let len = expected.len();
+        expected[len - 8..len - 4].copy_from_slice(&[0xEF, 0x60, 0x50, 0x40]);
+        assert_eq!(frames[1], expected);
+
+        // Brightness is clamped, and always keeps the three leading one bits.
+        keypad.set_brightness(2.0);
+        scan(&mut keypad, &clock);
+        keypad.set_brightness(-1.0);
+        scan(&mut keypad, &clock);
+
+        let frames = spi.frames();
+        assert_eq!(frames[2][4], 0xFF);
+        assert_eq!(frames[3][4], 0xE0);
+    }
+}
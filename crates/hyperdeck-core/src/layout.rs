@@ -0,0 +1,237 @@
+//! Translation of text into keystrokes for the host's keyboard layout.
+//!
+//! The keypad sends HID usages, which name physical key positions rather than
+//! characters; the host then interprets them according to its own layout. To type
+//! a character we have to know that layout and pick the key (and modifiers) that
+//! produce it there.
+//!
+//! Letters, digits, space, tab and newline sit in the same place on every layout
+//! supported here (apart from German swapping Y and Z), so only the symbols
+//! need per-layout tables. Characters behind a dead key are typed as the dead key
+//! followed by a space.
+
+use heapless::Vec;
+use serde::{Deserialize, Serialize};
+
+const SHIFT: u8 = 0x02;
+/// Right alt, which acts as AltGr on European layouts.
+const ALTGR: u8 = 0x40;
+
+// Keys that move around between layouts, named after their US legends.
+const SPACE: u8 = 0x2C;
+/// The key left of Enter on ISO keyboards (HID "Non-US # and ~").
+const NON_US_HASH: u8 = 0x32;
+/// The key right of left shift on ISO keyboards (HID "Non-US \ and |").
+const NON_US_BACKSLASH: u8 = 0x64;
+
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Layout {
+    /// US English (ANSI).
+    #[default]
+    Us,
+    /// UK English (ISO).
+    Uk,
+    /// German QWERTZ (ISO), with dead accent keys.
+    De,
+}
+
+/// A key press with the modifiers it needs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Keystroke {
+    /// Modifier bits, as in the first byte of a keyboard report.
+    pub modifiers: u8,
+    /// HID keyboard usage.
+    pub key: u8,
+}
+
+/// The keystrokes for a single character.
+pub type Keystrokes = Vec<Keystroke, 2>;
+
+/// `(character, modifiers, key)`.
+type Table = &'static [(char, u8, u8)];
+
+#[rustfmt::skip]
+const US: Table = &[
+    ('!', SHIFT, 0x1E), ('@', SHIFT, 0x1F), ('#', SHIFT, 0x20), ('$', SHIFT, 0x21),
+    ('%', SHIFT, 0x22), ('^', SHIFT, 0x23), ('&', SHIFT, 0x24), ('*', SHIFT, 0x25),
+    ('(', SHIFT, 0x26), (')', SHIFT, 0x27),
+    ('-', 0, 0x2D), ('_', SHIFT, 0x2D), ('=', 0, 0x2E), ('+', SHIFT, 0x2E),
+    ('[', 0, 0x2F), ('{', SHIFT, 0x2F), (']', 0, 0x30), ('}', SHIFT, 0x30),
+    ('\\', 0, 0x31), ('|', SHIFT, 0x31), (';', 0, 0x33), (':', SHIFT, 0x33),
+    ('\'', 0, 0x34), ('"', SHIFT, 0x34), ('`', 0, 0x35), ('~', SHIFT, 0x35),
+    (',', 0, 0x36), ('<', SHIFT, 0x36), ('.', 0, 0x37), ('>', SHIFT, 0x37),
+    ('/', 0, 0x38), ('?', SHIFT, 0x38),
+];
+
+#[rustfmt::skip]
+const UK: Table = &[
+    ('!', SHIFT, 0x1E), ('"', SHIFT, 0x1F), ('£', SHIFT, 0x20), ('$', SHIFT, 0x21),
+    ('%', SHIFT, 0x22), ('^', SHIFT, 0x23), ('&', SHIFT, 0x24), ('*', SHIFT, 0x25),
+    ('(', SHIFT, 0x26), (')', SHIFT, 0x27), ('€', ALTGR, 0x21),
+    ('-', 0, 0x2D), ('_', SHIFT, 0x2D), ('=', 0, 0x2E), ('+', SHIFT, 0x2E),
+    ('[', 0, 0x2F), ('{', SHIFT, 0x2F), (']', 0, 0x30), ('}', SHIFT, 0x30),
+    ('#', 0, NON_US_HASH), ('~', SHIFT, NON_US_HASH), (';', 0, 0x33), (':', SHIFT, 0x33),
+    ('\'', 0, 0x34), ('@', SHIFT, 0x34), ('`', 0, 0x35), ('¬', SHIFT, 0x35),
+    ('\\', 0, NON_US_BACKSLASH), ('|', SHIFT, NON_US_BACKSLASH),
+    (',', 0, 0x36), ('<', SHIFT, 0x36), ('.', 0, 0x37), ('>', SHIFT, 0x37),
+    ('/', 0, 0x38), ('?', SHIFT, 0x38),
+];
+
+#[rustfmt::skip]
+const DE: Table = &[
+    ('!', SHIFT, 0x1E), ('"', SHIFT, 0x1F), ('²', ALTGR, 0x1F), ('§', SHIFT, 0x20),
+    ('³', ALTGR, 0x20), ('$', SHIFT, 0x21), ('%', SHIFT, 0x22), ('&', SHIFT, 0x23),
+    ('/', SHIFT, 0x24), ('{', ALTGR, 0x24), ('(', SHIFT, 0x25), ('[', ALTGR, 0x25),
+    (')', SHIFT, 0x26), (']', ALTGR, 0x26), ('=', SHIFT, 0x27), ('}', ALTGR, 0x27),
+    ('ß', 0, 0x2D), ('?', SHIFT, 0x2D), ('\\', ALTGR, 0x2D),
+    ('´', 0, 0x2E), ('`', SHIFT, 0x2E),
+    ('ü', 0, 0x2F), ('Ü', SHIFT, 0x2F), ('+', 0, 0x30), ('*', SHIFT, 0x30), ('~', ALTGR, 0x30),
+    ('ö', 0, 0x33), ('Ö', SHIFT, 0x33), ('ä', 0, 0x34), ('Ä', SHIFT, 0x34),
+    ('#', 0, NON_US_HASH), ('\'', SHIFT, NON_US_HASH),
+    ('^', 0, 0x35), ('°', SHIFT, 0x35),
+    ('<', 0, NON_US_BACKSLASH), ('>', SHIFT, NON_US_BACKSLASH), ('|', ALTGR, NON_US_BACKSLASH),
+    (',', 0, 0x36), (';', SHIFT, 0x36), ('.', 0, 0x37), (':', SHIFT, 0x37),
+    ('-', 0, 0x38), ('_', SHIFT, 0x38),
+    ('@', ALTGR, 0x14), ('€', ALTGR, 0x08), ('µ', ALTGR, 0x10),
+];
+
+impl Layout {
+    /// The keystrokes that type `c`, or `None` if the layout can't produce it.
+    pub fn keystrokes(self, c: char) -> Option<Keystrokes> {
+        let mut strokes = Keystrokes::new();
+        let stroke = self.keystroke(c)?;
+
+        let _ = strokes.push(stroke);
+
+        // Dead keys wait for the next key, and a space makes them type themselves.
+        if self.dead_keys().contains(&c) {
+            let _ = strokes.push(Keystroke {
+                modifiers: 0,
+                key: SPACE,
+            });
+        }
+
+        Some(strokes)
+    }
+
+    fn keystroke(self, c: char) -> Option<Keystroke> {
+        let stroke = |modifiers, key| Some(Keystroke { modifiers, key });
+
+        let letter = |c: char| {
+            let c = match (self, c) {
+                (Self::De, 'y') => 'z',
+                (Self::De, 'z') => 'y',
+                _ => c,
+            };
+
+            0x04 + (c as u8 - b'a')
+        };
+
+        match c {
+            'a'..='z' => stroke(0, letter(c)),
+            'A'..='Z' => stroke(SHIFT, letter(c.to_ascii_lowercase())),
+            '1'..='9' => stroke(0, 0x1E + (c as u8 - b'1')),
+            '0' => stroke(0, 0x27),
+            '\n' => stroke(0, 0x28),
+            '\t' => stroke(0, 0x2B),
+            ' ' => stroke(0, SPACE),
+            _ => self
+                .table()
+                .iter()
+                .find(|(ch, _, _)| *ch == c)
+                .and_then(|(_, modifiers, key)| stroke(*modifiers, *key)),
+        }
+    }
+
+    fn table(self) -> Table {
+        match self {
+            Self::Us => US,
+            Self::Uk => UK,
+            Self::De => DE,
+        }
+    }
+
+    fn dead_keys(self) -> &'static [char] {
+        match self {
+            Self::De => &['^', '´', '`'],
+            _ => &[],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn strokes(layout: Layout, c: char) -> std::vec::Vec<(u8, u8)> {
+        layout
+            .keystrokes(c)
+            .unwrap_or_else(|| panic!("{layout:?} can't type {c:?}"))
+            .iter()
+            .map(|stroke| (stroke.modifiers, stroke.key))
+            .collect()
+    }
+
+    #[test]
+    fn all_layouts_type_ascii() {
+        for layout in [Layout::Us, Layout::Uk, Layout::De] {
+            let mut seen = HashMap::new();
+
+            for c in (' '..='~').chain(['\n', '\t']) {
+                // Every character needs its own key combination, or the table has a typo.
+                if let Some(other) = seen.insert(strokes(layout, c), c) {
+                    panic!("{layout:?} types {c:?} and {other:?} the same way");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn tables_have_no_duplicates() {
+        for table in [US, UK, DE] {
+            for (i, (c, modifiers, key)) in table.iter().enumerate() {
+                let clash = table[i + 1..]
+                    .iter()
+                    .find(|(other, m, k)| other == c || (m, k) == (modifiers, key));
+
+                assert_eq!(clash, None, "{c:?} clashes");
+            }
+        }
+    }
+
+    #[test]
+    fn us() {
+        assert_eq!(strokes(Layout::Us, 'a'), [(0, 0x04)]);
+        assert_eq!(strokes(Layout::Us, 'Z'), [(SHIFT, 0x1D)]);
+        assert_eq!(strokes(Layout::Us, '@'), [(SHIFT, 0x1F)]);
+        assert_eq!(strokes(Layout::Us, '"'), [(SHIFT, 0x34)]);
+        assert_eq!(strokes(Layout::Us, '\\'), [(0, 0x31)]);
+        assert_eq!(Layout::Us.keystrokes('£'), None);
+    }
+
+    #[test]
+    fn uk() {
+        assert_eq!(strokes(Layout::Uk, '@'), [(SHIFT, 0x34)]);
+        assert_eq!(strokes(Layout::Uk, '"'), [(SHIFT, 0x1F)]);
+        assert_eq!(strokes(Layout::Uk, '#'), [(0, NON_US_HASH)]);
+        assert_eq!(strokes(Layout::Uk, '\\'), [(0, NON_US_BACKSLASH)]);
+        assert_eq!(strokes(Layout::Uk, '£'), [(SHIFT, 0x20)]);
+        assert_eq!(strokes(Layout::Uk, '€'), [(ALTGR, 0x21)]);
+    }
+
+    #[test]
+    fn de() {
+        assert_eq!(strokes(Layout::De, 'z'), [(0, 0x1C)]);
+        assert_eq!(strokes(Layout::De, 'Y'), [(SHIFT, 0x1D)]);
+        assert_eq!(strokes(Layout::De, '@'), [(ALTGR, 0x14)]);
+        assert_eq!(strokes(Layout::De, 'ß'), [(0, 0x2D)]);
+        assert_eq!(strokes(Layout::De, 'Ä'), [(SHIFT, 0x34)]);
+        assert_eq!(strokes(Layout::De, '-'), [(0, 0x38)]);
+        assert_eq!(strokes(Layout::De, '^'), [(0, 0x35), (0, SPACE)]);
+        assert_eq!(strokes(Layout::De, '`'), [(SHIFT, 0x2E), (0, SPACE)]);
+        assert_eq!(Layout::De.keystrokes('£'), None);
+    }
+}
@@ -0,0 +1,135 @@
+//! The host's keyboard LEDs.
+//!
+//! Lock state lives on the host, which tells every keyboard which LEDs to light
+//! with an output report. [`Leds`] is the state from the last one, and
+//! [`apply`] shows it on whichever keys [`Config::indicators`] name.
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::{Config, NUM_LEDS};
+use crate::hid::ReportId;
+use crate::keypad::NUM_KEYS;
+
+/// A keyboard LED, in the order of the LED report's bits.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Led {
+    NumLock,
+    CapsLock,
+    ScrollLock,
+    Compose,
+    Kana,
+}
+
+impl Led {
+    pub const ALL: [Self; NUM_LEDS] = [
+        Self::NumLock,
+        Self::CapsLock,
+        Self::ScrollLock,
+        Self::Compose,
+        Self::Kana,
+    ];
+
+    /// A short name for the display.
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::NumLock => "NUM",
+            Self::CapsLock => "CAPS",
+            Self::ScrollLock => "SCRL",
+            Self::Compose => "COMP",
+            Self::Kana => "KANA",
+        }
+    }
+}
+
+/// Which LEDs the host wants lit, one bit per [`Led`].
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub struct Leds(pub u8);
+
+impl Leds {
+    /// Reads an LED output report. Reports for the composite interface start with
+    /// their ID (`with_id`); the boot interface's are just the LED byte.
+    pub fn parse(report: &[u8], with_id: bool) -> Option<Self> {
+        let leds = match (with_id, report) {
+            (true, [id, leds, ..]) if *id == ReportId::Keyboard as u8 => leds,
+            (false, [leds, ..]) => leds,
+            _ => return None,
+        };
+
+        Some(Self(leds & ((1 << NUM_LEDS) - 1)))
+    }
+
+    pub fn is_lit(self, led: Led) -> bool {
+        self.0 & 1 << led as u8 != 0
+    }
+
+    /// The lit LEDs that `config` wants shown on the display.
+    pub fn displayed(self, config: &Config) -> impl Iterator<Item = Led> + '_ {
+        Led::ALL.into_iter().filter(move |led| {
+            self.is_lit(*led) && config.indicators[*led as usize].is_some_and(|i| i.display)
+        })
+    }
+}
+
+/// Gives the keys of lit indicators their indicator color, in place of their
+/// default one.
+pub fn apply(config: &Config, leds: Leds, colors: &mut [[u8; 6]; NUM_KEYS]) {
+    for led in Led::ALL {
+        let Some(indicator) = config.indicators[led as usize] else {
+            continue;
+        };
+
+        let key = indicator.key.and_then(|key| colors.get_mut(key as usize));
+
+        if let (Some(key), true) = (key, leds.is_lit(led)) {
+            key[..3].copy_from_slice(&indicator.color);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use super::*;
+    use crate::config::Indicator;
+
+    #[test]
+    fn parses_reports() {
+        assert_eq!(Leds::parse(&[1, 0b0000_0010], true), Some(Leds(0b10)));
+        assert_eq!(Leds::parse(&[0b1110_0011], false), Some(Leds(0b11)));
+
+        // Wrong report ID, or nothing to read.
+        assert_eq!(Leds::parse(&[2, 0b10], true), None);
+        assert_eq!(Leds::parse(&[], false), None);
+
+        let leds = Leds(0b10);
+        assert!(leds.is_lit(Led::CapsLock));
+        assert!(!leds.is_lit(Led::NumLock));
+    }
+
+    #[test]
+    fn recolors_lit_indicators() {
+        let mut config = Config::default();
+
+        config.indicators[Led::CapsLock as usize] = Some(Indicator {
+            key: Some(3),
+            color: [255, 0, 0],
+            display: true,
+        });
+        config.indicators[Led::NumLock as usize] = Some(Indicator {
+            key: Some(4),
+            color: [0, 0, 255],
+            display: false,
+        });
+
+        let mut colors = [[1, 2, 3, 4, 5, 6]; NUM_KEYS];
+        apply(&config, Leds(0b10), &mut colors);
+
+        assert_eq!(colors[3], [255, 0, 0, 4, 5, 6]);
+        assert_eq!(colors[4], [1, 2, 3, 4, 5, 6]);
+
+        let shown: Vec<_> = Leds(0b11).displayed(&config).collect();
+        assert_eq!(shown, [Led::CapsLock]);
+    }
+}
@@ -0,0 +1,31 @@
+//! Hardware-independent logic for the Hyperdeck firmware.
+//!
+//! Everything in here is `no_std` and free of RP2040 specifics, so it can be
+//! shared with host tooling and unit tested with a plain `cargo test`.
+
+#![cfg_attr(not(any(test, feature = "mock")), no_std)]
+
+pub mod combo;
+pub mod config;
+pub mod consumer;
+pub mod crc;
+pub mod debounce;
+pub mod device;
+pub mod engine;
+pub mod hid;
+pub mod keyboard;
+pub mod keypad;
+pub mod layout;
+pub mod leds;
+pub mod macros;
+pub mod mouse;
+pub mod protocol;
+pub mod queue;
+pub mod screen;
+pub mod session;
+pub mod storage;
+pub mod time;
+pub mod unicode;
+
+#[cfg(any(test, feature = "mock"))]
+pub mod mock;
@@ -0,0 +1,443 @@
+//! Non-blocking playback of [`Macro`]s and text snippets.
+//!
+//! [`MacroPlayer::poll`] is called from the main loop. Each call sends at most one
+//! keyboard report, and only once the previous one is at least
+//! [`MacroPlayer::STEP_INTERVAL`] old, so the host sees every intermediate state
+//! and the keypad scan carries on in between. If the USB stack isn't ready for a
+//! report yet, the same report is offered again on the next poll.
+//!
+//! Text is turned into keystrokes one character at a time as it's typed, using the
+//! host's [`Layout`]. Characters the layout doesn't have are entered with a
+//! [`UnicodeMode`] if one is configured, and skipped otherwise.
+
+use heapless::{String, Vec};
+
+use crate::config::{Macro, MacroStep, TEXT_LEN};
+use crate::layout::{Keystroke, Layout};
+use crate::time::{Duration, Instant};
+use crate::unicode::{self, UnicodeMode};
+
+/// HID usages for the modifier keys, which live in the report's first byte.
+const MODIFIERS: core::ops::RangeInclusive<u8> = 0xE0..=0xE7;
+
+/// What the player is working through.
+enum Source {
+    Steps(Macro),
+    Text(String<TEXT_LEN>, Layout, Option<UnicodeMode>),
+}
+
+#[derive(Clone, Copy)]
+enum Step {
+    Macro(MacroStep),
+    /// A key tapped together with its modifiers, when typing text.
+    Stroke(Keystroke),
+}
+
+pub struct MacroPlayer {
+    source: Source,
+    /// Index of the next macro step, or byte offset of the next character.
+    pos: usize,
+    /// Steps for the character being typed.
+    pending: Vec<Step, { unicode::MAX_STEPS }>,
+    pending_pos: usize,
+    /// The step being played, kept until its report is accepted.
+    current: Option<Step>,
+    /// The report as last sent to the host.
+    report: [u8; 8],
+    /// A tapped key (and modifiers) that still needs releasing.
+    tapped: Option<Keystroke>,
+    /// When the last report was sent, pushed forward by any delays since.
+    /// Delays count from here, rather than from when the step is reached.
+    anchor: Instant,
+    resume_at: Instant,
+    playing: bool,
+}
+
+impl MacroPlayer {
+    /// Minimum time between two reports.
+    pub const STEP_INTERVAL: Duration = Duration::millis(10);
+}
+
+impl MacroPlayer {
+    pub const fn new() -> Self {
+        Self {
+            source: Source::Steps(Macro::new()),
+            pos: 0,
+            pending: Vec::new(),
+            pending_pos: 0,
+            current: None,
+            report: [0; 8],
+            tapped: None,
+            anchor: Instant::from_ticks(0),
+            resume_at: Instant::from_ticks(0),
+            playing: false,
+        }
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.playing
+    }
+
+    /// Starts playing `steps`. Does nothing (and returns `false`) if something
+    /// else is still playing.
+    pub fn play(&mut self, steps: &Macro, now: Instant) -> bool {
+        self.start(Source::Steps(steps.clone()), now)
+    }
+
+    /// Starts typing `text` for a host using `layout`, falling back to `unicode`
+    /// for characters the layout doesn't have. Does nothing (and returns `false`)
+    /// if something else is still playing, or `text` is longer than [`TEXT_LEN`].
+    pub fn type_text(
+        &mut self,
+        text: &str,
+        layout: Layout,
+        unicode: Option<UnicodeMode>,
+        now: Instant,
+    ) -> bool {
+        let mut owned = String::new();
+
+        match owned.push_str(text) {
+            Ok(()) => self.start(Source::Text(owned, layout, unicode), now),
+            Err(()) => false,
+        }
+    }
+
+    fn start(&mut self, source: Source, now: Instant) -> bool {
+        if self.playing {
+            return false;
+        }
+
+        self.playing = match &source {
+            Source::Steps(steps) => !steps.is_empty(),
+            Source::Text(text, ..) => !text.is_empty(),
+        };
+
+        self.source = source;
+        self.pos = 0;
+        self.pending.clear();
+        self.pending_pos = 0;
+        self.current = None;
+        self.report = [0; 8];
+        self.tapped = None;
+        self.anchor = now;
+        self.resume_at = now;
+
+        true
+    }
+
+    /// Plays the next step if it's due, handing any report to `send`.
+    ///
+    /// `send` returns whether the report was accepted; if it wasn't, the step is
+    /// retried on the next call. Keys still down when the macro ends are released.
+    pub fn poll(&mut self, now: Instant, mut send: impl FnMut([u8; 8]) -> bool) {
+        if !self.playing || now < self.resume_at {
+            return;
+        }
+
+        if let Some(stroke) = self.tapped {
+            let mut report = self.with(stroke.key, false);
+            report[0] &= !stroke.modifiers;
+
+            if send(report) {
+                self.report = report;
+                self.tapped = None;
+                self.sent(now);
+            }
+
+            return;
+        }
+
+        let Some(step) = self.current.take().or_else(|| self.fetch()) else {
+            if self.report == [0; 8] || send([0; 8]) {
+                self.report = [0; 8];
+                self.playing = false;
+            }
+
+            return;
+        };
+
+        let report = match step {
+            Step::Macro(MacroStep::Press(key) | MacroStep::Tap(key)) => self.with(key, true),
+            Step::Macro(MacroStep::Release(key)) => self.with(key, false),
+            Step::Macro(MacroStep::Delay(ms)) => {
+                self.anchor += Duration::millis(ms as u64);
+                self.resume_at = self.resume_at.max(self.anchor);
+                return;
+            }
+            Step::Stroke(stroke) => {
+                let mut report = self.with(stroke.key, true);
+                report[0] |= stroke.modifiers;
+                report
+            }
+        };
+
+        // Steps that don't change anything (e.g. releasing a key that isn't down)
+        // don't need a report of their own.
+        if report != self.report && !send(report) {
+            self.current = Some(step);
+            return;
+        }
+
+        self.tapped = match step {
+            Step::Macro(MacroStep::Tap(key)) => Some(Keystroke { modifiers: 0, key }),
+            Step::Stroke(stroke) => Some(stroke),
+            _ => None,
+        };
+
+        self.report = report;
+        self.sent(now);
+    }
+
+    /// Takes the next step from the source.
+    fn fetch(&mut self) -> Option<Step> {
+        let (layout, unicode) = match &self.source {
+            Source::Steps(steps) => {
+                let step = *steps.get(self.pos)?;
+                self.pos += 1;
+
+                return Some(Step::Macro(step));
+            }
+            Source::Text(_, layout, unicode) => (*layout, *unicode),
+        };
+
+        loop {
+            if let Some(&step) = self.pending.get(self.pending_pos) {
+                self.pending_pos += 1;
+                return Some(step);
+            }
+
+            let Source::Text(text, ..) = &self.source else {
+                unreachable!();
+            };
+
+            let c = text[self.pos..].chars().next()?;
+
+            self.pos += c.len_utf8();
+            self.pending.clear();
+            self.pending_pos = 0;
+
+            if let Some(strokes) = layout.keystrokes(c) {
+                self.pending.extend(strokes.into_iter().map(Step::Stroke));
+            } else if let Some(steps) = unicode.and_then(|mode| mode.steps(c)) {
+                self.pending.extend(steps.into_iter().map(Step::Macro));
+            }
+        }
+    }
+
+    fn sent(&mut self, now: Instant) {
+        self.anchor = now;
+        self.resume_at = now + Self::STEP_INTERVAL;
+    }
+
+    /// The current report with `key` pressed or released.
+    ///
+    /// Pressing a seventh non-modifier key has no effect.
+    fn with(&self, key: u8, pressed: bool) -> [u8; 8] {
+        let mut report = self.report;
+
+        if MODIFIERS.contains(&key) {
+            let bit = 1 << (key - MODIFIERS.start());
+
+            match pressed {
+                true => report[0] |= bit,
+                false => report[0] &= !bit,
+            }
+
+            return report;
+        }
+
+        let keys = &mut report[2..];
+
+        match (pressed, keys.iter().position(|&k| k == key)) {
+            (true, None) => {
+                if let Some(slot) = keys.iter_mut().find(|k| **k == 0) {
+                    *slot = key;
+                }
+            }
+            (false, Some(index)) => {
+                // Keep the remaining keys packed at the front.
+                keys.copy_within(index + 1.., index);
+                keys[keys.len() - 1] = 0;
+            }
+            _ => (),
+        }
+
+        report
+    }
+}
+
+impl Default for MacroPlayer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use super::*;
+    use crate::config::MacroStep::*;
+
+    const CTRL: u8 = 0xE0;
+    const C: u8 = 0x06;
+    const K: u8 = 0x0E;
+
+    fn play(steps: &[MacroStep], accept: impl FnMut(u64) -> bool) -> Vec<(u64, [u8; 8])> {
+        let mut player = MacroPlayer::new();
+
+        assert!(player.play(&Macro::from_slice(steps).unwrap(), Instant::from_ticks(0)));
+        run(player, accept)
+    }
+
+    fn type_text(text: &str, layout: Layout) -> Vec<[u8; 8]> {
+        let mut player = MacroPlayer::new();
+
+        assert!(player.type_text(text, layout, None, Instant::from_ticks(0)));
+        run(player, |_| true)
+            .into_iter()
+            .map(|(_, report)| report)
+            .collect()
+    }
+
+    /// Polls every millisecond until the player finishes, logging each report
+    /// with the time it was sent.
+    fn run(mut player: MacroPlayer, mut accept: impl FnMut(u64) -> bool) -> Vec<(u64, [u8; 8])> {
+        let mut log = Vec::new();
+
+        for ms in 0..10_000 {
+            player.poll(Instant::from_ticks(ms * 1000), |report| {
+                let accepted = accept(ms);
+
+                if accepted {
+                    log.push((ms, report));
+                }
+
+                accepted
+            });
+
+            if !player.is_playing() {
+                return log;
+            }
+        }
+
+        panic!("macro never finished");
+    }
+
+    #[test]
+    fn chord_sequence() {
+        // Ctrl+K, Ctrl+C
+        let log = play(&[Press(CTRL), Tap(K), Tap(C), Release(CTRL)], |_| true);
+
+        assert_eq!(
+            log,
+            [
+                (0, [0x01, 0, 0, 0, 0, 0, 0, 0]),
+                (10, [0x01, 0, K, 0, 0, 0, 0, 0]),
+                (20, [0x01, 0, 0, 0, 0, 0, 0, 0]),
+                (30, [0x01, 0, C, 0, 0, 0, 0, 0]),
+                (40, [0x01, 0, 0, 0, 0, 0, 0, 0]),
+                (50, [0; 8]),
+            ]
+        );
+    }
+
+    #[test]
+    fn delays() {
+        let log = play(&[Tap(K), Delay(100), Tap(C)], |_| true);
+        let times: Vec<_> = log.iter().map(|(ms, _)| *ms).collect();
+
+        assert_eq!(times, [0, 10, 110, 120]);
+    }
+
+    #[test]
+    fn retries_rejected_reports() {
+        // The USB stack is busy for the first 25 ms.
+        let log = play(&[Tap(K)], |ms| ms >= 25);
+
+        assert_eq!(log, [(25, [0, 0, K, 0, 0, 0, 0, 0]), (35, [0; 8])]);
+    }
+
+    #[test]
+    fn releases_leftovers() {
+        let log = play(&[Press(CTRL), Press(K), Press(C), Release(K)], |_| true);
+
+        assert_eq!(log[3], (30, [0x01, 0, C, 0, 0, 0, 0, 0]));
+        assert_eq!(log.last(), Some(&(40, [0; 8])));
+    }
+
+    #[test]
+    fn types_text() {
+        assert_eq!(
+            type_text("Hi!", Layout::Us),
+            [
+                [0x02, 0, 0x0B, 0, 0, 0, 0, 0],
+                [0; 8],
+                [0, 0, 0x0C, 0, 0, 0, 0, 0],
+                [0; 8],
+                [0x02, 0, 0x1E, 0, 0, 0, 0, 0],
+                [0; 8],
+            ]
+        );
+
+        // Repeated letters need a release in between.
+        assert_eq!(
+            type_text("zz", Layout::De),
+            [
+                [0, 0, 0x1C, 0, 0, 0, 0, 0],
+                [0; 8],
+                [0, 0, 0x1C, 0, 0, 0, 0, 0],
+                [0; 8],
+            ]
+        );
+    }
+
+    #[test]
+    fn types_dead_keys_and_skips_untypeable() {
+        assert_eq!(
+            type_text("^£", Layout::De),
+            [
+                [0, 0, 0x35, 0, 0, 0, 0, 0],
+                [0; 8],
+                [0, 0, 0x2C, 0, 0, 0, 0, 0],
+                [0; 8],
+            ]
+        );
+    }
+
+    #[test]
+    fn falls_back_to_unicode() {
+        let mut player = MacroPlayer::new();
+        let unicode = Some(UnicodeMode::WinCompose);
+
+        assert!(player.type_text("a€", Layout::Us, unicode, Instant::from_ticks(0)));
+
+        // Every key is released again, so only the presses are interesting.
+        let pressed: Vec<_> = run(player, |_| true)
+            .into_iter()
+            .filter(|(_, report)| *report != [0; 8])
+            .map(|(_, report)| (report[0], report[2]))
+            .collect();
+
+        // 'a', then compose, U, 2, 0, A, C and Enter.
+        assert_eq!(pressed, [
+            (0, 0x04),
+            (0x40, 0),
+            (0, 0x18),
+            (0, 0x1F),
+            (0, 0x27),
+            (0, 0x04),
+            (0, 0x06),
+            (0, 0x28),
+        ]);
+    }
+
+    #[test]
+    fn one_at_a_time() {
+        let steps = Macro::from_slice(&[Tap(K)]).unwrap();
+        let mut player = MacroPlayer::new();
+
+        assert!(player.play(&steps, Instant::from_ticks(0)));
+        assert!(!player.play(&steps, Instant::from_ticks(0)));
+    }
+}
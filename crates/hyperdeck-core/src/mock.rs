@@ -0,0 +1,323 @@
+//! Test doubles for hardware traits.
+//!
+//! Used by the tests in here, and by host tools through the `mock` feature.
+
+use std::cell::{Cell, RefCell};
+use std::convert::Infallible;
+use std::rc::Rc;
+use std::vec;
+use std::vec::Vec;
+
+use embedded_graphics::pixelcolor::Rgb565;
+use embedded_graphics::prelude::*;
+use embedded_hal::blocking::{i2c, spi};
+use embedded_hal::digital::v2::OutputPin;
+use embedded_storage::nor_flash::{
+    ErrorType, NorFlash, NorFlashError, NorFlashErrorKind, ReadNorFlash,
+};
+
+use crate::keypad::EXPANDER_ADDR;
+use crate::screen::{HEIGHT, WIDTH};
+
+/// In-memory NOR flash with RP2040-like geometry.
+///
+/// Like real NOR flash, erasing sets bytes to `0xFF` and writing can only
+/// clear bits, so a missing erase shows up as corrupted data.
+pub struct MemFlash<const N: usize> {
+    pub data: Box<[u8; N]>,
+}
+
+#[derive(Debug)]
+pub struct MemFlashError(NorFlashErrorKind);
+
+impl NorFlashError for MemFlashError {
+    fn kind(&self) -> NorFlashErrorKind {
+        self.0
+    }
+}
+
+impl<const N: usize> MemFlash<N> {
+    pub fn new() -> Self {
+        Self {
+            data: Box::new([0xFF; N]),
+        }
+    }
+
+    fn check(offset: u32, len: usize, align: usize) -> Result<(), MemFlashError> {
+        let offset = offset as usize;
+
+        if offset + len > N {
+            return Err(MemFlashError(NorFlashErrorKind::OutOfBounds));
+        }
+
+        if !offset.is_multiple_of(align) || !len.is_multiple_of(align) {
+            return Err(MemFlashError(NorFlashErrorKind::NotAligned));
+        }
+
+        Ok(())
+    }
+}
+
+impl<const N: usize> Default for MemFlash<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> ErrorType for MemFlash<N> {
+    type Error = MemFlashError;
+}
+
+impl<const N: usize> ReadNorFlash for MemFlash<N> {
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        Self::check(offset, bytes.len(), Self::READ_SIZE)?;
+
+        let offset = offset as usize;
+        bytes.copy_from_slice(&self.data[offset..offset + bytes.len()]);
+
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        N
+    }
+}
+
+impl<const N: usize> NorFlash for MemFlash<N> {
+    const WRITE_SIZE: usize = 256;
+    const ERASE_SIZE: usize = 4096;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        Self::check(from, (to - from) as usize, Self::ERASE_SIZE)?;
+        self.data[from as usize..to as usize].fill(0xFF);
+
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        Self::check(offset, bytes.len(), Self::WRITE_SIZE)?;
+
+        let offset = offset as usize;
+
+        for (cell, byte) in self.data[offset..].iter_mut().zip(bytes) {
+            *cell &= *byte;
+        }
+
+        Ok(())
+    }
+}
+
+/// A TCA9555 I/O expander with the keypad's sixteen keys on its input ports.
+///
+/// Clones share the same keys, so a test can hold on to one to press them
+/// while the [`Keypad`](crate::keypad::Keypad) owns another.
+#[derive(Clone, Default)]
+pub struct Tca9555 {
+    /// The keys that are down, one bit per key.
+    pressed: Rc<Cell<u16>>,
+    /// The register the next read starts at, once one's been written.
+    register: Rc<Cell<Option<u8>>>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Tca9555Error {
+    /// Nothing answered at the address.
+    Nack(u8),
+    /// A read came before any register was written.
+    NoRegister,
+    /// A register other than the two input ports.
+    Unsupported(u8),
+}
+
+impl Tca9555 {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_pressed(&self, keys: u16) {
+        self.pressed.set(keys);
+    }
+
+    fn check(address: u8) -> Result<(), Tca9555Error> {
+        match address {
+            EXPANDER_ADDR => Ok(()),
+            _ => Err(Tca9555Error::Nack(address)),
+        }
+    }
+}
+
+impl i2c::Write for Tca9555 {
+    type Error = Tca9555Error;
+
+    fn write(&mut self, address: u8, bytes: &[u8]) -> Result<(), Self::Error> {
+        Self::check(address)?;
+
+        // Only the register pointer is supported, not writing to registers.
+        match *bytes {
+            [register @ (0 | 1)] => {
+                self.register.set(Some(register));
+                Ok(())
+            }
+            [register, ..] => Err(Tca9555Error::Unsupported(register)),
+            [] => Ok(()),
+        }
+    }
+}
+
+impl i2c::Read for Tca9555 {
+    type Error = Tca9555Error;
+
+    fn read(&mut self, address: u8, buffer: &mut [u8]) -> Result<(), Self::Error> {
+        Self::check(address)?;
+
+        let mut register = self.register.get().ok_or(Tca9555Error::NoRegister)?;
+        // The inputs are pulled up, so pressed keys read as zeros.
+        let ports = (!self.pressed.get()).to_le_bytes();
+
+        // Reads flip between the two registers of a pair.
+        for byte in buffer {
+            *byte = ports[register as usize];
+            register ^= 1;
+        }
+
+        Ok(())
+    }
+}
+
+/// A chain of APA102 LEDs, recording every frame sent while its chip select
+/// was low.
+#[derive(Default)]
+pub struct Apa102 {
+    selected: bool,
+    frame: Vec<u8>,
+    frames: Vec<Vec<u8>>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct NotSelected;
+
+impl Apa102 {
+    /// Returns the SPI bus and chip select pin for a new chain.
+    pub fn chain() -> (Apa102Spi, Apa102Cs) {
+        let chain = Rc::new(RefCell::new(Self::default()));
+        (Apa102Spi(chain.clone()), Apa102Cs(chain))
+    }
+}
+
+#[derive(Clone)]
+pub struct Apa102Spi(Rc<RefCell<Apa102>>);
+
+impl Apa102Spi {
+    /// Every frame sent so far.
+    pub fn frames(&self) -> Vec<Vec<u8>> {
+        self.0.borrow().frames.clone()
+    }
+
+    /// The frames sent since the last call, oldest first.
+    pub fn take_frames(&self) -> Vec<Vec<u8>> {
+        core::mem::take(&mut self.0.borrow_mut().frames)
+    }
+}
+
+impl spi::Write<u8> for Apa102Spi {
+    type Error = NotSelected;
+
+    fn write(&mut self, words: &[u8]) -> Result<(), Self::Error> {
+        let mut chain = self.0.borrow_mut();
+
+        if !chain.selected {
+            return Err(NotSelected);
+        }
+
+        chain.frame.extend_from_slice(words);
+        Ok(())
+    }
+}
+
+pub struct Apa102Cs(Rc<RefCell<Apa102>>);
+
+impl OutputPin for Apa102Cs {
+    type Error = Infallible;
+
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        self.0.borrow_mut().selected = true;
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        let mut chain = self.0.borrow_mut();
+
+        if chain.selected {
+            let frame = core::mem::take(&mut chain.frame);
+            chain.frames.push(frame);
+        }
+
+        chain.selected = false;
+        Ok(())
+    }
+}
+
+/// A display-sized frame buffer that screens can be drawn onto and inspected.
+pub struct Canvas {
+    pub pixels: Vec<Rgb565>,
+    /// How many pixels were drawn outside the display.
+    pub clipped: usize,
+}
+
+impl Canvas {
+    pub fn new() -> Self {
+        Self {
+            pixels: vec![Rgb565::BLACK; WIDTH as usize * HEIGHT as usize],
+            clipped: 0,
+        }
+    }
+
+    pub fn pixel(&self, x: u16, y: u16) -> Rgb565 {
+        self.pixels[y as usize * WIDTH as usize + x as usize]
+    }
+
+    /// How many pixels are `color`.
+    pub fn count(&self, color: Rgb565) -> usize {
+        self.pixels.iter().filter(|&&pixel| pixel == color).count()
+    }
+}
+
+impl Default for Canvas {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl OriginDimensions for Canvas {
+    fn size(&self) -> Size {
+        Size::new(WIDTH as u32, HEIGHT as u32)
+    }
+}
+
+impl DrawTarget for Canvas {
+    type Color = Rgb565;
+    type Error = Infallible;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        for Pixel(point, color) in pixels {
+            let (Ok(x), Ok(y)) = (u16::try_from(point.x), u16::try_from(point.y)) else {
+                self.clipped += 1;
+                continue;
+            };
+
+            if x >= WIDTH || y >= HEIGHT {
+                self.clipped += 1;
+                continue;
+            }
+
+            self.pixels[y as usize * WIDTH as usize + x as usize] = color;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,267 @@
+//! Mouse emulation.
+//!
+//! Buttons are reported as soon as they change. While a movement key is down, the
+//! pointer moves every [`Mouse::MOVE_INTERVAL`], starting slowly for precise
+//! positioning and speeding up along a quadratic curve until it reaches full speed
+//! after [`Mouse::ACCELERATION_TIME`]. Scroll keys turn the wheel one notch at a
+//! time, every [`Mouse::SCROLL_INTERVAL`].
+
+use crate::config::{Direction, MouseAction};
+use crate::hid::MouseReport;
+use crate::time::{Duration, Instant};
+
+pub struct Mouse {
+    buttons: u8,
+    /// When each movement key went down, indexed by [`Direction`].
+    moving: [Option<Instant>; 4],
+    /// When each scroll key went down, indexed by [`Direction`].
+    scrolling: [Option<Instant>; 4],
+    /// Whether the buttons changed since the last report.
+    dirty: bool,
+    next_move: Instant,
+    next_scroll: Instant,
+}
+
+impl Mouse {
+    /// Time between movement reports.
+    pub const MOVE_INTERVAL: Duration = Duration::millis(16);
+    /// Time between scroll notches.
+    pub const SCROLL_INTERVAL: Duration = Duration::millis(80);
+    /// How long a movement key has to be held to reach [`Mouse::MAX_SPEED`].
+    pub const ACCELERATION_TIME: Duration = Duration::millis(1500);
+    /// Pixels per movement report when a key is first pressed.
+    pub const MIN_SPEED: u32 = 1;
+    /// Pixels per movement report at full speed.
+    pub const MAX_SPEED: u32 = 24;
+}
+
+impl Mouse {
+    pub const fn new() -> Self {
+        Self {
+            buttons: 0,
+            moving: [None; 4],
+            scrolling: [None; 4],
+            dirty: false,
+            next_move: Instant::from_ticks(0),
+            next_scroll: Instant::from_ticks(0),
+        }
+    }
+
+    /// Handles a mouse key being pressed or released.
+    pub fn handle(&mut self, action: MouseAction, pressed: bool, now: Instant) {
+        let since = pressed.then_some(now);
+
+        match action {
+            MouseAction::Button(button @ 1..=5) => {
+                let bit = 1 << (button - 1);
+
+                match pressed {
+                    true => self.buttons |= bit,
+                    false => self.buttons &= !bit,
+                }
+
+                self.dirty = true;
+            }
+            MouseAction::Button(_) => (),
+            MouseAction::Move(direction) => {
+                // Start moving straight away, rather than on the old schedule.
+                if pressed && self.moving.iter().all(Option::is_none) {
+                    self.next_move = now;
+                }
+
+                self.moving[direction as usize] = since;
+            }
+            MouseAction::Scroll(direction) => {
+                if pressed && self.scrolling.iter().all(Option::is_none) {
+                    self.next_scroll = now;
+                }
+
+                self.scrolling[direction as usize] = since;
+            }
+        }
+    }
+
+    /// Sends a report if the buttons changed or the pointer or wheel is due to
+    /// move. Rejected reports are retried on the next call.
+    pub fn poll(&mut self, now: Instant, send: impl FnOnce(MouseReport) -> bool) {
+        let mut report = MouseReport {
+            buttons: self.buttons,
+            ..Default::default()
+        };
+
+        let moving = self.moving.iter().flatten().min().copied();
+        let move_due = moving.is_some() && now >= self.next_move;
+
+        if let (Some(since), true) = (moving, move_due) {
+            let speed = Self::speed(now - since) as i32;
+            let (x, y) = axes(&self.moving);
+
+            report.x = (x * speed) as i8;
+            report.y = (y * speed) as i8;
+        }
+
+        let scroll_due = self.scrolling.iter().any(Option::is_some) && now >= self.next_scroll;
+
+        if scroll_due {
+            let (x, y) = axes(&self.scrolling);
+
+            // The wheel counts up as it scrolls up, unlike the Y axis.
+            report.wheel = -y as i8;
+            report.pan = x as i8;
+        }
+
+        if !(self.dirty || move_due || scroll_due) || !send(report) {
+            return;
+        }
+
+        self.dirty = false;
+
+        if move_due {
+            self.next_move = now + Self::MOVE_INTERVAL;
+        }
+
+        if scroll_due {
+            self.next_scroll = now + Self::SCROLL_INTERVAL;
+        }
+    }
+
+    /// Pixels per report after a movement key has been held for `held`.
+    fn speed(held: Duration) -> u32 {
+        let total = Self::ACCELERATION_TIME.to_millis();
+        let t = held.to_millis().min(total);
+        let range = (Self::MAX_SPEED - Self::MIN_SPEED) as u64;
+
+        Self::MIN_SPEED + (range * t * t / (total * total)) as u32
+    }
+}
+
+impl Default for Mouse {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Net X and Y direction (-1, 0 or 1) of the keys that are down, with Y pointing
+/// down as in HID.
+fn axes(keys: &[Option<Instant>; 4]) -> (i32, i32) {
+    let down = |direction: Direction| keys[direction as usize].is_some() as i32;
+
+    (
+        down(Direction::Right) - down(Direction::Left),
+        down(Direction::Down) - down(Direction::Up),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(ms: u64) -> Instant {
+        Instant::from_ticks(ms * 1000)
+    }
+
+    /// Polls every millisecond in `from..to`, logging each report with the time it
+    /// was sent.
+    fn run(mouse: &mut Mouse, from: u64, to: u64) -> Vec<(u64, MouseReport)> {
+        let mut log = Vec::new();
+
+        for ms in from..to {
+            mouse.poll(at(ms), |report| {
+                log.push((ms, report));
+                true
+            });
+        }
+
+        log
+    }
+
+    #[test]
+    fn buttons() {
+        let mut mouse = Mouse::new();
+
+        mouse.handle(MouseAction::Button(1), true, at(0));
+        mouse.handle(MouseAction::Button(3), true, at(0));
+        assert_eq!(
+            run(&mut mouse, 0, 100),
+            [(
+                0,
+                MouseReport {
+                    buttons: 0b101,
+                    ..Default::default()
+                }
+            )]
+        );
+
+        mouse.handle(MouseAction::Button(1), false, at(100));
+        mouse.handle(MouseAction::Button(3), false, at(100));
+        assert_eq!(run(&mut mouse, 100, 200), [(100, MouseReport::default())]);
+    }
+
+    #[test]
+    fn accelerates() {
+        let mut mouse = Mouse::new();
+
+        mouse.handle(MouseAction::Move(Direction::Right), true, at(0));
+
+        let log = run(&mut mouse, 0, 3000);
+        let speeds: Vec<_> = log.iter().map(|(_, report)| report.x).collect();
+
+        // One report per interval, each at least as fast as the last.
+        assert_eq!(log.len(), 3000 / 16 + 1);
+        assert_eq!(speeds[0], 1);
+        assert!(speeds.windows(2).all(|pair| pair[0] <= pair[1]));
+        assert_eq!(*speeds.last().unwrap(), Mouse::MAX_SPEED as i8);
+
+        // Halfway through, the curve is a quarter of the way up.
+        let half = log.iter().find(|(ms, _)| *ms >= 750).unwrap().1.x;
+        assert!((6..=8).contains(&half), "{half}");
+
+        mouse.handle(MouseAction::Move(Direction::Right), false, at(3000));
+        assert_eq!(run(&mut mouse, 3000, 3100), []);
+    }
+
+    #[test]
+    fn diagonals() {
+        let mut mouse = Mouse::new();
+
+        mouse.handle(MouseAction::Move(Direction::Up), true, at(0));
+        mouse.handle(MouseAction::Move(Direction::Left), true, at(0));
+
+        let report = run(&mut mouse, 0, 1).pop().unwrap().1;
+        assert_eq!((report.x, report.y), (-1, -1));
+    }
+
+    #[test]
+    fn scrolls() {
+        let mut mouse = Mouse::new();
+
+        mouse.handle(MouseAction::Scroll(Direction::Up), true, at(0));
+
+        let log = run(&mut mouse, 0, 200);
+        let times: Vec<_> = log.iter().map(|(ms, _)| *ms).collect();
+
+        assert_eq!(times, [0, 80, 160]);
+        assert!(log
+            .iter()
+            .all(|(_, report)| report.wheel == 1 && report.pan == 0));
+    }
+
+    #[test]
+    fn retries_rejected_reports() {
+        let mut mouse = Mouse::new();
+
+        mouse.handle(MouseAction::Button(2), true, at(0));
+        mouse.poll(at(0), |_| false);
+
+        assert_eq!(
+            run(&mut mouse, 1, 2),
+            [(
+                1,
+                MouseReport {
+                    buttons: 0b10,
+                    ..Default::default()
+                }
+            )]
+        );
+    }
+}
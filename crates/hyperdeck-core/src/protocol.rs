@@ -0,0 +1,836 @@
+//! Request/response protocol spoken over the CDC serial port.
+//!
+//! Every message travels in its own frame:
+//!
+//! ```text
+//! COBS(version | postcard(message) | CRC-32) 0x00
+//! ```
+//!
+//! COBS guarantees the frame body contains no zero bytes, so a zero always marks
+//! the end of a frame and a receiver can resynchronise after line noise by waiting
+//! for the next one. Frames whose version byte isn't [`VERSION`] are rejected before
+//! their contents are looked at.
+//!
+//! The host sends [`Request`]s and the device answers each with exactly one
+//! [`Response`]. postcard encodes an enum variant as its index, which doubles as
+//! the opcode, so new variants must only ever be appended.
+
+use embedded_storage::nor_flash::NorFlash;
+use heapless::String;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::config::{
+    Brightness, Config, Indicator, KeyAction, KeyConfig, LayerConfig, Macro, Repeat, Tapping,
+    LAYER_KEYS, MAX_LAYERS, MAX_MACROS, MAX_TEXTS, NAME_LEN, NUM_LEDS, TEXT_LEN,
+};
+use crate::crc::crc32;
+use crate::debounce::Debounce;
+use crate::keypad::NUM_KEYS;
+use crate::layout::Layout;
+use crate::queue::Stats;
+use crate::unicode::UnicodeMode;
+
+/// Protocol version carried in every frame.
+pub const VERSION: u8 = 1;
+/// Maximum size of an encoded frame, delimiter included. Big enough for a layer
+/// with every action of every key set.
+pub const MAX_FRAME_LEN: usize = 1024;
+
+/// Bytes of overhead around the postcard payload (version byte and CRC).
+const OVERHEAD: usize = 5;
+
+// No allocator for boxing the layer variants, and messages are short-lived anyway.
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Request {
+    /// Opens a session; answered with [`Response::Hello`].
+    Hello,
+    GetKey {
+        layer: u8,
+        key: u8,
+    },
+    SetKey {
+        layer: u8,
+        key: u8,
+        config: KeyConfig,
+    },
+    GetLayer {
+        layer: u8,
+    },
+    /// Replaces a whole layer, or removes it if `config` is `None`.
+    SetLayer {
+        layer: u8,
+        config: Option<LayerConfig>,
+    },
+    GetLayerName {
+        layer: u8,
+    },
+    SetLayerName {
+        layer: u8,
+        name: String<NAME_LEN>,
+    },
+    GetBrightness,
+    SetBrightness(Brightness),
+    /// Writes the working configuration to flash.
+    Commit,
+    /// Discards uncommitted changes by reloading the configuration from flash.
+    Revert,
+    GetMacro {
+        index: u8,
+    },
+    /// Replaces a macro; an empty one is unused.
+    SetMacro {
+        index: u8,
+        steps: Macro,
+    },
+    GetText {
+        index: u8,
+    },
+    /// Replaces a text snippet; an empty one is unused.
+    SetText {
+        index: u8,
+        text: String<TEXT_LEN>,
+    },
+    GetLayout,
+    /// Sets the host keyboard layout text snippets are typed for.
+    SetLayout(Layout),
+    GetUnicode,
+    /// Sets how characters missing from the layout are entered, or disables it.
+    SetUnicode(Option<UnicodeMode>),
+    /// Reads the device's HID report counters.
+    GetStats,
+    GetIndicators,
+    /// Replaces every LED indicator, indexed by [`Led`](crate::leds::Led).
+    SetIndicators([Option<Indicator>; NUM_LEDS]),
+    GetTapping,
+    /// Sets the global tap-hold timing and options.
+    SetTapping(Tapping),
+    GetDebounce,
+    /// Sets how the keypad's raw key states are debounced.
+    SetDebounce(Debounce),
+    GetRepeat,
+    /// Sets the auto-repeat delay and rate.
+    SetRepeat(Repeat),
+}
+
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Response {
+    Hello {
+        version: u8,
+        max_layers: u8,
+        layer_keys: u8,
+    },
+    Ok,
+    Key(KeyConfig),
+    Layer(Option<LayerConfig>),
+    LayerName(String<NAME_LEN>),
+    Brightness(Brightness),
+    Error(RequestError),
+    Macro(Macro),
+    Text(String<TEXT_LEN>),
+    Layout(Layout),
+    Unicode(Option<UnicodeMode>),
+    Stats(Stats),
+    Indicators([Option<Indicator>; NUM_LEDS]),
+    Tapping(Tapping),
+    Debounce(Debounce),
+    Repeat(Repeat),
+}
+
+/// Why the device refused a request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RequestError {
+    /// The frame was corrupt or didn't contain a request we understand.
+    BadFrame,
+    /// The frame was for a different protocol version.
+    UnsupportedVersion,
+    NoSuchLayer,
+    NoSuchKey,
+    /// The layer exists in range but hasn't been configured.
+    EmptyLayer,
+    /// Reading or writing flash failed.
+    Storage,
+    NoSuchMacro,
+    NoSuchText,
+    /// A value was out of range, like a brightness over 100.
+    OutOfRange,
+}
+
+/// Why a frame couldn't be encoded or decoded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameError {
+    /// The frame exceeded [`MAX_FRAME_LEN`].
+    TooLarge,
+    /// The COBS encoding was invalid, or the frame was too short.
+    Malformed,
+    BadChecksum,
+    UnsupportedVersion(u8),
+    /// The frame was intact, but its contents didn't deserialize.
+    BadMessage,
+}
+
+impl From<FrameError> for RequestError {
+    fn from(err: FrameError) -> Self {
+        match err {
+            FrameError::UnsupportedVersion(_) => Self::UnsupportedVersion,
+            _ => Self::BadFrame,
+        }
+    }
+}
+
+/// Encodes `message` into `out` as a complete frame, delimiter included,
+/// returning the number of bytes written.
+pub fn encode_frame<T: Serialize>(message: &T, out: &mut [u8]) -> Result<usize, FrameError> {
+    let mut raw = [0_u8; MAX_FRAME_LEN];
+
+    raw[0] = VERSION;
+
+    let length = postcard::to_slice(message, &mut raw[1..MAX_FRAME_LEN - 4])
+        .map_err(|_| FrameError::TooLarge)?
+        .len()
+        + 1;
+
+    let crc = crc32(&raw[..length]);
+    raw[length..length + 4].copy_from_slice(&crc.to_le_bytes());
+
+    let limit = out.len().min(MAX_FRAME_LEN).saturating_sub(1);
+
+    let encoded = cobs::try_encode(&raw[..length + 4], &mut out[..limit])
+        .map_err(|_| FrameError::TooLarge)?;
+
+    out[encoded] = 0;
+
+    Ok(encoded + 1)
+}
+
+/// Decodes a single frame (without its delimiter) in place.
+pub fn decode_frame<T: DeserializeOwned>(frame: &mut [u8]) -> Result<T, FrameError> {
+    let length = cobs::decode_in_place(frame).map_err(|_| FrameError::Malformed)?;
+
+    if length < OVERHEAD {
+        return Err(FrameError::Malformed);
+    }
+
+    let (body, crc) = frame[..length].split_at(length - 4);
+
+    if crc32(body).to_le_bytes() != crc {
+        return Err(FrameError::BadChecksum);
+    }
+
+    if body[0] != VERSION {
+        return Err(FrameError::UnsupportedVersion(body[0]));
+    }
+
+    postcard::from_bytes(&body[1..]).map_err(|_| FrameError::BadMessage)
+}
+
+/// Accumulates received bytes and splits them into frames.
+pub struct FrameDecoder {
+    buf: [u8; MAX_FRAME_LEN],
+    len: usize,
+    overflowed: bool,
+}
+
+impl FrameDecoder {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; MAX_FRAME_LEN],
+            len: 0,
+            overflowed: false,
+        }
+    }
+
+    /// Feeds one received byte into the decoder.
+    ///
+    /// Returns the decoded message (or why it couldn't be decoded) whenever a
+    /// delimiter completes a frame. Empty frames are ignored, so hosts may send a
+    /// lone zero to flush any partial frame left over from an earlier session.
+    pub fn feed<T: DeserializeOwned>(&mut self, byte: u8) -> Option<Result<T, FrameError>> {
+        if byte != 0 {
+            match self.len < self.buf.len() {
+                true => {
+                    self.buf[self.len] = byte;
+                    self.len += 1;
+                }
+                false => self.overflowed = true,
+            }
+
+            return None;
+        }
+
+        let length = core::mem::take(&mut self.len);
+
+        if core::mem::take(&mut self.overflowed) {
+            return Some(Err(FrameError::TooLarge));
+        }
+
+        match length {
+            0 => None,
+            _ => Some(decode_frame(&mut self.buf[..length])),
+        }
+    }
+}
+
+impl Default for FrameDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Executes `request` against the working `config`, committing it to
+/// (or reverting it from) `flash` on request. `stats` are only read.
+pub fn respond<F: NorFlash>(
+    request: Request,
+    config: &mut Config,
+    flash: &mut F,
+    stats: Stats,
+) -> Response {
+    use Request::*;
+
+    let result = match request {
+        Hello => Ok(Response::Hello {
+            version: VERSION,
+            max_layers: MAX_LAYERS as u8,
+            layer_keys: LAYER_KEYS as u8,
+        }),
+        GetKey { layer, key } => configured(config, layer)
+            .and_then(|layer| key_index(key).map(|key| Response::Key(layer.keys[key].clone()))),
+        SetKey {
+            layer,
+            key,
+            config: key_config,
+        } => configured(config, layer).and_then(|layer| {
+            check_key(&key_config)?;
+            layer.keys[key_index(key)?] = key_config;
+            Ok(Response::Ok)
+        }),
+        GetLayer { layer } => slot(config, layer).map(|layer| Response::Layer(layer.clone())),
+        SetLayer {
+            layer,
+            config: layer_config,
+        } => slot(config, layer).and_then(|layer| {
+            layer_config.iter().try_for_each(check_layer)?;
+            *layer = layer_config;
+            Ok(Response::Ok)
+        }),
+        GetLayerName { layer } => {
+            configured(config, layer).map(|layer| Response::LayerName(layer.name.clone()))
+        }
+        SetLayerName { layer, name } => configured(config, layer).map(|layer| {
+            layer.name = name;
+            Response::Ok
+        }),
+        GetBrightness => Ok(Response::Brightness(config.brightness)),
+        SetBrightness(brightness) => check_brightness(brightness).map(|_| {
+            config.brightness = brightness;
+            Response::Ok
+        }),
+        Commit => config
+            .store(flash)
+            .map(|_| Response::Ok)
+            .map_err(|_| RequestError::Storage),
+        Revert => Config::load(flash)
+            .map(|stored| {
+                *config = stored;
+                Response::Ok
+            })
+            .map_err(|_| RequestError::Storage),
+        GetMacro { index } => macro_slot(config, index).map(|steps| Response::Macro(steps.clone())),
+        SetMacro { index, steps } => macro_slot(config, index).map(|slot| {
+            *slot = steps;
+            Response::Ok
+        }),
+        GetText { index } => text_slot(config, index).map(|text| Response::Text(text.clone())),
+        SetText { index, text } => text_slot(config, index).map(|slot| {
+            *slot = text;
+            Response::Ok
+        }),
+        GetLayout => Ok(Response::Layout(config.layout)),
+        SetLayout(layout) => {
+            config.layout = layout;
+            Ok(Response::Ok)
+        }
+        GetUnicode => Ok(Response::Unicode(config.unicode)),
+        SetUnicode(mode) => {
+            config.unicode = mode;
+            Ok(Response::Ok)
+        }
+        GetStats => Ok(Response::Stats(stats)),
+        GetIndicators => Ok(Response::Indicators(config.indicators)),
+        SetIndicators(indicators) => check_indicators(&indicators).map(|_| {
+            config.indicators = indicators;
+            Response::Ok
+        }),
+        GetTapping => Ok(Response::Tapping(config.tapping)),
+        SetTapping(tapping) => {
+            config.tapping = tapping;
+            Ok(Response::Ok)
+        }
+        GetDebounce => Ok(Response::Debounce(config.debounce)),
+        SetDebounce(debounce) => {
+            config.debounce = debounce;
+            Ok(Response::Ok)
+        }
+        GetRepeat => Ok(Response::Repeat(config.repeat)),
+        SetRepeat(repeat) => {
+            config.repeat = repeat;
+            Ok(Response::Ok)
+        }
+    };
+
+    result.unwrap_or_else(Response::Error)
+}
+
+fn slot(config: &mut Config, layer: u8) -> Result<&mut Option<LayerConfig>, RequestError> {
+    config
+        .layers
+        .get_mut(layer as usize)
+        .ok_or(RequestError::NoSuchLayer)
+}
+
+fn configured(config: &mut Config, layer: u8) -> Result<&mut LayerConfig, RequestError> {
+    slot(config, layer)?
+        .as_mut()
+        .ok_or(RequestError::EmptyLayer)
+}
+
+fn macro_slot(config: &mut Config, index: u8) -> Result<&mut Macro, RequestError> {
+    config
+        .macros
+        .get_mut(index as usize)
+        .ok_or(RequestError::NoSuchMacro)
+}
+
+fn text_slot(config: &mut Config, index: u8) -> Result<&mut String<TEXT_LEN>, RequestError> {
+    config
+        .texts
+        .get_mut(index as usize)
+        .ok_or(RequestError::NoSuchText)
+}
+
+fn key_index(key: u8) -> Result<usize, RequestError> {
+    match (key as usize) < LAYER_KEYS {
+        true => Ok(key as usize),
+        false => Err(RequestError::NoSuchKey),
+    }
+}
+
+// Hosts are expected to validate configurations before sending them, but the
+// firmware shouldn't have to cope with ones that didn't.
+
+fn check_brightness(brightness: Brightness) -> Result<(), RequestError> {
+    match brightness.keypad <= 100 && brightness.display <= 100 {
+        true => Ok(()),
+        false => Err(RequestError::OutOfRange),
+    }
+}
+
+/// Checks that `action` only refers to layers, macros and texts that can exist.
+/// They needn't be configured yet, since hosts may send them afterwards.
+fn check_action(action: &KeyAction) -> Result<(), RequestError> {
+    match *action {
+        KeyAction::Layer(action) if action.layer() as usize >= MAX_LAYERS => {
+            Err(RequestError::NoSuchLayer)
+        }
+        KeyAction::Macro(index) if index as usize >= MAX_MACROS => Err(RequestError::NoSuchMacro),
+        KeyAction::Text(index) if index as usize >= MAX_TEXTS => Err(RequestError::NoSuchText),
+        _ => Ok(()),
+    }
+}
+
+fn check_key(key: &KeyConfig) -> Result<(), RequestError> {
+    key.actions().try_for_each(check_action)
+}
+
+fn check_layer(layer: &LayerConfig) -> Result<(), RequestError> {
+    layer.keys.iter().try_for_each(check_key)?;
+    layer
+        .combos
+        .iter()
+        .try_for_each(|combo| check_action(&combo.action))
+}
+
+fn check_indicators(indicators: &[Option<Indicator>]) -> Result<(), RequestError> {
+    match indicators
+        .iter()
+        .flatten()
+        .filter_map(|indicator| indicator.key)
+        .all(|key| (key as usize) < NUM_KEYS)
+    {
+        true => Ok(()),
+        false => Err(RequestError::NoSuchKey),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use heapless::Vec;
+
+    use super::*;
+    use crate::config::{Combo, LayerAction, MacroStep, MAX_COMBOS, MAX_TAPS};
+    use crate::debounce::Algorithm;
+    use crate::mock::MemFlash;
+
+    type Flash = MemFlash<{ 2 * 8192 }>;
+
+    fn round_trip(request: &Request) -> Request {
+        let mut buf = [0_u8; MAX_FRAME_LEN];
+        let length = encode_frame(request, &mut buf).unwrap();
+
+        // Frames contain exactly one zero, at the very end.
+        assert_eq!(buf[..length].iter().position(|b| *b == 0), Some(length - 1));
+
+        let mut decoder = FrameDecoder::new();
+        let mut decoded = None;
+
+        for byte in &buf[..length] {
+            if let Some(result) = decoder.feed(*byte) {
+                decoded = Some(result.unwrap());
+            }
+        }
+
+        decoded.unwrap()
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        let requests = [
+            Request::Hello,
+            Request::SetKey {
+                layer: 1,
+                key: 13,
+                config: KeyConfig {
+                    on_press: Some(KeyAction::Keyboard([0; 8])),
+                    on_hold: None,
+                    colors: [0, 1, 2, 3, 4, 5],
+                    tapping_term: Some(300),
+                    on_taps: [None, Some(KeyAction::Macro(2))],
+                    on_tap_hold: None,
+                    repeat: true,
+                },
+            },
+            Request::SetLayer {
+                layer: 5,
+                config: Some(LayerConfig::default()),
+            },
+            Request::SetLayerName {
+                layer: 0,
+                name: String::from("Editing"),
+            },
+            Request::SetMacro {
+                index: 15,
+                steps: Macro::from_slice(&[MacroStep::Tap(0x04), MacroStep::Delay(500)]).unwrap(),
+            },
+            Request::SetUnicode(Some(UnicodeMode::WinCompose)),
+        ];
+
+        for request in &requests {
+            assert_eq!(&round_trip(request), request);
+        }
+    }
+
+    #[test]
+    fn largest_layer_fits() {
+        let action = Some(KeyAction::Keyboard([0xFF; 8]));
+
+        let key = KeyConfig {
+            on_press: action,
+            on_hold: action,
+            colors: [0xFF; 6],
+            tapping_term: Some(u16::MAX),
+            on_taps: [action; MAX_TAPS - 1],
+            on_tap_hold: action,
+            repeat: true,
+        };
+
+        let layer = LayerConfig {
+            name: String::from("0123456789abcdef"),
+            keys: core::array::from_fn(|_| key.clone()),
+            combos: Vec::from_slice(
+                &[Combo {
+                    keys: u16::MAX,
+                    action: KeyAction::Keyboard([0xFF; 8]),
+                }; MAX_COMBOS],
+            )
+            .unwrap(),
+        };
+
+        let request = Request::SetLayer {
+            layer: 0,
+            config: Some(layer.clone()),
+        };
+        assert_eq!(round_trip(&request), request);
+
+        let mut buf = [0_u8; MAX_FRAME_LEN];
+        assert!(encode_frame(&Response::Layer(Some(layer)), &mut buf).is_ok());
+    }
+
+    #[test]
+    fn rejects_bad_frames() {
+        let mut buf = [0_u8; MAX_FRAME_LEN];
+        let length = encode_frame(&Request::Commit, &mut buf).unwrap();
+
+        let mut corrupt = buf;
+        corrupt[length - 2] ^= 0x40;
+        let result = decode_frame::<Request>(&mut corrupt[..length - 1]);
+        assert_eq!(result, Err(FrameError::BadChecksum));
+
+        // A frame from a future protocol version, with a valid checksum.
+        let mut raw = [99, 9, 0, 0, 0, 0];
+        let crc = crc32(&raw[..2]);
+        raw[2..].copy_from_slice(&crc.to_le_bytes());
+
+        let mut frame = [0_u8; 16];
+        let length = cobs::encode(&raw, &mut frame);
+        let result = decode_frame::<Request>(&mut frame[..length]);
+        assert_eq!(result, Err(FrameError::UnsupportedVersion(99)));
+
+        assert_eq!(
+            decode_frame::<Request>(&mut [1, 1]),
+            Err(FrameError::Malformed)
+        );
+    }
+
+    #[test]
+    fn decoder_resynchronises() {
+        let mut decoder = FrameDecoder::new();
+
+        for _ in 0..MAX_FRAME_LEN + 10 {
+            assert_eq!(decoder.feed::<Request>(0xAA), None);
+        }
+
+        assert_eq!(decoder.feed::<Request>(0), Some(Err(FrameError::TooLarge)));
+        assert_eq!(round_trip(&Request::Revert), Request::Revert);
+    }
+
+    #[test]
+    fn garbage_never_panics() {
+        // Cheap stand-in for a fuzzer: xorshift-generated noise, with zeros
+        // sprinkled in often enough to produce lots of short frames.
+        let mut state = 0x2545_F491_u32;
+        let mut decoder = FrameDecoder::new();
+
+        for _ in 0..200_000 {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+
+            let byte = match state % 23 {
+                0 => 0,
+                _ => (state >> 8) as u8,
+            };
+
+            let _ = decoder.feed::<Request>(byte);
+        }
+    }
+
+    #[test]
+    fn requests_edit_config() {
+        let mut flash = Flash::new();
+        let mut config = Config::default();
+
+        let key = KeyConfig {
+            colors: [9; 6],
+            ..Default::default()
+        };
+        let steps = Macro::from_slice(&[MacroStep::Tap(0x04)]).unwrap();
+        let tapping = Tapping {
+            term: 150,
+            hold_on_other_key_press: true,
+            ..Default::default()
+        };
+        let debounce = Debounce {
+            algorithm: Algorithm::EagerPerKey,
+            time: 8,
+        };
+        let repeat = Repeat {
+            delay: 250,
+            rate: 30,
+        };
+
+        // Run in order, so each set is followed by a get that reads it back.
+        let exchanges = [
+            (
+                Request::GetKey { layer: 0, key: 0 },
+                Response::Error(RequestError::EmptyLayer),
+            ),
+            (
+                Request::SetLayer {
+                    layer: 0,
+                    config: Some(LayerConfig::default()),
+                },
+                Response::Ok,
+            ),
+            (
+                Request::SetKey {
+                    layer: 0,
+                    key: 3,
+                    config: key.clone(),
+                },
+                Response::Ok,
+            ),
+            (Request::GetKey { layer: 0, key: 3 }, Response::Key(key)),
+            (
+                Request::GetKey { layer: 0, key: 14 },
+                Response::Error(RequestError::NoSuchKey),
+            ),
+            (
+                Request::GetLayer { layer: 6 },
+                Response::Error(RequestError::NoSuchLayer),
+            ),
+            (
+                Request::SetMacro {
+                    index: 2,
+                    steps: steps.clone(),
+                },
+                Response::Ok,
+            ),
+            (Request::GetMacro { index: 2 }, Response::Macro(steps)),
+            (
+                Request::GetMacro { index: 16 },
+                Response::Error(RequestError::NoSuchMacro),
+            ),
+            (Request::SetTapping(tapping), Response::Ok),
+            (Request::GetTapping, Response::Tapping(tapping)),
+            (Request::SetDebounce(debounce), Response::Ok),
+            (Request::GetDebounce, Response::Debounce(debounce)),
+            (Request::SetRepeat(repeat), Response::Ok),
+            (Request::GetRepeat, Response::Repeat(repeat)),
+        ];
+
+        for (request, expected) in exchanges {
+            let response = respond(request.clone(), &mut config, &mut flash, Stats::default());
+            assert_eq!(response, expected, "{request:?}");
+        }
+    }
+
+    #[test]
+    fn rejects_out_of_range_values() {
+        let mut flash = Flash::new();
+        let mut config = Config::default();
+        config.layers[0] = Some(LayerConfig::default());
+
+        let before = config.clone();
+
+        let key = |action| KeyConfig {
+            on_hold: Some(action),
+            ..Default::default()
+        };
+        let mut layer = LayerConfig::default();
+        layer
+            .combos
+            .push(Combo {
+                keys: 0b11,
+                action: KeyAction::Text(8),
+            })
+            .unwrap();
+        let mut indicators = [None; NUM_LEDS];
+        indicators[0] = Some(Indicator {
+            key: Some(16),
+            ..Default::default()
+        });
+
+        let requests = [
+            (
+                Request::SetBrightness(Brightness {
+                    keypad: 101,
+                    display: 0,
+                }),
+                RequestError::OutOfRange,
+            ),
+            (
+                Request::SetKey {
+                    layer: 0,
+                    key: 0,
+                    config: key(KeyAction::Layer(LayerAction::Momentary(6))),
+                },
+                RequestError::NoSuchLayer,
+            ),
+            (
+                Request::SetKey {
+                    layer: 0,
+                    key: 0,
+                    config: key(KeyAction::Macro(16)),
+                },
+                RequestError::NoSuchMacro,
+            ),
+            (
+                Request::SetLayer {
+                    layer: 1,
+                    config: Some(layer),
+                },
+                RequestError::NoSuchText,
+            ),
+            (Request::SetIndicators(indicators), RequestError::NoSuchKey),
+        ];
+
+        for (request, error) in requests {
+            let response = respond(request.clone(), &mut config, &mut flash, Stats::default());
+            assert_eq!(response, Response::Error(error), "{request:?}");
+        }
+
+        assert_eq!(config, before);
+    }
+
+    #[test]
+    fn commit_and_revert() {
+        let mut flash = Flash::new();
+        let mut config = Config::default();
+
+        let response = respond(Request::Revert, &mut config, &mut flash, Stats::default());
+        assert_eq!(response, Response::Error(RequestError::Storage));
+
+        let brightness = Brightness {
+            keypad: 50,
+            display: 25,
+        };
+        respond(
+            Request::SetBrightness(brightness),
+            &mut config,
+            &mut flash,
+            Stats::default(),
+        );
+        assert_eq!(
+            respond(Request::Commit, &mut config, &mut flash, Stats::default()),
+            Response::Ok
+        );
+
+        respond(
+            Request::SetBrightness(Brightness::default()),
+            &mut config,
+            &mut flash,
+            Stats::default(),
+        );
+        assert_eq!(
+            respond(Request::Revert, &mut config, &mut flash, Stats::default()),
+            Response::Ok
+        );
+
+        let response = respond(
+            Request::GetBrightness,
+            &mut config,
+            &mut flash,
+            Stats::default(),
+        );
+        assert_eq!(response, Response::Brightness(brightness));
+    }
+
+    #[test]
+    fn reports_stats() {
+        let stats = Stats {
+            sent: 10,
+            overflows: 2,
+            ..Default::default()
+        };
+
+        let response = respond(
+            Request::GetStats,
+            &mut Config::default(),
+            &mut Flash::new(),
+            stats,
+        );
+        assert_eq!(response, Response::Stats(stats));
+    }
+}
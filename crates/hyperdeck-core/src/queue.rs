@@ -0,0 +1,164 @@
+//! Bounded queue for outgoing HID reports.
+//!
+//! An interrupt endpoint holds one report at a time, so anything sent while the
+//! host hasn't collected the last one would be lost. Reports wait here instead,
+//! and the USB interrupt hands them over one by one as the endpoint frees up.
+//!
+//! When the queue is full, [`ReportQueue::push`] refuses the report rather than
+//! dropping an older one, and counts an overflow. Everything that sends reports
+//! keeps its own state and tries again later, so a refusal only delays a press or
+//! release; the [`Stats`] show how often that happens. Senders stop taking new
+//! input while they're backed up, but if they fall too far behind anyway they
+//! count what they had to give up in [`Stats::dropped`].
+
+use heapless::Deque;
+use serde::{Deserialize, Serialize};
+
+/// What happened to a report handed to the USB stack.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Delivery {
+    Sent,
+    /// The endpoint is still busy with the last report.
+    Busy,
+    /// The report can't be sent at all, such as a boot report after the host
+    /// switched back to the report protocol.
+    Rejected,
+}
+
+/// Counters kept since the device started.
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Stats {
+    /// Reports handed to the USB stack.
+    pub sent: u32,
+    /// Reports the USB stack refused outright, which were dropped.
+    pub rejected: u32,
+    /// Times a report was refused because the queue was full.
+    pub overflows: u32,
+    /// The most reports that have been waiting at once.
+    pub peak: u16,
+    /// Key or media changes merged into a later one because their sender was too
+    /// far behind, any of which may have been a lost tap. Filled in by the caller.
+    pub dropped: u32,
+}
+
+pub struct ReportQueue<T, const N: usize> {
+    reports: Deque<T, N>,
+    stats: Stats,
+}
+
+impl<T, const N: usize> ReportQueue<T, N> {
+    pub const fn new() -> Self {
+        Self {
+            reports: Deque::new(),
+            stats: Stats {
+                sent: 0,
+                rejected: 0,
+                overflows: 0,
+                peak: 0,
+                dropped: 0,
+            },
+        }
+    }
+
+    /// Adds `report` to the back of the queue, returning whether there was room.
+    pub fn push(&mut self, report: T) -> bool {
+        if self.reports.push_back(report).is_err() {
+            self.stats.overflows = self.stats.overflows.wrapping_add(1);
+            return false;
+        }
+
+        self.stats.peak = self.stats.peak.max(self.reports.len() as u16);
+        true
+    }
+
+    /// Hands reports to `send`, oldest first, until the queue is empty or the
+    /// endpoint is busy.
+    pub fn drain(&mut self, mut send: impl FnMut(&T) -> Delivery) {
+        while let Some(report) = self.reports.front() {
+            match send(report) {
+                Delivery::Sent => self.stats.sent = self.stats.sent.wrapping_add(1),
+                Delivery::Rejected => self.stats.rejected = self.stats.rejected.wrapping_add(1),
+                Delivery::Busy => return,
+            }
+
+            self.reports.pop_front();
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.reports.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.reports.is_empty()
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.stats
+    }
+}
+
+impl<T, const N: usize> Default for ReportQueue<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use super::*;
+
+    #[test]
+    fn drains_in_order_while_the_endpoint_is_free() {
+        let mut queue = ReportQueue::<u8, 4>::new();
+        let mut sent = Vec::new();
+
+        (1..=3).for_each(|report| assert!(queue.push(report)));
+
+        // The endpoint takes one report, then stays busy.
+        queue.drain(|report| match sent.is_empty() {
+            true => {
+                sent.push(*report);
+                Delivery::Sent
+            }
+            false => Delivery::Busy,
+        });
+        assert_eq!(sent, [1]);
+        assert_eq!(queue.len(), 2);
+
+        queue.drain(|report| {
+            sent.push(*report);
+            Delivery::Sent
+        });
+        assert_eq!(sent, [1, 2, 3]);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn counts_overflows_and_rejections() {
+        let mut queue = ReportQueue::<u8, 2>::new();
+
+        assert!(queue.push(1));
+        assert!(queue.push(2));
+        assert!(!queue.push(3));
+        assert!(!queue.push(3));
+
+        queue.drain(|report| match report {
+            1 => Delivery::Rejected,
+            _ => Delivery::Sent,
+        });
+
+        assert_eq!(
+            queue.stats(),
+            Stats {
+                sent: 1,
+                rejected: 1,
+                overflows: 2,
+                peak: 2,
+                dropped: 0,
+            }
+        );
+    }
+}
@@ -0,0 +1,398 @@
+//! The screens shown on the keypad's display.
+//!
+//! Screens draw onto any [`DrawTarget`], so the firmware renders them into its
+//! frame buffer, while tests and host tools can render them anywhere else.
+
+use core::fmt::Write;
+
+use embedded_graphics::geometry::AnchorPoint;
+use embedded_graphics::pixelcolor::Rgb565;
+use embedded_graphics::prelude::*;
+use embedded_graphics::primitives::{PrimitiveStyle, Rectangle};
+use heapless::{String, Vec};
+use u8g2_fonts::fonts::u8g2_font_profont15_mf as Profont15;
+use u8g2_fonts::fonts::u8g2_font_profont29_mf as Profont29;
+use u8g2_fonts::types::{FontColor, HorizontalAlignment, VerticalPosition};
+use u8g2_fonts::{Error as FontError, FontRenderer};
+
+use crate::config::{Config, MAX_LAYERS, NUM_LEDS};
+use crate::leds::{Led, Leds};
+
+/// Display width, in pixels.
+pub const WIDTH: u16 = 240;
+/// Display height, in pixels.
+pub const HEIGHT: u16 = 135;
+
+/// Accent colors for the home screen, one per layer.
+pub const LAYER_COLORS: [Rgb565; MAX_LAYERS] = [
+    Rgb565::CSS_DODGER_BLUE,
+    Rgb565::CSS_ORANGE,
+    Rgb565::CSS_LIME_GREEN,
+    Rgb565::CSS_MEDIUM_PURPLE,
+    Rgb565::CSS_GOLD,
+    Rgb565::CSS_CRIMSON,
+];
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Screen {
+    /// The wordmark on a field of stars, placed and colored according to `seed`.
+    Splash {
+        seed: u32,
+    },
+    /// Shown while a host tool is in a configuration session.
+    Configuring,
+    Home {
+        layer_id: u8,
+        layer_name: String<16>,
+        layer_color: Rgb565,
+        /// Lit keyboard LEDs to list along the bottom.
+        indicators: Vec<Led, NUM_LEDS>,
+    },
+    Panic {
+        message: String<64>,
+    },
+}
+
+impl Screen {
+    /// The home screen for `layer`, listing the lit indicators that `config`
+    /// wants shown, or `None` if there's no such layer.
+    pub fn home(config: &Config, layer: u8, leds: Leds) -> Option<Self> {
+        let layer_config = config.layers.get(layer as usize)?.as_ref()?;
+
+        Some(Self::Home {
+            layer_id: layer,
+            layer_name: layer_config.name.clone(),
+            layer_color: LAYER_COLORS[layer as usize],
+            indicators: leds.displayed(config).collect(),
+        })
+    }
+
+    /// Clears `target` and draws the screen onto it.
+    pub fn draw<D>(&self, target: &mut D) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = Rgb565>,
+    {
+        match self {
+            Self::Splash { seed } => splash(target, *seed),
+            Self::Configuring => configuring(target),
+            Self::Home {
+                layer_id,
+                layer_name,
+                layer_color,
+                indicators,
+            } => home(target, *layer_id, layer_name, *layer_color, indicators),
+            Self::Panic { message } => panic(target, message),
+        }
+    }
+}
+
+/// Draws `string` centered on `position`.
+fn text<D>(
+    target: &mut D,
+    font: &FontRenderer,
+    string: &str,
+    position: Point,
+    color: Rgb565,
+) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = Rgb565>,
+{
+    let result = font.render_aligned(
+        string,
+        position,
+        VerticalPosition::Center,
+        HorizontalAlignment::Center,
+        FontColor::Transparent(color),
+        target,
+    );
+
+    match result {
+        Ok(_) => Ok(()),
+        Err(FontError::DisplayError(e)) => Err(e),
+        // Anything else is a glyph missing from the font, which just gets skipped.
+        Err(_) => Ok(()),
+    }
+}
+
+/// A xorshift generator, so the same seed always draws the same splash screen.
+struct Random(u32);
+
+impl Random {
+    fn new(seed: u32) -> Self {
+        // Zero is the one state xorshift can't leave.
+        Self(seed.max(1))
+    }
+
+    /// A number in `[0, max)`.
+    fn below(&mut self, max: u32) -> u32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        self.0 % max
+    }
+}
+
+fn splash<D>(target: &mut D, seed: u32) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = Rgb565>,
+{
+    let mut random = Random::new(seed);
+
+    target.clear(Rgb565::BLACK)?;
+
+    // Draw 255 random white pixels (stars) on the background.
+    for _ in 0..255 {
+        let x = random.below(WIDTH as u32) as i32;
+        let y = random.below(HEIGHT as u32) as i32;
+
+        Pixel(Point::new(x, y), Rgb565::WHITE).draw(target)?;
+    }
+
+    // Randomly pick an accent color combo for the wordmark.
+    let (color_a, color_b) = match random.below(4) {
+        0 => (Rgb565::CSS_DARK_BLUE, Rgb565::CSS_DARK_RED),
+        1 => (Rgb565::CSS_DARK_BLUE, Rgb565::CSS_DARK_GOLDENROD),
+        2 => (Rgb565::CSS_PURPLE, Rgb565::CSS_DARK_GREEN),
+        _ => (Rgb565::CSS_PURPLE, Rgb565::CSS_DARK_CYAN),
+    };
+
+    let bounds = target.bounding_box().offset(-20);
+    let font = FontRenderer::new::<Profont29>();
+
+    text(target, &font, "HYPERDECK", Point::new(119, 60), color_a)?;
+    text(target, &font, "HYPERDECK", Point::new(119, 75), color_b)?;
+    text(
+        target,
+        &font,
+        "HYPERDECK",
+        bounds.anchor_point(AnchorPoint::Center),
+        Rgb565::WHITE,
+    )
+}
+
+fn configuring<D>(target: &mut D) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = Rgb565>,
+{
+    target.clear(Rgb565::BLACK)?;
+
+    let bounds = target.bounding_box().offset(-20);
+
+    let lg_font = FontRenderer::new::<Profont29>();
+    let sm_font = FontRenderer::new::<Profont15>();
+
+    text(
+        target,
+        &lg_font,
+        "CONFIGURING...",
+        bounds.anchor_point(AnchorPoint::Center),
+        Rgb565::WHITE,
+    )?;
+    text(
+        target,
+        &sm_font,
+        "Host tool connected",
+        bounds.anchor_point(AnchorPoint::BottomCenter),
+        Rgb565::CSS_LIGHT_GRAY,
+    )
+}
+
+fn home<D>(
+    target: &mut D,
+    layer_id: u8,
+    layer_name: &str,
+    layer_color: Rgb565,
+    indicators: &[Led],
+) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = Rgb565>,
+{
+    target.clear(Rgb565::BLACK)?;
+
+    let bounds = target.bounding_box().offset(-20);
+
+    let lg_font = FontRenderer::new::<Profont29>();
+    let sm_font = FontRenderer::new::<Profont15>();
+
+    // Accent bar along the top, in the layer's color.
+    Rectangle::new(Point::zero(), Size::new(WIDTH as u32, 8))
+        .into_styled(PrimitiveStyle::with_fill(layer_color))
+        .draw(target)?;
+
+    let mut caption: String<16> = String::new();
+    let _ = write!(&mut caption, "LAYER {layer_id}");
+
+    text(
+        target,
+        &sm_font,
+        &caption,
+        bounds.anchor_point(AnchorPoint::TopCenter),
+        layer_color,
+    )?;
+    text(
+        target,
+        &lg_font,
+        layer_name,
+        bounds.anchor_point(AnchorPoint::Center),
+        Rgb565::WHITE,
+    )?;
+
+    // Lit keyboard LEDs along the bottom, like "CAPS NUM".
+    let mut labels: String<32> = String::new();
+
+    for (i, led) in indicators.iter().enumerate() {
+        let _ = write!(
+            &mut labels,
+            "{}{}",
+            if i == 0 { "" } else { " " },
+            led.label()
+        );
+    }
+
+    text(
+        target,
+        &sm_font,
+        &labels,
+        bounds.anchor_point(AnchorPoint::BottomCenter),
+        Rgb565::CSS_LIGHT_GRAY,
+    )
+}
+
+fn panic<D>(target: &mut D, message: &str) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = Rgb565>,
+{
+    target.clear(Rgb565::CSS_DARK_RED)?;
+
+    let bounds = target.bounding_box().offset(-20);
+
+    let lg_font = FontRenderer::new::<Profont29>();
+    let sm_font = FontRenderer::new::<Profont15>();
+
+    text(
+        target,
+        &lg_font,
+        "SYSTEM PANIC",
+        bounds.anchor_point(AnchorPoint::TopCenter),
+        Rgb565::WHITE,
+    )?;
+    text(
+        target,
+        &sm_font,
+        "Hyperdeck firmware halted. \n Power cycle to reset.",
+        bounds.anchor_point(AnchorPoint::Center),
+        Rgb565::WHITE,
+    )?;
+    text(
+        target,
+        &sm_font,
+        message,
+        bounds.anchor_point(AnchorPoint::BottomCenter),
+        Rgb565::WHITE,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Indicator, LayerConfig};
+    use crate::mock::Canvas;
+
+    fn draw(screen: &Screen) -> Canvas {
+        let mut canvas = Canvas::new();
+        screen.draw(&mut canvas).unwrap();
+        canvas
+    }
+
+    #[test]
+    fn splash_is_seeded() {
+        let a = draw(&Screen::Splash { seed: 7 });
+        let b = draw(&Screen::Splash { seed: 7 });
+        let c = draw(&Screen::Splash { seed: 8 });
+
+        assert!(a.pixels == b.pixels);
+        assert!(a.pixels != c.pixels);
+        // The stars, plus the white wordmark
+        assert!(a.count(Rgb565::WHITE) > 255);
+    }
+
+    #[test]
+    fn home() {
+        let mut config = Config::default();
+
+        config.layers[2] = Some(LayerConfig {
+            name: "Media".into(),
+            ..Default::default()
+        });
+        config.indicators[Led::CapsLock as usize] = Some(Indicator {
+            key: None,
+            color: [255, 0, 0],
+            display: true,
+        });
+
+        assert_eq!(Screen::home(&config, 1, Leds(0)), None);
+
+        let screen = Screen::home(&config, 2, Leds(0b11)).unwrap();
+        let Screen::Home {
+            layer_id,
+            ref layer_name,
+            layer_color,
+            ref indicators,
+        } = screen
+        else {
+            panic!("not a home screen: {screen:?}");
+        };
+
+        assert_eq!(layer_id, 2);
+        assert_eq!(layer_name, "Media");
+        assert_eq!(layer_color, LAYER_COLORS[2]);
+        // Num lock is lit, but not configured to be shown.
+        assert_eq!(indicators[..], [Led::CapsLock]);
+
+        let canvas = draw(&screen);
+
+        // The accent bar...
+        assert_eq!(canvas.pixel(0, 0), LAYER_COLORS[2]);
+        assert_eq!(canvas.pixel(WIDTH - 1, 7), LAYER_COLORS[2]);
+        assert_eq!(canvas.pixel(0, 8), Rgb565::BLACK);
+        // ...the name, and the indicators.
+        assert!(canvas.count(Rgb565::WHITE) > 0);
+        assert!(canvas.count(Rgb565::CSS_LIGHT_GRAY) > 0);
+    }
+
+    #[test]
+    fn screens_fit_the_display() {
+        let screens = [
+            Screen::Splash { seed: 1 },
+            Screen::Configuring,
+            Screen::Home {
+                layer_id: 5,
+                layer_name: "Media".into(),
+                layer_color: LAYER_COLORS[5],
+                indicators: Led::ALL.into_iter().collect(),
+            },
+            Screen::Panic {
+                message: "Location: src/main.rs:1:1\nMessage: N/A".into(),
+            },
+        ];
+
+        for screen in &screens {
+            assert_eq!(draw(screen).clipped, 0, "{screen:?}");
+        }
+    }
+
+    #[test]
+    fn screens_clear_what_came_before() {
+        let mut canvas = Canvas::new();
+
+        Screen::Panic {
+            message: String::new(),
+        }
+        .draw(&mut canvas)
+        .unwrap();
+        assert_eq!(canvas.pixel(0, 0), Rgb565::CSS_DARK_RED);
+
+        Screen::Configuring.draw(&mut canvas).unwrap();
+        assert_eq!(canvas.count(Rgb565::CSS_DARK_RED), 0);
+    }
+}
@@ -0,0 +1,386 @@
+//! Cooperative driver for configuration sessions.
+//!
+//! [`Session::poll`] does a bounded amount of work and returns straight away, so it
+//! can be called from the main loop alongside the keypad scan without starving it.
+//! Each call either pushes out more of the previous response or handles at most
+//! one incoming request; a new request is never read while a response is still
+//! being sent.
+
+use embedded_storage::nor_flash::NorFlash;
+
+use crate::config::Config;
+use crate::protocol::{self, FrameDecoder, Request, Response, MAX_FRAME_LEN};
+use crate::queue::Stats;
+use crate::time::{Duration, Instant};
+
+/// A non-blocking byte stream to the host, such as the CDC serial port.
+pub trait Transport {
+    /// Reads whatever is available into `buf`, returning the number of bytes read.
+    fn read(&mut self, buf: &mut [u8]) -> usize;
+
+    /// Writes as much of `buf` as possible, returning the number of bytes written.
+    fn write(&mut self, buf: &[u8]) -> usize;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Event {
+    /// The host sent its first request. If that request changed the working
+    /// configuration, [`Event::Changed`] follows on the next poll.
+    Started,
+    /// The working configuration was modified.
+    Changed,
+    /// The host committed, reverted, or went quiet for [`Session::TIMEOUT`].
+    Ended,
+}
+
+pub struct Session {
+    decoder: FrameDecoder,
+    inbox: [u8; 64],
+    inbox_pos: usize,
+    inbox_len: usize,
+    outbox: [u8; MAX_FRAME_LEN],
+    outbox_pos: usize,
+    outbox_len: usize,
+    active: bool,
+    last_seen: Instant,
+    /// An event held back for the next poll, since each poll returns only one.
+    pending: Option<Event>,
+}
+
+impl Session {
+    /// How long the host may stay silent before the session is considered over.
+    ///
+    /// Uncommitted changes are left in place when this happens.
+    pub const TIMEOUT: Duration = Duration::secs(3);
+}
+
+impl Session {
+    pub const fn new() -> Self {
+        Self {
+            decoder: FrameDecoder::new(),
+            inbox: [0; 64],
+            inbox_pos: 0,
+            inbox_len: 0,
+            outbox: [0; MAX_FRAME_LEN],
+            outbox_pos: 0,
+            outbox_len: 0,
+            active: false,
+            last_seen: Instant::from_ticks(0),
+            pending: None,
+        }
+    }
+
+    /// Whether a host is currently talking to us.
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    /// Advances the session by one step. `stats` are reported to hosts that ask.
+    pub fn poll<T, F>(
+        &mut self,
+        transport: &mut T,
+        config: &mut Config,
+        flash: &mut F,
+        stats: Stats,
+        now: Instant,
+    ) -> Option<Event>
+    where
+        T: Transport,
+        F: NorFlash,
+    {
+        if let Some(event) = self.pending.take() {
+            return Some(event);
+        }
+
+        if self.outbox_pos < self.outbox_len {
+            self.outbox_pos += transport.write(&self.outbox[self.outbox_pos..self.outbox_len]);
+            return None;
+        }
+
+        if self.inbox_pos == self.inbox_len {
+            self.inbox_pos = 0;
+            self.inbox_len = transport.read(&mut self.inbox);
+        }
+
+        while self.inbox_pos < self.inbox_len {
+            let byte = self.inbox[self.inbox_pos];
+            self.inbox_pos += 1;
+
+            if let Some(request) = self.decoder.feed::<Request>(byte) {
+                return self.handle(request, transport, config, flash, stats, now);
+            }
+        }
+
+        if self.active && now - self.last_seen >= Self::TIMEOUT {
+            self.active = false;
+            self.decoder = FrameDecoder::new();
+
+            return Some(Event::Ended);
+        }
+
+        None
+    }
+
+    fn handle<T, F>(
+        &mut self,
+        request: Result<Request, protocol::FrameError>,
+        transport: &mut T,
+        config: &mut Config,
+        flash: &mut F,
+        stats: Stats,
+        now: Instant,
+    ) -> Option<Event>
+    where
+        T: Transport,
+        F: NorFlash,
+    {
+        use Request::*;
+
+        let request = match request {
+            Ok(request) => request,
+            Err(err) => {
+                self.queue_response(&Response::Error(err.into()), transport);
+                return None;
+            }
+        };
+
+        let event = match &request {
+            Commit | Revert => Some(Event::Ended),
+            SetKey { .. }
+            | SetLayer { .. }
+            | SetLayerName { .. }
+            | SetBrightness(_)
+            | SetMacro { .. }
+            | SetText { .. }
+            | SetLayout(_)
+            | SetUnicode(_)
+            | SetIndicators(_)
+            | SetTapping(_)
+            | SetDebounce(_)
+            | SetRepeat(_) => Some(Event::Changed),
+            _ => None,
+        };
+
+        let response = protocol::respond(request, config, flash, stats);
+        self.queue_response(&response, transport);
+
+        self.last_seen = now;
+
+        let ended = event == Some(Event::Ended);
+        let started = !core::mem::replace(&mut self.active, !ended);
+
+        match started && !ended {
+            true => {
+                self.pending = event;
+                Some(Event::Started)
+            }
+            false => event,
+        }
+    }
+
+    fn queue_response<T: Transport>(&mut self, response: &Response, transport: &mut T) {
+        // Every response fits in a frame; if one somehow doesn't, the host will time out.
+        self.outbox_len = protocol::encode_frame(response, &mut self.outbox).unwrap_or(0);
+        self.outbox_pos = transport.write(&self.outbox[..self.outbox_len]);
+    }
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use super::*;
+    use crate::config::Brightness;
+    use crate::mock::MemFlash;
+
+    /// Serial port stand-in that only accepts `write_limit` bytes per call.
+    struct FakePort {
+        rx: VecDeque<u8>,
+        tx: Vec<u8>,
+        write_limit: usize,
+    }
+
+    impl FakePort {
+        fn new(write_limit: usize) -> Self {
+            Self {
+                rx: VecDeque::new(),
+                tx: Vec::new(),
+                write_limit,
+            }
+        }
+
+        fn send(&mut self, request: &Request) {
+            let mut frame = [0_u8; MAX_FRAME_LEN];
+            let length = protocol::encode_frame(request, &mut frame).unwrap();
+            self.rx.extend(&frame[..length]);
+        }
+
+        fn responses(&mut self) -> Vec<Response> {
+            let mut decoder = FrameDecoder::new();
+
+            self.tx
+                .drain(..)
+                .filter_map(|byte| decoder.feed(byte))
+                .map(Result::unwrap)
+                .collect()
+        }
+    }
+
+    impl Transport for FakePort {
+        fn read(&mut self, buf: &mut [u8]) -> usize {
+            let count = buf.len().min(self.rx.len());
+
+            for (slot, byte) in buf.iter_mut().zip(self.rx.drain(..count)) {
+                *slot = byte;
+            }
+
+            count
+        }
+
+        fn write(&mut self, buf: &[u8]) -> usize {
+            let count = buf.len().min(self.write_limit);
+            self.tx.extend(&buf[..count]);
+            count
+        }
+    }
+
+    struct Harness {
+        session: Session,
+        port: FakePort,
+        config: Config,
+        flash: MemFlash<{ 2 * 8192 }>,
+        now: Instant,
+    }
+
+    impl Harness {
+        fn new(write_limit: usize) -> Self {
+            Self {
+                session: Session::new(),
+                port: FakePort::new(write_limit),
+                config: Config::default(),
+                flash: MemFlash::new(),
+                now: Instant::from_ticks(0),
+            }
+        }
+
+        /// Polls until nothing is left to read or write, collecting events.
+        fn run(&mut self) -> Vec<Event> {
+            let mut events = Vec::new();
+
+            for _ in 0..1000 {
+                let event = self.session.poll(
+                    &mut self.port,
+                    &mut self.config,
+                    &mut self.flash,
+                    Stats::default(),
+                    self.now,
+                );
+
+                events.extend(event);
+                self.now += Duration::millis(1);
+            }
+
+            events
+        }
+    }
+
+    #[test]
+    fn serves_requests_in_order() {
+        let mut harness = Harness::new(7);
+
+        let brightness = Brightness {
+            keypad: 40,
+            display: 60,
+        };
+
+        harness.port.send(&Request::Hello);
+        harness.port.send(&Request::SetBrightness(brightness));
+        harness.port.send(&Request::GetBrightness);
+        harness.port.send(&Request::Commit);
+
+        let events = harness.run();
+
+        assert_eq!(events, [Event::Started, Event::Changed, Event::Ended]);
+        assert!(!harness.session.is_active());
+
+        let responses = harness.port.responses();
+
+        assert!(matches!(responses[0], Response::Hello { .. }));
+        assert_eq!(
+            responses[1..],
+            [Response::Ok, Response::Brightness(brightness), Response::Ok,]
+        );
+
+        assert_eq!(
+            Config::load(&mut harness.flash).unwrap().brightness,
+            brightness
+        );
+    }
+
+    #[test]
+    fn reports_changes_that_start_a_session() {
+        let mut harness = Harness::new(64);
+
+        harness.port.send(&Request::SetBrightness(Brightness {
+            keypad: 10,
+            display: 20,
+        }));
+
+        assert_eq!(harness.run(), [Event::Started, Event::Changed]);
+        assert_eq!(harness.config.brightness.keypad, 10);
+    }
+
+    #[test]
+    fn polls_do_bounded_work() {
+        let mut harness = Harness::new(4);
+
+        harness.port.send(&Request::Hello);
+        harness.port.send(&Request::GetBrightness);
+
+        // The first poll reads and answers Hello, but only gets 4 bytes out.
+        let event = harness.session.poll(
+            &mut harness.port,
+            &mut harness.config,
+            &mut harness.flash,
+            Stats::default(),
+            harness.now,
+        );
+
+        assert_eq!(event, Some(Event::Started));
+        assert_eq!(harness.port.tx.len(), 4);
+
+        // GetBrightness is still sitting unparsed in the inbox.
+        assert!(harness.session.inbox_pos < harness.session.inbox_len);
+    }
+
+    #[test]
+    fn times_out() {
+        let mut harness = Harness::new(64);
+
+        harness.port.send(&Request::Hello);
+        assert_eq!(harness.run(), [Event::Started]);
+
+        harness.now += Session::TIMEOUT;
+        assert_eq!(harness.run(), [Event::Ended]);
+    }
+
+    #[test]
+    fn reports_bad_frames() {
+        let mut harness = Harness::new(64);
+
+        harness.port.rx.extend([0x03, 0xAA, 0xBB, 0x00]);
+        harness.run();
+
+        let responses = harness.port.responses();
+        assert_eq!(
+            responses,
+            [Response::Error(protocol::RequestError::BadFrame)]
+        );
+    }
+}
@@ -0,0 +1,226 @@
+//! Wear-levelled, power-loss tolerant storage for [`Config`] records.
+//!
+//! The flash region is split into equally sized slots, each large enough to hold
+//! one record. Every write goes to the slot after the newest valid one (wrapping
+//! around), so erases are spread across the whole region and the previous record
+//! is never touched while the new one is being written. If power is lost halfway
+//! through, the torn slot fails its checksum and the previous record wins.
+//!
+//! Each slot is laid out as:
+//!
+//! | Offset | Size | Field                                                    |
+//! |--------|------|----------------------------------------------------------|
+//! | 0      | 4    | Sequence number (little endian)                          |
+//! | 4      | 4    | CRC-32 of the sequence number and record (little endian) |
+//! | 8      | ..   | Record, as written by [`Config::encode`]                 |
+
+use embedded_storage::nor_flash::NorFlash;
+
+use crate::config::{Config, Error};
+use crate::crc::Crc32;
+
+const SLOT_HEADER_LEN: usize = 8;
+const BUF_LEN: usize = SLOT_HEADER_LEN + Config::MAX_ENCODED_LEN;
+/// How much of a freshly written slot is read back at a time to verify it.
+const VERIFY_CHUNK: usize = 256;
+
+/// Size of a single slot, in bytes.
+fn slot_len<F: NorFlash>() -> usize {
+    BUF_LEN.next_multiple_of(F::ERASE_SIZE)
+}
+
+/// Number of slots that fit in `flash`.
+pub fn slot_count<F: NorFlash>(flash: &F) -> usize {
+    flash.capacity() / slot_len::<F>()
+}
+
+/// Reads slot `index` into `buf`, returning its sequence number and the length
+/// of the slot's contents if it holds a valid record.
+fn read_slot<F: NorFlash>(
+    flash: &mut F,
+    index: usize,
+    buf: &mut [u8; BUF_LEN],
+) -> Result<(u32, usize), Error> {
+    let offset = (index * slot_len::<F>()) as u32;
+
+    let header_len = (SLOT_HEADER_LEN + Config::HEADER_LEN).next_multiple_of(F::READ_SIZE);
+    flash.read(offset, &mut buf[..header_len])?;
+
+    let length = SLOT_HEADER_LEN
+        + Config::HEADER_LEN
+        + Config::check_header(&buf[SLOT_HEADER_LEN..])?;
+
+    flash.read(offset, &mut buf[..length.next_multiple_of(F::READ_SIZE)])?;
+
+    let crc = u32::from_le_bytes([buf[4], buf[5], buf[6], buf[7]]);
+
+    if slot_crc(&buf[..length]) != crc {
+        return Err(Error::BadChecksum);
+    }
+
+    Ok((u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]), length))
+}
+
+/// Checksum over the sequence number and record, skipping the CRC field itself.
+fn slot_crc(slot: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+
+    crc.update(&slot[..4]);
+    crc.update(&slot[SLOT_HEADER_LEN..]);
+
+    crc.finish()
+}
+
+/// Finds the valid slot with the highest sequence number, as `(index, sequence)`.
+fn newest<F: NorFlash>(flash: &mut F, buf: &mut [u8; BUF_LEN]) -> Option<(usize, u32)> {
+    (0..slot_count(flash))
+        .filter_map(|index| read_slot(flash, index, buf).ok().map(|(seq, _)| (index, seq)))
+        .max_by_key(|(_, seq)| *seq)
+}
+
+/// Loads the newest valid record from `flash`.
+///
+/// Fails with [`Error::NoValidRecord`] only if every slot is blank or corrupt.
+pub fn load<F: NorFlash>(flash: &mut F) -> Result<Config, Error> {
+    let mut buf = [0_u8; BUF_LEN];
+
+    let (index, _) = newest(flash, &mut buf).ok_or(Error::NoValidRecord)?;
+    let (_, length) = read_slot(flash, index, &mut buf)?;
+
+    Config::decode(&buf[SLOT_HEADER_LEN..length])
+}
+
+/// Writes `config` into the slot after the newest valid one, leaving the
+/// previous record intact until the new one has been written and verified.
+pub fn store<F: NorFlash>(flash: &mut F, config: &Config) -> Result<(), Error> {
+    let count = slot_count(flash);
+
+    // With a single slot there'd be no known-good copy to fall back on.
+    if count < 2 {
+        return Err(Error::TooLarge);
+    }
+
+    let mut buf = [0xFF_u8; BUF_LEN];
+
+    let (index, seq) = match newest(flash, &mut buf) {
+        Some((index, seq)) => ((index + 1) % count, seq.wrapping_add(1)),
+        None => (0, 0),
+    };
+
+    buf.fill(0xFF);
+
+    let length = SLOT_HEADER_LEN + config.encode(&mut buf[SLOT_HEADER_LEN..])?;
+
+    buf[..4].copy_from_slice(&seq.to_le_bytes());
+    let crc = slot_crc(&buf[..length]);
+    buf[4..8].copy_from_slice(&crc.to_le_bytes());
+
+    let offset = (index * slot_len::<F>()) as u32;
+    let length = length.next_multiple_of(F::WRITE_SIZE);
+
+    flash.erase(offset, offset + slot_len::<F>() as u32)?;
+    flash.write(offset, &buf[..length])?;
+
+    // Read back what we just wrote before calling it committed, a chunk at a time
+    // so there's no second full-sized buffer on the stack.
+    let mut check = [0_u8; VERIFY_CHUNK];
+
+    for (i, expected) in buf[..length].chunks(VERIFY_CHUNK).enumerate() {
+        let written = &mut check[..expected.len()];
+        flash.read(offset + (i * VERIFY_CHUNK) as u32, written)?;
+
+        if written != expected {
+            return Err(Error::BadChecksum);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use heapless::String;
+
+    use super::*;
+    use crate::config::LayerConfig;
+    use crate::mock::MemFlash;
+
+    const SLOT: usize = 8192;
+
+    type Flash = MemFlash<{ 4 * SLOT }>;
+
+    fn named(name: &str) -> Config {
+        let mut config = Config::default();
+
+        config.layers[0] = Some(LayerConfig {
+            name: String::from(name),
+            ..Default::default()
+        });
+
+        config
+    }
+
+    fn seq(flash: &Flash, index: usize) -> u32 {
+        let bytes = &flash.data[index * SLOT..index * SLOT + 4];
+        u32::from_le_bytes(bytes.try_into().unwrap())
+    }
+
+    #[test]
+    fn blank_flash() {
+        let mut flash = Flash::new();
+
+        assert_eq!(slot_count(&flash), 4);
+        assert_eq!(load(&mut flash), Err(Error::NoValidRecord));
+    }
+
+    #[test]
+    fn rotates_through_slots() {
+        let mut flash = Flash::new();
+
+        for i in 0..6 {
+            store(&mut flash, &named(&format!("Layer {i}"))).unwrap();
+            assert_eq!(load(&mut flash), Ok(named(&format!("Layer {i}"))));
+        }
+
+        // Six writes over four slots: the first two were overwritten in turn.
+        assert_eq!([0, 1, 2, 3].map(|i| seq(&flash, i)), [4, 5, 2, 3]);
+    }
+
+    #[test]
+    fn torn_write_falls_back() {
+        let mut flash = Flash::new();
+
+        store(&mut flash, &named("Old")).unwrap();
+        store(&mut flash, &named("New")).unwrap();
+
+        // Simulate losing power partway through programming "New".
+        flash.data[SLOT + 64..2 * SLOT].fill(0xFF);
+        assert_eq!(load(&mut flash), Ok(named("Old")));
+
+        // The next write must not clobber the surviving record.
+        store(&mut flash, &named("Newer")).unwrap();
+        assert_eq!(load(&mut flash), Ok(named("Newer")));
+        assert_eq!(seq(&flash, 0), 0);
+    }
+
+    #[test]
+    fn corrupt_newest_falls_back() {
+        let mut flash = Flash::new();
+
+        store(&mut flash, &named("Old")).unwrap();
+        store(&mut flash, &named("New")).unwrap();
+
+        flash.data[SLOT + 20] ^= 0x01;
+        assert_eq!(load(&mut flash), Ok(named("Old")));
+
+        flash.data[SLOT + 20] ^= 0x01;
+        assert_eq!(load(&mut flash), Ok(named("New")));
+
+        // A sequence number mangled without touching the record is caught too.
+        flash.data[SLOT] ^= 0x80;
+        assert_eq!(load(&mut flash), Ok(named("Old")));
+
+        flash.data[20] ^= 0x01;
+        assert_eq!(load(&mut flash), Err(Error::NoValidRecord));
+    }
+}
@@ -0,0 +1,61 @@
+//! Time types shared with the firmware.
+//!
+//! These match the RP2040 timer's 1 MHz tick, so [`Instant`]s from the hardware
+//! can be passed straight through.
+
+use core::cell::Cell;
+
+pub type Instant = fugit::TimerInstantU64<1_000_000>;
+pub type Duration = fugit::TimerDurationU64<1_000_000>;
+
+/// A monotonic source of [`Instant`]s.
+pub trait Clock {
+    fn now(&self) -> Instant;
+
+    /// Busy-waits for `duration` to pass.
+    fn wait(&self, duration: Duration) {
+        let start = self.now();
+        while self.now() - start < duration {}
+    }
+}
+
+/// A [`Clock`] that only moves when it's told to, so time-dependent code can
+/// be run off the device.
+pub struct FakeClock {
+    now: Cell<Instant>,
+}
+
+impl FakeClock {
+    /// Starts the clock at zero.
+    pub const fn new() -> Self {
+        Self {
+            now: Cell::new(Instant::from_ticks(0)),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        self.now.set(self.now.get() + duration);
+    }
+
+    /// Advances the clock by `ms` milliseconds.
+    pub fn advance_ms(&self, ms: u64) {
+        self.advance(Duration::millis(ms));
+    }
+}
+
+impl Default for FakeClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for FakeClock {
+    fn now(&self) -> Instant {
+        self.now.get()
+    }
+
+    /// Waits without waiting, by moving the clock forward.
+    fn wait(&self, duration: Duration) {
+        self.advance(duration);
+    }
+}
@@ -0,0 +1,229 @@
+//! Entry of arbitrary Unicode characters through the host's input methods.
+//!
+//! There's no standard way to send a character that isn't on the host's keyboard
+//! layout, but every major OS has some form of hex code point entry. None of them
+//! are on by default, so the host has to be set up for whichever
+//! [`UnicodeMode`] the keypad is configured to use.
+//!
+//! Hex digits sit in the same place on every [`Layout`](crate::layout::Layout),
+//! so the sequences don't depend on it.
+
+use heapless::Vec;
+use serde::{Deserialize, Serialize};
+
+use crate::config::MacroStep;
+
+const LEFT_CTRL: u8 = 0xE0;
+const LEFT_SHIFT: u8 = 0xE1;
+const LEFT_ALT: u8 = 0xE2;
+const RIGHT_ALT: u8 = 0xE6;
+
+const U: u8 = 0x18;
+const ENTER: u8 = 0x28;
+const SPACE: u8 = 0x2C;
+const KEYPAD_PLUS: u8 = 0x57;
+
+/// Maximum number of steps needed to enter one character.
+pub const MAX_STEPS: usize = 16;
+
+/// The steps that enter a single character.
+pub type Steps = Vec<MacroStep, MAX_STEPS>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UnicodeMode {
+    /// IBus and GTK: Ctrl+Shift+U, the code point in hex, then space.
+    Linux,
+    /// The "Unicode Hex Input" input source: Option held while typing each
+    /// UTF-16 code unit in hex.
+    MacOs,
+    /// Alt held while typing keypad + and the code point in hex, with the hex
+    /// digits on the keypad. Needs the `EnableHexNumpad` registry value, and can't
+    /// go beyond U+FFFF.
+    Windows,
+    /// [WinCompose](http://wincompose.info/): the compose key (right Alt by
+    /// default), U, the code point in hex, then Enter.
+    WinCompose,
+}
+
+impl UnicodeMode {
+    /// The steps that enter `c`, or `None` if this method can't produce it.
+    pub fn steps(self, c: char) -> Option<Steps> {
+        let mut steps = Steps::new();
+        let mut push = |step| steps.push(step).unwrap();
+
+        match self {
+            Self::Linux => {
+                push(MacroStep::Press(LEFT_CTRL));
+                push(MacroStep::Press(LEFT_SHIFT));
+                push(MacroStep::Tap(U));
+                push(MacroStep::Release(LEFT_SHIFT));
+                push(MacroStep::Release(LEFT_CTRL));
+                hex(c as u32, false, &mut push);
+                push(MacroStep::Tap(SPACE));
+            }
+            Self::MacOs => {
+                push(MacroStep::Press(LEFT_ALT));
+
+                for unit in c.encode_utf16(&mut [0; 2]) {
+                    hex(*unit as u32, false, &mut push);
+                }
+
+                push(MacroStep::Release(LEFT_ALT));
+            }
+            Self::Windows => {
+                if c as u32 > 0xFFFF {
+                    return None;
+                }
+
+                push(MacroStep::Press(LEFT_ALT));
+                push(MacroStep::Tap(KEYPAD_PLUS));
+                hex(c as u32, true, &mut push);
+                push(MacroStep::Release(LEFT_ALT));
+            }
+            Self::WinCompose => {
+                push(MacroStep::Tap(RIGHT_ALT));
+                push(MacroStep::Tap(U));
+                hex(c as u32, false, &mut push);
+                push(MacroStep::Tap(ENTER));
+            }
+        }
+
+        Some(steps)
+    }
+}
+
+/// Taps out `value` in lowercase hex, with at least four digits.
+///
+/// With `keypad` set, the decimal digits come from the numeric keypad.
+fn hex(value: u32, keypad: bool, push: &mut impl FnMut(MacroStep)) {
+    let digits = (8 - value.leading_zeros() as usize / 4).max(4);
+
+    for i in (0..digits).rev() {
+        let key = match ((value >> (i * 4)) & 0xF, keypad) {
+            (0, false) => 0x27,
+            (0, true) => 0x62,
+            (digit @ 1..=9, false) => 0x1E + digit as u8 - 1,
+            (digit @ 1..=9, true) => 0x59 + digit as u8 - 1,
+            (digit, _) => 0x04 + digit as u8 - 10,
+        };
+
+        push(MacroStep::Tap(key));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::MacroStep::*;
+
+    // Hex digit usages, on the main block and the keypad.
+    const D0: u8 = 0x27;
+    const D1: u8 = 0x1E;
+    const D3: u8 = 0x20;
+    const D6: u8 = 0x23;
+    const D8: u8 = 0x25;
+    const A: u8 = 0x04;
+    const C: u8 = 0x06;
+    const D: u8 = 0x07;
+    const E: u8 = 0x08;
+    const F: u8 = 0x09;
+    const KP0: u8 = 0x62;
+    const KP2: u8 = 0x5A;
+
+    fn steps(mode: UnicodeMode, c: char) -> std::vec::Vec<MacroStep> {
+        mode.steps(c).unwrap().to_vec()
+    }
+
+    #[test]
+    fn linux() {
+        assert_eq!(
+            steps(UnicodeMode::Linux, 'è'),
+            [
+                Press(LEFT_CTRL),
+                Press(LEFT_SHIFT),
+                Tap(U),
+                Release(LEFT_SHIFT),
+                Release(LEFT_CTRL),
+                Tap(D0),
+                Tap(D0),
+                Tap(E),
+                Tap(D8),
+                Tap(SPACE),
+            ]
+        );
+
+        // Code points beyond the BMP get as many digits as they need.
+        let emoji = steps(UnicodeMode::Linux, '😀');
+        assert_eq!(
+            emoji[5..],
+            [Tap(D1), Tap(F), Tap(D6), Tap(D0), Tap(D0), Tap(SPACE)]
+        );
+    }
+
+    #[test]
+    fn macos_uses_surrogates() {
+        // U+1F600 is D83D DE00 in UTF-16.
+        assert_eq!(
+            steps(UnicodeMode::MacOs, '😀'),
+            [
+                Press(LEFT_ALT),
+                Tap(D),
+                Tap(D8),
+                Tap(D3),
+                Tap(D),
+                Tap(D),
+                Tap(E),
+                Tap(D0),
+                Tap(D0),
+                Release(LEFT_ALT),
+            ]
+        );
+    }
+
+    #[test]
+    fn windows() {
+        assert_eq!(
+            steps(UnicodeMode::Windows, '€'),
+            [
+                Press(LEFT_ALT),
+                Tap(KEYPAD_PLUS),
+                Tap(KP2),
+                Tap(KP0),
+                Tap(A),
+                Tap(C),
+                Release(LEFT_ALT),
+            ]
+        );
+
+        assert_eq!(UnicodeMode::Windows.steps('😀'), None);
+    }
+
+    #[test]
+    fn wincompose() {
+        assert_eq!(
+            steps(UnicodeMode::WinCompose, '😀'),
+            [
+                Tap(RIGHT_ALT),
+                Tap(U),
+                Tap(D1),
+                Tap(F),
+                Tap(D6),
+                Tap(D0),
+                Tap(D0),
+                Tap(ENTER),
+            ]
+        );
+    }
+
+    #[test]
+    fn longest_sequence_fits() {
+        for mode in [
+            UnicodeMode::Linux,
+            UnicodeMode::MacOs,
+            UnicodeMode::WinCompose,
+        ] {
+            assert!(mode.steps(char::MAX).is_some());
+        }
+    }
+}
@@ -1,11 +1,16 @@
 MEMORY {
-    BOOT2 : ORIGIN = 0x10000000, LENGTH = 0x100
-    FLASH : ORIGIN = 0x10000100, LENGTH = 2048K - 0x100
-    RAM   : ORIGIN = 0x20000000, LENGTH = 256K
+    BOOT2  : ORIGIN = 0x10000000, LENGTH = 0x100
+    FLASH  : ORIGIN = 0x10000100, LENGTH = 2048K - 0x100 - 64K
+    /* Reserved for persistent configuration (eight 8K slots); see src/config.rs */
+    CONFIG : ORIGIN = 0x101F0000, LENGTH = 64K
+    RAM    : ORIGIN = 0x20000000, LENGTH = 256K
 }
 
 EXTERN(BOOT2_FIRMWARE)
 
+__config_start = ORIGIN(CONFIG);
+__config_end = ORIGIN(CONFIG) + LENGTH(CONFIG);
+
 SECTIONS {
     /* ### Boot loader */
     .boot2 ORIGIN(BOOT2) :
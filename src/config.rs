@@ -1,18 +1,109 @@
-use heapless::String;
+pub use hyperdeck_core::config::*;
 
-#[derive(Default)]
-pub struct Config {
-    layers: [Option<LayerConfig>; 6]
+use embedded_storage::nor_flash::{
+    ErrorType, NorFlash, NorFlashError, NorFlashErrorKind, ReadNorFlash,
+};
+use rp2040_flash::flash;
+
+use crate::display::Display;
+
+extern "C" {
+    // Defined in memory.x
+    static __config_start: u8;
+    static __config_end: u8;
 }
 
-pub struct LayerConfig {
-    pub name: String<16>,
-    pub keys: [KeyConfig; 14]
+/// Start of the execute-in-place window the flash is mapped into.
+const XIP_BASE: u32 = 0x1000_0000;
+
+/// The flash region reserved for configuration storage by `memory.x`.
+///
+/// Offsets passed to the [`NorFlash`] methods are relative to the start of the region.
+pub struct ConfigFlash;
+
+#[derive(Debug)]
+pub struct FlashError(NorFlashErrorKind);
+
+impl NorFlashError for FlashError {
+    fn kind(&self) -> NorFlashErrorKind {
+        self.0
+    }
 }
 
-pub struct KeyConfig {
-    pub on_press: Option<[u8; 8]>,
-    pub on_hold: Option<[u8; 8]>,
-    pub colors: [u8; 6]
+impl ConfigFlash {
+    /// Address of the region within the XIP window.
+    fn start() -> u32 {
+        // We only ever take the address of the linker symbols, never read them.
+        core::ptr::addr_of!(__config_start) as u32
+    }
+
+    fn check(&self, offset: u32, len: usize, align: usize) -> Result<(), FlashError> {
+        if offset as usize + len > self.capacity() {
+            return Err(FlashError(NorFlashErrorKind::OutOfBounds));
+        }
+
+        if !(offset as usize).is_multiple_of(align) || !len.is_multiple_of(align) {
+            return Err(FlashError(NorFlashErrorKind::NotAligned));
+        }
+
+        Ok(())
+    }
+}
+
+impl ErrorType for ConfigFlash {
+    type Error = FlashError;
 }
 
+impl ReadNorFlash for ConfigFlash {
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        self.check(offset, bytes.len(), Self::READ_SIZE)?;
+
+        // Safety: the range was bounds checked against the reserved region,
+        // which is always mapped while XIP is enabled.
+        unsafe {
+            let src = (Self::start() + offset) as *const u8;
+            core::ptr::copy_nonoverlapping(src, bytes.as_mut_ptr(), bytes.len());
+        }
+
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        (core::ptr::addr_of!(__config_end) as u32 - Self::start()) as usize
+    }
+}
+
+impl NorFlash for ConfigFlash {
+    const WRITE_SIZE: usize = 256;
+    const ERASE_SIZE: usize = 4096;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        self.check(from, to.saturating_sub(from) as usize, Self::ERASE_SIZE)?;
+
+        let addr = Self::start() - XIP_BASE + from;
+
+        // Safety: nothing can touch the flash while we're in here; core 1 is parked
+        // in RAM, interrupts are disabled, and we don't use DMA.
+        Display::pause(|| {
+            cortex_m::interrupt::free(|_| unsafe { flash::flash_range_erase(addr, to - from, true) })
+        });
+
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.check(offset, bytes.len(), Self::WRITE_SIZE)?;
+
+        let addr = Self::start() - XIP_BASE + offset;
+
+        // Safety: as above. `bytes` must not live in flash either, which holds
+        // for the stack buffers [`Config::store`] writes from.
+        Display::pause(|| {
+            cortex_m::interrupt::free(|_| unsafe { flash::flash_range_program(addr, bytes, true) })
+        });
+
+        Ok(())
+    }
+}
@@ -2,11 +2,13 @@
 
 mod driver;
 
+use core::sync::atomic::{AtomicU8, Ordering};
+
 use cortex_m::delay::Delay;
 use display_interface_spi::SPIInterface;
-use embedded_graphics::pixelcolor::Rgb565;
 use embedded_hal::PwmPin;
 use heapless::{String, mpmc::Q16};
+use hyperdeck_core::screen::{Screen, WIDTH, HEIGHT};
 use rp2040_hal::gpio::bank0::*;
 use rp2040_hal::gpio::{Disabled, Pin, PullDown};
 use rp2040_hal::multicore::{Multicore, Stack};
@@ -14,8 +16,6 @@ use rp2040_hal::pac::SPI1;
 use rp2040_hal::pwm::{Channel, FreeRunning, Pwm3, A};
 use rp2040_hal::spi::{Enabled, Spi};
 
-const WIDTH: u16 = 240;
-const HEIGHT: u16 = 135;
 const SCREEN_SIZE: usize = (WIDTH * HEIGHT) as usize;
 
 /// Stack for core 1.
@@ -23,7 +23,14 @@ const SCREEN_SIZE: usize = (WIDTH * HEIGHT) as usize;
 /// 64_800 bytes for the frame buffer, plus an extra 25%.
 static mut CORE1_STACK: Stack<20250> = Stack::new();
 
-static COMMAND_QUEUE: Q16<Command> = Q16::new();
+static SCREEN_QUEUE: Q16<Screen> = Q16::new();
+
+/// Handshake used by [`Display::pause`] to park core 1 while flash is unavailable.
+static PAUSE: AtomicU8 = AtomicU8::new(RUNNING);
+
+const RUNNING: u8 = 0;
+const PAUSE_REQUESTED: u8 = 1;
+const PAUSED: u8 = 2;
 
 type DC = Pin<Gpio16, Disabled<PullDown>>;
 type CS = Pin<Gpio21, Disabled<PullDown>>;
@@ -32,24 +39,6 @@ type RST = Pin<Gpio28, Disabled<PullDown>>;
 
 type SPI = Spi<Enabled, SPI1, 8>;
 
-pub enum Command {
-    Splash,
-    Home {
-        layer_id: u8,
-        layer_name: String<16>,
-        layer_color: Rgb565
-    }, 
-    Selector {
-        
-    },
-    Settings {
-
-    },
-    Panic {
-        message: String<64>
-    }
-}
-
 pub struct Display {
     bl: BL,
 }
@@ -101,17 +90,63 @@ impl Display {
         self.bl.set_duty(brightness);
     }
 
-    /// Enqueue a command for the display.
+    /// Enqueue a screen for the display to show.
     /// 
     /// Note: the queue can only hold 16 elements. If the queue is full,
     /// any excess will be silently dropped.
-    pub fn send_command(&self, command: Command) {
-        let _ = COMMAND_QUEUE.enqueue(command);
+    pub fn show(&self, screen: Screen) {
+        let _ = SCREEN_QUEUE.enqueue(screen);
     }
 
-    /// Send a panic message to the display command queue, without needing a reference to the display.
+    /// Runs `f` while core 1 is parked in a RAM-resident spin loop.
+    ///
+    /// Core 1 executes the display driver straight from flash, so it must be out of the
+    /// way before anything erases or programs the flash. Blocks until the driver finishes
+    /// its current frame.
+    pub fn pause<R>(f: impl FnOnce() -> R) -> R {
+        PAUSE.store(PAUSE_REQUESTED, Ordering::SeqCst);
+
+        while PAUSE.load(Ordering::SeqCst) != PAUSED {}
+
+        let result = f();
+
+        PAUSE.store(RUNNING, Ordering::SeqCst);
+
+        result
+    }
+
+    /// Send a panic message to the display screen queue, without needing a reference to the display.
     pub fn send_panic(message: String<64>) {
-        let _ = COMMAND_QUEUE.enqueue(Command::Panic { message });
+        let _ = SCREEN_QUEUE.enqueue(Screen::Panic { message });
+    }
+}
+
+
+/// Called by the driver on core 1 between frames; parks it if [`Display::pause`] asked us to.
+fn check_pause() {
+    if PAUSE.load(Ordering::SeqCst) == PAUSE_REQUESTED {
+        // Safety: the pointer is to a static, and core 1 never enables interrupts.
+        unsafe { park(PAUSE.as_ptr()) }
     }
 }
 
+/// Acknowledges a pause request, then spins until it is released.
+///
+/// This lives in RAM and is written in assembly so that nothing (not even an
+/// out-of-line atomic load in a debug build) fetches instructions from flash.
+#[inline(never)]
+#[link_section = ".data.ram_func"]
+unsafe extern "C" fn park(state: *mut u8) {
+    core::arch::asm!(
+        "strb {paused}, [{state}]",
+        "2:",
+        "ldrb {tmp}, [{state}]",
+        "cmp {tmp}, {running}",
+        "bne 2b",
+        state = in(reg) state,
+        paused = in(reg) PAUSED as u32,
+        running = in(reg) RUNNING as u32,
+        tmp = out(reg) _,
+        options(nostack),
+    );
+}
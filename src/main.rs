@@ -1,5 +1,7 @@
 #![no_std]
 #![no_main]
+// Peripherals shared with interrupt handlers live in `static mut`s by design.
+#![allow(static_mut_refs)]
 
 mod config;
 mod display;
@@ -17,69 +19,115 @@ use rp2040_hal::pwm::Slices;
 use rp2040_hal::timer::Timer;
 use rp2040_hal::usb::UsbBus;
 use rp2040_hal::{self as hal, pac, Clock, Spi, I2C};
+use hyperdeck_core::device::{Device, Host};
+use hyperdeck_core::hid::Report;
+use hyperdeck_core::keyboard::{Output, Protocol};
+use hyperdeck_core::leds::Leds;
+use hyperdeck_core::queue::Stats;
+use hyperdeck_core::screen::Screen;
+use hyperdeck_core::session::{Event as SessionEvent, Session};
+use hyperdeck_core::time::{Clock as _, Duration};
 use usb_device::class_prelude::UsbBusAllocator;
 
-use crate::display::{Display, Command::*};
+use crate::config::{Config, ConfigFlash};
+use crate::display::Display;
 use crate::keypad::Keypad;
-use crate::utils::wait;
+use crate::usb::SerialLink;
+use crate::utils::SystemClock;
 
 #[rp_pico::entry]
 fn main() -> ! {
-    // let mut config = Config::load();
-    let (mut display, mut keypad) = hardware_init();
+    let mut config = Config::load(&mut ConfigFlash).unwrap_or_default();
+    let (mut display, mut keypad, clock) = hardware_init();
     
-    display.set_brightness(1.0);
-    display.send_command(Splash);
-    wait(1000);
+    display.set_brightness(config.brightness.display_f32());
+    display.show(splash());
+    clock.wait(Duration::millis(1000));
 
-    keypad.set_brightness(0.1);
+    keypad.set_brightness(config.brightness.keypad_f32());
+    keypad.set_debounce(config.debounce);
+    keypad.set_repeat(config.repeat);
+
+    let mut session = Session::new();
+    let mut device = Device::new();
+
+    keypad.set_colors(device.key_colors(&config));
+
+    if let Some(screen) = device.home(&config) {
+        display.show(screen);
+    }
 
     loop {
-        for (id, event) in keypad.update() {
-            if id == 0 && matches!(event, keypad::KeyEvent::Pressed) {
-                display.send_command(Splash);
-            }
-            if id == 0 && matches!(event, keypad::KeyEvent::Held) {
-                for i in 0..16 {
-                    keypad.keys[i].pressed_color = keypad::Color::new(255, 0, 0);
-                }
-            }
-            if id == 1 && matches!(event, keypad::KeyEvent::Held) {
-                for i in 0..16 {
-                    keypad.keys[i].pressed_color = keypad::Color::new(0, 255, 0);
-                }
-            }
-            if id == 2 && matches!(event, keypad::KeyEvent::Held) {
-                for i in 0..16 {
-                    keypad.keys[i].pressed_color = keypad::Color::new(0, 0, 255);
+        let stats = Stats {
+            dropped: device.dropped(),
+            ..usb::stats()
+        };
+
+        match session.poll(&mut SerialLink, &mut config, &mut ConfigFlash, stats, clock.now()) {
+            Some(SessionEvent::Started) => display.show(Screen::Configuring),
+            Some(event) => {
+                display.set_brightness(config.brightness.display_f32());
+                keypad.set_brightness(config.brightness.keypad_f32());
+                keypad.set_debounce(config.debounce);
+                keypad.set_repeat(config.repeat);
+                keypad.set_colors(device.key_colors(&config));
+
+                if event == SessionEvent::Ended {
+                    display.show(device.home(&config).unwrap_or_else(splash));
                 }
             }
-            if id == 3 && matches!(event, keypad::KeyEvent::Held) {
-                panic!("l bozo");
-                rp2040_hal::rom_data::reset_to_usb_boot(0, 0);
-            }
-            if id == 15 && matches!(event, keypad::KeyEvent::Pressed) {
-                let _ = usb::push_keyboard(usbd_hid::descriptor::KeyboardReport {
-                    modifier: 0b00000101,
-                    reserved: 0,
-                    leds: 0,
-                    keycodes: [0x17, 0x0, 0x0, 0x0, 0x0, 0x0],
-                });
-            }
+            None => (),
+        }
 
-            if matches!(event, keypad::KeyEvent::Released) {
-                let _ = usb::push_keyboard(usbd_hid::descriptor::KeyboardReport {
-                    modifier: 0,
-                    reserved: 0,
-                    leds: 0,
-                    keycodes: [0x0, 0x0, 0x0, 0x0, 0x0, 0x0],
-                });
-            }
+        let mut board = Board {
+            display: &display,
+            configuring: session.is_active(),
+        };
+
+        device.update(&config, &mut keypad, &clock, &mut board).unwrap();
+    }
+}
+
+/// The USB stack and display, as the [`Device`] sees them.
+struct Board<'a> {
+    display: &'a Display,
+    /// The configuring screen stays up until the session ends.
+    configuring: bool,
+}
+
+impl Host for Board<'_> {
+    fn send_keyboard(&mut self, output: Output) -> bool {
+        match output {
+            Output::Boot(report) => usb::push_boot_keyboard(report),
+            Output::Report(report) => usb::push_report(Report::Keyboard(report)),
+        }
+    }
+
+    fn send(&mut self, report: Report) -> bool {
+        usb::push_report(report)
+    }
+
+    fn leds(&self) -> Leds {
+        usb::leds()
+    }
+
+    fn protocol(&self) -> Protocol {
+        usb::protocol()
+    }
+
+    fn show(&mut self, screen: Screen) {
+        if !self.configuring {
+            self.display.show(screen);
         }
     }
 }
 
-fn hardware_init() -> (Display, Keypad) {
+/// The splash screen, with a fresh field of stars.
+fn splash() -> Screen {
+    Screen::Splash { seed: utils::random(0, u32::MAX - 1) }
+}
+
+fn hardware_init() -> (Display, Keypad, SystemClock) {
     let mut pac = pac::Peripherals::take().unwrap();
     let core = pac::CorePeripherals::take().unwrap();
 
@@ -109,10 +157,11 @@ fn hardware_init() -> (Display, Keypad) {
     // Safety: we're still in the initialization stage,
     // so there's no race risk.
     unsafe { 
-        utils::TIMER = Timer::new(pac.TIMER, &mut pac.RESETS).into();
         utils::ROSC = RingOscillator::new(pac.ROSC).initialize().into();
     }
 
+    let clock = SystemClock::new(Timer::new(pac.TIMER, &mut pac.RESETS));
+
     let bus_allocator = UsbBusAllocator::new(UsbBus::new(
         pac.USBCTRL_REGS,
         pac.USBCTRL_DPRAM,
@@ -185,7 +234,7 @@ fn hardware_init() -> (Display, Keypad) {
         &mut mc,
     );
 
-    (display, keypad)
+    (display, keypad, clock)
 }
 
 
@@ -1,11 +1,20 @@
 use rp_pico::hal::usb::UsbBus;
 use rp_pico::pac::{self, interrupt};
+use heapless::spsc::Queue;
+use hyperdeck_core::hid::{
+    Report, ReportId, BOOT_KEYBOARD_DESCRIPTOR, MAX_REPORT_LEN, REPORT_DESCRIPTOR,
+};
+use hyperdeck_core::keyboard::Protocol;
+use hyperdeck_core::leds::Leds;
+use hyperdeck_core::queue::{Delivery, ReportQueue, Stats};
+use hyperdeck_core::session::Transport;
 use usb_device::class_prelude::*;
 use usb_device::prelude::*;
 use usb_device::UsbError;
-use usbd_hid::descriptor::generator_prelude::*;
-use usbd_hid::descriptor::KeyboardReport;
-use usbd_hid::hid_class::HIDClass;
+use usbd_hid::hid_class::{
+    HIDClass, HidClassSettings, HidCountryCode, HidProtocol, HidProtocolMode, HidSubClass,
+    ProtocolModeConfig, ReportType,
+};
 use usbd_serial::SerialPort;
 
 type Device = UsbDevice<'static, UsbBus>;
@@ -18,6 +27,23 @@ static mut USB_BUS: Option<Bus> = None;
 
 static mut SERIAL: Option<Serial> = None;
 static mut HID: Option<Hid> = None;
+/// The boot keyboard interface, only used while the host wants the boot protocol.
+static mut BOOT_HID: Option<Hid> = None;
+
+/// Bytes received over serial, waiting to be picked up by [`SerialLink`].
+static mut SERIAL_RX: Queue<u8, 1024> = Queue::new();
+
+/// Reports waiting for their endpoint, sent from the USB interrupt.
+static mut REPORTS: ReportQueue<Outgoing, 32> = ReportQueue::new();
+
+/// The keyboard LEDs the host last asked for, on either interface.
+static mut LEDS: Leds = Leds(0);
+
+enum Outgoing {
+    Report(Report),
+    /// A report for the boot keyboard interface.
+    Boot([u8; 8]),
+}
 
 pub fn init(bus_allocator: Bus) {
     let bus_ref = unsafe {
@@ -26,10 +52,21 @@ pub fn init(bus_allocator: Bus) {
         USB_BUS.as_ref().unwrap()
     };
 
-    let hid = HIDClass::new(bus_ref, KeyboardReport::desc(), 60);
+    // The boot interface goes first, for BIOSes that only look at one.
+    let boot_settings = HidClassSettings {
+        subclass: HidSubClass::Boot,
+        protocol: HidProtocol::Keyboard,
+        config: ProtocolModeConfig::DefaultBehavior,
+        locale: HidCountryCode::NotSupported,
+    };
+    let boot_hid =
+        HIDClass::new_with_settings(bus_ref, BOOT_KEYBOARD_DESCRIPTOR, 10, boot_settings);
+    // Poll often enough for smooth mouse movement.
+    let hid = HIDClass::new(bus_ref, REPORT_DESCRIPTOR, 10);
     let serial = SerialPort::new(bus_ref);
 
     unsafe {
+        BOOT_HID = Some(boot_hid);
         HID = Some(hid);
         SERIAL = Some(serial);
     }
@@ -51,52 +88,105 @@ pub fn init(bus_allocator: Bus) {
     }
 }
 
-pub fn config_mode() {
-    let mut found = false;
+/// Handle to the CDC serial port for [`Session`](hyperdeck_core::session::Session)s.
+///
+/// Received bytes are buffered by the USB interrupt, so reads never touch the
+/// hardware; writes go straight to the port's own transmit buffer.
+pub struct SerialLink;
+
+impl Transport for SerialLink {
+    fn read(&mut self, buf: &mut [u8]) -> usize {
+        critical_section::with(|_| unsafe {
+            // Safety: the interrupt can't touch the queue while we're in a critical section.
+            let mut count = 0;
+
+            while count < buf.len() {
+                match SERIAL_RX.dequeue() {
+                    Some(byte) => buf[count] = byte,
+                    None => break,
+                }
+
+                count += 1;
+            }
 
-    critical_section::with(|_| unsafe {
-        // Safety: taking a mutable reference to these is okay within a critical section.
-        let usb_dev = USB_DEVICE.as_mut().unwrap();
-        let hid = HID.as_mut().unwrap();
-        let serial = SERIAL.as_mut().unwrap();
+            count
+        })
+    }
 
-        let mut throwaway_buf = [0; 64];
+    fn write(&mut self, buf: &[u8]) -> usize {
+        critical_section::with(|_| unsafe { SERIAL.as_mut().map(|serial| serial.write(buf)) })
+            .unwrap()
+            .unwrap_or(0)
+    }
+}
 
-        loop {
-            if !usb_dev.poll(&mut [hid, serial]) {
-                let _ = hid.pull_raw_output(&mut throwaway_buf);
-                continue;
-            }
+/// Queues `report` to be sent to the host under its report ID, returning whether
+/// there was room for it.
+pub fn push_report(report: Report) -> bool {
+    enqueue(Outgoing::Report(report))
+}
 
-            let mut magic_buf = [0u8; 5];
+/// Queues a boot keyboard report for the boot interface, returning whether there
+/// was room for it. The host only takes these while it wants the boot protocol.
+pub fn push_boot_keyboard(report: [u8; 8]) -> bool {
+    enqueue(Outgoing::Boot(report))
+}
 
-            match serial.read(&mut magic_buf[..]) {
-                Ok(count) => {
-                    if magic_buf == [b'H', b'Y', b'P', b'E', b'R'] {
-                        found = true;
-                        return;
-                    }
-                },
-                Err(UsbError::WouldBlock) => {
+fn enqueue(report: Outgoing) -> bool {
+    // Safety: the interrupt can't touch the queue while we're in a critical section.
+    let queued = critical_section::with(|_| unsafe { REPORTS.push(report) });
 
-                },
-                Err(err) => Err(err).unwrap() 
-            }
-        }
-    });
+    // The interrupt only fires by itself once an endpoint finishes, so nudge it in
+    // case both are idle.
+    pac::NVIC::pend(pac::Interrupt::USBCTRL_IRQ);
+    queued
+}
 
-    if found {
-        panic!("Magic recevied :)")
+/// Counters for the report queue.
+pub fn stats() -> Stats {
+    critical_section::with(|_| unsafe { REPORTS.stats() })
+}
+
+/// The keyboard protocol the host last asked for on the boot interface.
+pub fn protocol() -> Protocol {
+    let mode =
+        critical_section::with(|_| unsafe { BOOT_HID.as_ref().map(|hid| hid.get_protocol_mode()) });
+
+    match mode {
+        Some(Ok(HidProtocolMode::Boot)) => Protocol::Boot,
+        _ => Protocol::Report,
     }
 }
 
-pub fn push_report(report: [u8; 8]) -> Result<usize, UsbError> {
-    todo!()
+/// The keyboard LEDs the host wants lit.
+pub fn leds() -> Leds {
+    critical_section::with(|_| unsafe { LEDS })
 }
 
-pub fn push_keyboard(report: KeyboardReport) -> Result<usize, UsbError> {
-    critical_section::with(|_| unsafe { HID.as_mut().map(|hid| hid.push_input(&report)) })
-        .unwrap()
+/// Reads any LED report the host sent `hid`, either through its OUT endpoint or
+/// with a SET_REPORT request. Only the composite interface uses report IDs.
+fn read_leds(hid: &mut Hid, with_id: bool) -> Option<Leds> {
+    let mut buf = [0; 64];
+
+    // Both have to be read every time, or the host's next report is refused.
+    let output = match hid.pull_raw_output(&mut buf) {
+        Ok(len) => Leds::parse(&buf[..len], with_id),
+        Err(_) => None,
+    };
+    let set_report = match hid.pull_raw_report(&mut buf) {
+        Ok(info) if info.report_type == ReportType::Output && info.len > 0 => {
+            let id = if with_id { ReportId::Keyboard as u8 } else { 0 };
+
+            // Hosts disagree on whether the data repeats the report ID, but the
+            // LED byte always comes last.
+            (info.report_id == id)
+                .then(|| Leds::parse(&buf[info.len - 1..info.len], false))
+                .flatten()
+        }
+        _ => None,
+    };
+
+    set_report.or(output)
 }
 
 /// Whenever the USB hardware generates an interrupt request, this function is called.
@@ -107,14 +197,40 @@ unsafe fn USBCTRL_IRQ() {
     // as the interrupt preempts the rest of the program.
     let usb_dev = USB_DEVICE.as_mut().unwrap();
 
+    let boot_hid = BOOT_HID.as_mut().unwrap();
     let hid = HID.as_mut().unwrap();
     let serial = SERIAL.as_mut().unwrap();
 
-    usb_dev.poll(&mut [hid, serial]);
+    usb_dev.poll(&mut [boot_hid, hid, serial]);
 
-    // This is needed for reasons only known to the wizards
-    // at the USB-IF (it has something to do with caps lock LEDs?)
-    let mut throwaway_buf = [0; 64];
-    let _ = hid.pull_raw_output(&mut throwaway_buf);
-    let _ = serial.read(&mut throwaway_buf);
+    let composite_leds = read_leds(hid, true);
+    let boot_leds = read_leds(boot_hid, false);
+
+    if let Some(leds) = composite_leds.or(boot_leds) {
+        LEDS = leds;
+    }
+
+    REPORTS.drain(|report| {
+        let result = match report {
+            Outgoing::Report(report) => hid.push_raw_input(report.encode(&mut [0; MAX_REPORT_LEN])),
+            Outgoing::Boot(report) => boot_hid.push_raw_input(report),
+        };
+
+        match result {
+            Ok(_) => Delivery::Sent,
+            Err(UsbError::WouldBlock) => Delivery::Busy,
+            Err(_) => Delivery::Rejected,
+        }
+    });
+
+    // Serial data has to be read out here too, or the interrupt keeps firing.
+    // If the main loop has fallen this far behind, excess bytes are dropped;
+    // the protocol's checksums catch that and the host retries.
+    let mut serial_buf = [0; 64];
+
+    if let Ok(count) = serial.read(&mut serial_buf) {
+        for byte in &serial_buf[..count] {
+            let _ = SERIAL_RX.enqueue(*byte);
+        }
+    }
 }
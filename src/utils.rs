@@ -1,11 +1,24 @@
-use rp2040_hal::timer::{Timer, Instant};
+use rp2040_hal::timer::Timer;
 use rp2040_hal::rosc::{RingOscillator, Enabled};
 
+use hyperdeck_core::time::{Clock, Instant};
 
-pub static mut TIMER: Option<Timer> = None;
 pub static mut ROSC: Option<RingOscillator<Enabled>> = None;
 
-pub type Duration = fugit::Duration<u32, 1, 100000>;
+/// The RP2040's 1 MHz timer, as a [`Clock`].
+pub struct SystemClock(Timer);
+
+impl SystemClock {
+    pub fn new(timer: Timer) -> Self {
+        Self(timer)
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        self.0.get_counter()
+    }
+}
 
 /// Custom panic handler. Resets the Pico into BOOTSEL (flashing) mode.
 /// Useful for distinguishing between a hang/deadlock and panic/crash.
@@ -41,7 +54,8 @@ fn panic(info: &core::panic::PanicInfo) -> ! {
     Display::send_panic(message);
 
     // Busy-wait to give the screen a chance to render.
-    wait(1000);
+    // The clock belongs to the main loop, so count cycles instead (one second at 125 MHz).
+    cortex_m::asm::delay(125_000_000);
 
     // Reboot into BOOTSEL mode
     rp2040_hal::rom_data::reset_to_usb_boot(0, 0);
@@ -51,24 +65,6 @@ fn panic(info: &core::panic::PanicInfo) -> ! {
     }
 }
 
-/// Get an Instant representing "now."
-pub fn now() -> Instant {
-    // Safety: get_counter is a read-only operation.
-    unsafe { TIMER.as_ref().unwrap().get_counter() }
-}
-
-/// Simple busy-waiting implementation using [`now()`].
-pub fn wait(duration_ms: u32) {
-    let length = Duration::millis(duration_ms);
-    let start = now();
-
-    loop {
-        if now() - start >= length {
-            return;
-        }
-    }
-}
-
 /// Generates a random u32 in the range `[min, max]` using the RP2040's ring oscillator.
 pub fn random(min: u32, max: u32) -> u32 {
     fn random_bit() -> u32 {